@@ -0,0 +1,393 @@
+//! A persistent, append-only record of every command that was executed, so
+//! that disputes such as "I paid but nothing came out" can be settled from the
+//! device itself.
+//!
+//! Each command gets two records: one with the outcome `started`, written
+//! before the command is sent to its device, and one with its actual outcome
+//! once it's done. A `started` record without a matching outcome means that the
+//! server stopped while the command was running, so it may or may not have been
+//! executed. Commands that never reach a device, because they're unknown or
+//! their device is busy with another command, aren't recorded.
+//!
+//! Records are stored one JSON object per line. Every record ends with a
+//! `hash` field, the hex SHA-256 of the rest of its line, and includes the hash
+//! of the record before it as `prevHash`. Editing, removing or reordering
+//! records therefore breaks the chain, which `AuditLog::query` reports.
+
+use crate::command_executor::{CommandArguments, CommandValue, CommandValueType};
+use sha2::{Digest, Sha256};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// The `prevHash` of the first record.
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// The longest caller-supplied context that is stored with a record.
+pub const MAX_CONTEXT_LENGTH: usize = 4096;
+
+/// What is known about whoever asked for a command to be executed.
+#[derive(Clone, Debug, Default)]
+pub struct CallerContext {
+    /// The correlation id of the HTTP request that executed the command.
+    pub request_id: Option<String>,
+    /// Free-form context from the caller, such as the invoice that paid for a
+    /// vend.
+    pub context: Option<String>,
+}
+
+/// A command execution, as it is written to the log.
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditEntry<'a> {
+    /// When the command started or finished, depending on the record, in
+    /// milliseconds since the Unix epoch.
+    pub timestamp: u64,
+    pub duration_ms: u64,
+    pub namespace: &'a str,
+    /// The command as it was requested, which may use a namespace alias.
+    pub command: &'a str,
+    #[serde(rename = "type")]
+    pub value_type: CommandValueType,
+    pub arguments: &'a CommandArguments,
+    pub request_id: Option<&'a str>,
+    pub context: Option<&'a str>,
+    /// How many times the request was sent to the device.
+    pub attempts: u64,
+    /// `started` before the command is sent, then `success` or the kind of
+    /// error that the command failed with.
+    pub outcome: &'a str,
+    /// The sequence of the command's `started` record, on the record of its
+    /// outcome.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub started_sequence: Option<u64>,
+    pub result: Option<&'a CommandValue>,
+    /// The error, including the device's error message if it reported one.
+    pub error: Option<serde_json::Value>,
+}
+
+/// An entry along with its position in the chain. Serialized without its
+/// hash, which is appended once the rest of the line is known.
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ChainedEntry<'a> {
+    sequence: u64,
+    #[serde(flatten)]
+    entry: &'a AuditEntry<'a>,
+    prev_hash: &'a str,
+}
+
+/// Selects records by when they were written and which command they are for.
+#[derive(Clone, Debug, Default)]
+pub struct AuditQuery {
+    /// Inclusive bounds, in milliseconds since the Unix epoch.
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+    /// Either the command as it was requested, or just its name without the
+    /// namespace, such as `vend` for `arduino:85735313932351F0B1C1:vend`.
+    pub command: Option<String>,
+}
+
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditQueryResult {
+    pub records: Vec<serde_json::Value>,
+    /// Whether every record in the log, not just the selected ones, is intact
+    /// and chained to the record before it.
+    pub chain_intact: bool,
+    /// The 1-based line of the first record that isn't, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub first_broken_line: Option<usize>,
+}
+
+pub struct AuditLog {
+    path: PathBuf,
+    writer: Mutex<AuditLogWriter>,
+}
+
+struct AuditLogWriter {
+    file: File,
+    next_sequence: u64,
+    last_hash: String,
+}
+
+impl AuditLog {
+    /// Opens the log at `path`, creating it if it doesn't exist. New records
+    /// are chained to the last record that can be read, so a record that was
+    /// only partly written when power was lost stays reported as broken rather
+    /// than being silently dropped.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)
+            .map_err(|err| format!("Unable to open audit log {}: {err}", path.display()))?;
+
+        let mut next_sequence = 0;
+        let mut last_hash = String::from(GENESIS_HASH);
+        for line in BufReader::new(&file).split(b'\n') {
+            let line =
+                line.map_err(|err| format!("Unable to read audit log {}: {err}", path.display()))?;
+            let Some((sequence, hash)) = std::str::from_utf8(&line)
+                .ok()
+                .and_then(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+                .and_then(|record| {
+                    Some((
+                        record.get("sequence")?.as_u64()?,
+                        record.get("hash")?.as_str()?.to_string(),
+                    ))
+                })
+            else {
+                continue;
+            };
+            next_sequence = sequence + 1;
+            last_hash = hash;
+        }
+        // A partly written record must not have the next record appended to
+        // its line.
+        let mut last_byte = [b'\n'];
+        if file.metadata().map_or(0, |metadata| metadata.len()) > 0 {
+            file.seek(SeekFrom::End(-1))
+                .and_then(|_| file.read_exact(&mut last_byte))
+                .map_err(|err| format!("Unable to read audit log {}: {err}", path.display()))?;
+        }
+        if last_byte[0] != b'\n' {
+            file.write_all(b"\n")
+                .map_err(|err| format!("Unable to write audit log {}: {err}", path.display()))?;
+        }
+
+        Ok(Self {
+            path,
+            writer: Mutex::new(AuditLogWriter {
+                file,
+                next_sequence,
+                last_hash,
+            }),
+        })
+    }
+
+    /// Appends a record and waits for it to reach the disk. Returns the
+    /// record's sequence.
+    pub fn record(&self, entry: &AuditEntry) -> Result<u64, String> {
+        let mut writer = self.writer.lock().unwrap();
+        let sequence = writer.next_sequence;
+        let body = serde_json::to_string(&ChainedEntry {
+            sequence,
+            entry,
+            prev_hash: &writer.last_hash,
+        })
+        .map_err(|err| err.to_string())?;
+        let hash = get_sha256_hex(&body);
+        let line = format!(
+            "{},\"hash\":\"{hash}\"}}\n",
+            body.strip_suffix('}').unwrap_or(&body)
+        );
+
+        writer
+            .file
+            .write_all(line.as_bytes())
+            .and_then(|()| writer.file.sync_data())
+            .map_err(|err| format!("Unable to write audit log {}: {err}", self.path.display()))?;
+        writer.next_sequence += 1;
+        writer.last_hash = hash;
+        Ok(sequence)
+    }
+
+    /// Returns the records selected by `query`, oldest first, and checks the
+    /// whole chain along the way. Records appended while the log is being read
+    /// aren't included.
+    pub fn query(&self, query: &AuditQuery) -> Result<AuditQueryResult, String> {
+        // Records are only appended while the writer is held, so its length
+        // always ends at a complete record. Reading happens without it, so that
+        // commands finishing in the meantime aren't held up.
+        let length = self
+            .writer
+            .lock()
+            .unwrap()
+            .file
+            .metadata()
+            .map_err(|err| format!("Unable to read audit log {}: {err}", self.path.display()))?
+            .len();
+        let file = File::open(&self.path)
+            .map_err(|err| format!("Unable to open audit log {}: {err}", self.path.display()))?;
+
+        let mut result = AuditQueryResult {
+            records: Vec::new(),
+            chain_intact: true,
+            first_broken_line: None,
+        };
+        let mut expected_sequence = 0;
+        let mut expected_prev_hash = String::from(GENESIS_HASH);
+        for (i, line) in BufReader::new(file.take(length)).split(b'\n').enumerate() {
+            let line = line.map_err(|err| {
+                format!("Unable to read audit log {}: {err}", self.path.display())
+            })?;
+            let record = String::from_utf8(line)
+                .ok()
+                .and_then(|line| get_verified_record(&line));
+            let Some((record, hash)) = record else {
+                result.mark_broken(i + 1);
+                continue;
+            };
+
+            if record
+                .get("sequence")
+                .and_then(|sequence| sequence.as_u64())
+                != Some(expected_sequence)
+                || record
+                    .get("prevHash")
+                    .and_then(|prev_hash| prev_hash.as_str())
+                    != Some(expected_prev_hash.as_str())
+            {
+                result.mark_broken(i + 1);
+            }
+            expected_sequence = record
+                .get("sequence")
+                .and_then(|sequence| sequence.as_u64())
+                .map_or(expected_sequence, |sequence| sequence + 1);
+            expected_prev_hash = hash;
+
+            if query.matches(&record) {
+                result.records.push(record);
+            }
+        }
+        Ok(result)
+    }
+}
+
+impl AuditQuery {
+    fn matches(&self, record: &serde_json::Value) -> bool {
+        let timestamp = record
+            .get("timestamp")
+            .and_then(|timestamp| timestamp.as_u64())
+            .unwrap_or(0);
+        if self.from.is_some_and(|from| timestamp < from)
+            || self.to.is_some_and(|to| timestamp > to)
+        {
+            return false;
+        }
+
+        let Some(query_command) = &self.command else {
+            return true;
+        };
+        let command = record
+            .get("command")
+            .and_then(|command| command.as_str())
+            .unwrap_or("");
+        command == query_command || command.rsplit(':').next() == Some(query_command.as_str())
+    }
+}
+
+impl AuditQueryResult {
+    fn mark_broken(&mut self, line_number: usize) {
+        self.chain_intact = false;
+        self.first_broken_line.get_or_insert(line_number);
+    }
+}
+
+/// Parses a line of the log, returning the record and its hash if the hash
+/// matches the rest of the line. Blank lines, such as the one left behind by a
+/// partly written record, count as broken too.
+fn get_verified_record(line: &str) -> Option<(serde_json::Value, String)> {
+    let (body, hash) = line.strip_suffix("\"}")?.rsplit_once(",\"hash\":\"")?;
+    let body = format!("{body}}}");
+    if get_sha256_hex(&body) != hash {
+        return None;
+    }
+    Some((serde_json::from_str(line).ok()?, hash.to_string()))
+}
+
+fn get_sha256_hex(data: &str) -> String {
+    Sha256::digest(data.as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+
+    fn get_temp_path() -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "audit_log_{:016X}.jsonl",
+            RandomState::new().build_hasher().finish()
+        ))
+    }
+
+    fn record_command(audit_log: &AuditLog, timestamp: u64, command: &str) {
+        audit_log
+            .record(&AuditEntry {
+                timestamp,
+                duration_ms: 5,
+                namespace: "arduino:1",
+                command,
+                value_type: CommandValueType::Null,
+                arguments: &CommandArguments::new(),
+                request_id: Some("request-1"),
+                context: Some("lnbc1invoice"),
+                attempts: 1,
+                outcome: "success",
+                started_sequence: None,
+                result: Some(&CommandValue::Null),
+                error: None,
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn chains_records_across_restarts() {
+        let path = get_temp_path();
+        record_command(&AuditLog::open(&path).unwrap(), 1000, "arduino:1:vend");
+        let audit_log = AuditLog::open(&path).unwrap();
+        record_command(&audit_log, 2000, "arduino:1:stepper0HasInventory");
+        record_command(&audit_log, 3000, "left-column:vend");
+
+        let result = audit_log.query(&AuditQuery::default()).unwrap();
+        assert!(result.chain_intact);
+        assert_eq!(result.records.len(), 3);
+        assert_eq!(result.records[1]["sequence"], 1);
+        assert_eq!(result.records[1]["prevHash"], result.records[0]["hash"]);
+        assert_eq!(result.records[2]["context"], "lnbc1invoice");
+
+        let result = audit_log
+            .query(&AuditQuery {
+                from: Some(1000),
+                to: Some(2999),
+                command: Some(String::from("vend")),
+            })
+            .unwrap();
+        assert_eq!(result.records.len(), 1);
+        assert_eq!(result.records[0]["command"], "arduino:1:vend");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn detects_tampering() {
+        let path = get_temp_path();
+        let audit_log = AuditLog::open(&path).unwrap();
+        for timestamp in [1000, 2000, 3000] {
+            record_command(&audit_log, timestamp, "arduino:1:vend");
+        }
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, contents.replacen("\"success\"", "\"timeout\"", 1)).unwrap();
+        let result = audit_log.query(&AuditQuery::default()).unwrap();
+        assert!(!result.chain_intact);
+        assert_eq!(result.first_broken_line, Some(1));
+
+        let lines: Vec<&str> = contents.lines().collect();
+        std::fs::write(&path, format!("{}\n{}\n", lines[0], lines[2])).unwrap();
+        let result = audit_log.query(&AuditQuery::default()).unwrap();
+        assert!(!result.chain_intact);
+        assert_eq!(result.first_broken_line, Some(2));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,275 @@
+//! Emulates a LiVeACE sticker machine on a pseudo-terminal, so that the server
+//! and kiosk can be run without any hardware. Pass the printed port path (or
+//! the `--link` path) to the server through `LIVEACE_EXTRA_PORTS` or the
+//! `discovery.include_ports` option.
+//!
+//! While running, the emulated board can be controlled by typing commands into
+//! stdin. See `print_usage` for the available options and commands.
+//!
+//! Besides its steppers, the board can emulate any other commands, which are
+//! declared in a TOML file passed with `--commands`:
+//!
+//! ```toml
+//! [[commands]]
+//! name = "temperature"
+//! type = "float"
+//! response = "21.5"
+//!
+//! # `{sku}` and `{copies}` are replaced by the arguments as they were sent.
+//! [[commands]]
+//! name = "printLabel"
+//! type = "json"
+//! response = '{"sku": "{sku}", "copies": {copies}}'
+//! args = [
+//!     { name = "sku", type = "string" },
+//!     { name = "copies", type = "integer", min = 1, max = 10 },
+//! ]
+//! ```
+
+// Shared with the server's tests, which use parts of it that this binary doesn't.
+#[allow(dead_code)]
+#[path = "../liveace_simulator.rs"]
+mod liveace_simulator;
+
+use liveace_simulator::{SimulatedBoard, SimulatedCommand, SimulatorConfig};
+use rocket::figment::providers::{Format, Toml};
+use rocket::figment::Figment;
+use serialport::{SerialPort, TTYPort};
+use std::io::{BufRead, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (config, link_path) = match parse_args(&args) {
+        Ok(parsed_args) => parsed_args,
+        Err(err) => {
+            eprintln!("{err}");
+            eprintln!();
+            print_usage();
+            std::process::exit(2);
+        }
+    };
+
+    let (mut master, slave) = TTYPort::pair().expect("Unable to open a pseudo-terminal");
+    let port_path = slave.name().expect("Pseudo-terminal has no name");
+    // The slave side is kept open so that reads from the master side don't
+    // fail while the server has the port closed.
+    let _slave = slave;
+    master
+        .set_timeout(Duration::from_millis(5))
+        .expect("Unable to set pseudo-terminal timeout");
+
+    if let Some(link_path) = &link_path {
+        // Replaces the link left behind by a previous run, if any.
+        let _ = std::fs::remove_file(link_path);
+        std::os::unix::fs::symlink(&port_path, link_path).expect("Unable to create link");
+    }
+
+    println!("Emulating a LiVeACE board on {port_path}");
+    if let Some(link_path) = &link_path {
+        println!("Linked to {link_path}");
+    }
+    println!("Type 'help' for a list of commands.");
+
+    let board = Arc::new(Mutex::new(SimulatedBoard::new(config, Instant::now())));
+
+    let control_board = board.clone();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            match line {
+                Ok(line) => handle_control_command(&control_board, &line),
+                Err(_) => return,
+            }
+        }
+    });
+
+    let mut buffer = [0; 1024];
+    loop {
+        match master.read(&mut buffer) {
+            Ok(bytes_read) => board
+                .lock()
+                .unwrap()
+                .receive_bytes(&buffer[..bytes_read], Instant::now()),
+            Err(err) if err.kind() == std::io::ErrorKind::TimedOut => {}
+            Err(err) => panic!("Unable to read from pseudo-terminal: {err}"),
+        }
+
+        let output = {
+            let mut board = board.lock().unwrap();
+            let now = Instant::now();
+            let readable_byte_count = board.get_readable_byte_count(now);
+            board.take_output(readable_byte_count, now)
+        };
+        if !output.is_empty() {
+            master
+                .write_all(&output)
+                .expect("Unable to write to pseudo-terminal");
+        }
+    }
+}
+
+fn parse_args(args: &[String]) -> Result<(SimulatorConfig, Option<String>), String> {
+    let mut config = SimulatorConfig::default();
+    let mut link_path = None;
+    let mut stepper_count = config.initial_inventory.len();
+    let mut inventory = config.initial_inventory[0];
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut get_value = || {
+            args.next()
+                .ok_or_else(|| format!("Missing value for '{arg}'"))
+        };
+        match arg.as_str() {
+            "--help" => {
+                print_usage();
+                std::process::exit(0);
+            }
+            "--link" => link_path = Some(get_value()?.clone()),
+            "--steppers" => stepper_count = parse_value(arg, get_value()?)?,
+            "--inventory" => inventory = parse_value(arg, get_value()?)?,
+            "--vend-ms" => config.vend_duration = parse_millis(arg, get_value()?)?,
+            "--homing-timeout-ms" => config.homing_timeout = parse_millis(arg, get_value()?)?,
+            "--boot-ms" => config.boot_duration = parse_millis(arg, get_value()?)?,
+            "--byte-drop-rate" => config.byte_drop_rate = parse_value(arg, get_value()?)?,
+            "--byte-corruption-rate" => {
+                config.byte_corruption_rate = parse_value(arg, get_value()?)?
+            }
+            "--seed" => config.seed = parse_value(arg, get_value()?)?,
+            "--rx-buffer-size" => config.rx_buffer_size = parse_value(arg, get_value()?)?,
+            "--no-handshake" => config.supports_handshake = false,
+            "--no-checksums" => config.supports_checksums = false,
+            "--no-events" => config.supports_events = false,
+            "--device-id" => config.device_id = Some(get_value()?.clone()),
+            "--commands" => config.extra_commands = load_extra_commands(get_value()?)?,
+            "--no-device-id" => config.supports_device_id = false,
+            "--no-boot-id" => config.supports_boot_id = false,
+            "--liveace-1.0" => {
+                config.supports_handshake = false;
+                config.supports_request_ids = false;
+            }
+            _ => return Err(format!("Unknown option '{arg}'")),
+        }
+    }
+
+    config.initial_inventory = vec![inventory; stepper_count];
+    Ok((config, link_path))
+}
+
+/// The file passed with `--commands`.
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ExtraCommandsFile {
+    commands: Vec<SimulatedCommand>,
+}
+
+fn load_extra_commands(path: &str) -> Result<Vec<SimulatedCommand>, String> {
+    let contents =
+        std::fs::read_to_string(path).map_err(|err| format!("Unable to read '{path}': {err}"))?;
+    let extra_commands_file: ExtraCommandsFile =
+        Figment::from(Toml::string(&contents))
+            .extract()
+            .map_err(|err| format!("Invalid commands in '{path}': {err}"))?;
+
+    for command in &extra_commands_file.commands {
+        if !["null", "boolean", "integer", "float", "string", "json"]
+            .contains(&command.value_type.as_str())
+        {
+            return Err(format!(
+                "Invalid type '{}' for command '{}' in '{path}'",
+                command.value_type, command.name
+            ));
+        }
+        if let Some(arg) = command.args.iter().find(|arg| {
+            !["integer", "float", "boolean", "string"].contains(&arg.argument_type.as_str())
+        }) {
+            return Err(format!(
+                "Invalid type '{}' for argument '{}' of command '{}' in '{path}'",
+                arg.argument_type, arg.name, command.name
+            ));
+        }
+    }
+    Ok(extra_commands_file.commands)
+}
+
+fn parse_value<T: std::str::FromStr>(arg: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Invalid value '{value}' for '{arg}'"))
+}
+
+fn parse_millis(arg: &str, value: &str) -> Result<Duration, String> {
+    parse_value(arg, value).map(Duration::from_millis)
+}
+
+fn handle_control_command(board: &Mutex<SimulatedBoard>, line: &str) {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let mut board = board.lock().unwrap();
+    let stepper_count = board.get_stepper_count();
+    let parse_index = |word: &str| {
+        word.parse::<usize>()
+            .ok()
+            .filter(|stepper| *stepper < stepper_count)
+    };
+
+    match words.as_slice() {
+        [] => {}
+        ["inventory", stepper, count] => match (parse_index(stepper), count.parse()) {
+            (Some(stepper), Ok(count)) => board.set_inventory(stepper, count),
+            _ => println!("Usage: inventory <stepper> <count>"),
+        },
+        ["break", stepper] | ["fix", stepper] => match parse_index(stepper) {
+            Some(stepper) => board.set_homing_switch_broken(stepper, words[0] == "break"),
+            None => println!("Usage: {} <stepper>", words[0]),
+        },
+        ["drop", count] => match count.parse() {
+            Ok(count) => board.drop_next_responses(count),
+            Err(_) => println!("Usage: drop <count>"),
+        },
+        ["reboot"] => board.reboot(Instant::now()),
+        ["vends", stepper] => match parse_index(stepper) {
+            Some(stepper) => println!("{}", board.get_vend_count(stepper)),
+            None => println!("Usage: vends <stepper>"),
+        },
+        ["help"] => print_control_commands(),
+        _ => println!("Unknown command '{line}'. Type 'help' for a list of commands."),
+    }
+}
+
+fn print_usage() {
+    println!("Usage: liveace_emulator [options]");
+    println!();
+    println!("Options:");
+    println!("  --link <path>              Create a symlink to the emulated port");
+    println!("  --steppers <count>         Number of steppers (default 2)");
+    println!("  --inventory <count>        Items loaded into each stepper (default 10)");
+    println!("  --vend-ms <ms>             How long a vend takes");
+    println!("  --homing-timeout-ms <ms>   How long a vend takes to fail");
+    println!("  --boot-ms <ms>             How long the board ignores input after a reboot");
+    println!("  --byte-drop-rate <rate>    Chance of losing each byte sent to the board");
+    println!("  --byte-corruption-rate <rate>");
+    println!("                             Chance of garbling each byte sent by the board");
+    println!("  --seed <seed>              Seed for deciding which bytes are lost or garbled");
+    println!("  --rx-buffer-size <bytes>   Size of the board's receive buffer (default 64)");
+    println!("  --no-checksums             Don't support checksummed frames");
+    println!("  --no-events                Don't push events");
+    println!("  --device-id <id>           Device id stored in the emulated EEPROM");
+    println!("  --no-device-id             Don't support storing a device id");
+    println!("  --no-boot-id               Don't report a boot id");
+    println!("  --commands <file>          Emulate the commands declared in a TOML file");
+    println!("  --no-handshake             Emulate LiVeACE 1.1 firmware without `hello`");
+    println!("  --liveace-1.0              Emulate LiVeACE 1.0 firmware");
+    println!();
+    print_control_commands();
+}
+
+fn print_control_commands() {
+    println!("Commands:");
+    println!("  inventory <stepper> <count>   Set the number of items in a stepper");
+    println!("  break <stepper>               Break a stepper's homing switch");
+    println!("  fix <stepper>                 Fix a stepper's homing switch");
+    println!("  drop <count>                  Lose the next <count> responses");
+    println!("  reboot                        Reset the board");
+    println!("  vends <stepper>               Print how many items a stepper has vended");
+}
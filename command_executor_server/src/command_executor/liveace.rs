@@ -1,25 +1,289 @@
 use serialport::SerialPort;
-use std::collections::HashSet;
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet};
+use std::hash::{BuildHasher, Hasher};
 use std::io::{Read, Write};
-use std::sync::Mutex;
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use crate::command_executor::{CommandExecutor, NamespacedCommandExecutor};
+use crate::command_executor::{
+    CommandArgumentSpec, CommandArgumentValue, CommandArguments, CommandExecutor,
+    CommandExecutorError, CommandSpec, CommandValue, CommandValueType, DeviceInfo, ExecutorStats,
+    NamespacedCommandExecutor,
+};
+use crate::events::EventBus;
+
+/// The error code used for device errors reported by firmware that doesn't send
+/// error codes, such as LiVeACE 1.0 firmware.
+pub const DEVICE_ERROR_CODE_UNKNOWN: &str = "unknown";
+
+/// The error code that firmware reports when it can't accept a command right
+/// now, such as while it's still finishing a previous one.
+const DEVICE_ERROR_CODE_BUSY: &str = "busy";
+
+/// The error code that firmware reports when a request's checksum doesn't
+/// match its contents. The request isn't executed, so it's safe to resend.
+const DEVICE_ERROR_CODE_CHECKSUM_MISMATCH: &str = "checksumMismatch";
+
+/// How long to wait for a response to the handshake, or when checking whether a
+/// board that doesn't support the handshake speaks LiVeACE 1.1. The board has
+/// already answered `listCommands` by then, so it only needs to be long enough
+/// for the board to process a single command.
+const PROTOCOL_PROBE_TIMEOUT: Duration = Duration::from_millis(2000);
+
+/// How long to keep waiting for a response after receiving a corrupted line.
+/// The corrupted line may have been a late response to an earlier request, so
+/// the wait isn't given up right away, but it may also have been the awaited
+/// response, so the wait isn't drawn out either.
+const CORRUPTED_FRAME_GRACE_PERIOD: Duration = Duration::from_millis(250);
+
+/// How long the reader thread blocks waiting for data before checking whether
+/// it should stop.
+const READER_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// The longest delay between bytes written to boards that didn't report their
+/// receive buffer size.
+const MAX_LEGACY_BYTE_DELAY: Duration = Duration::from_millis(4);
+
+/// The number of consecutive answered requests after which the delay between
+/// bytes written to boards that didn't report their receive buffer size is
+/// halved.
+const LEGACY_PACING_RELAX_STREAK: u32 = 50;
+
+/// The longest device id that firmware stores in its EEPROM.
+const MAX_DEVICE_ID_LENGTH: usize = 32;
 
 #[derive(serde::Deserialize, Debug)]
 struct ArduinoCommandResponse {
     status: String,
     command: String,
+    /// The request id echoed back by LiVeACE 1.1 firmware. Always `None` for
+    /// LiVeACE 1.0 firmware.
+    #[serde(default)]
+    id: Option<u32>,
+    /// A machine-readable error code, only sent alongside non-ok statuses.
+    /// Always `None` for LiVeACE 1.0 firmware.
+    #[serde(default)]
+    code: Option<String>,
+    /// The command's return value, or a human-readable error message for
+    /// non-ok statuses.
     response: Option<serde_json::Value>,
 }
 
+/// An unsolicited line sent by firmware that supports events. Never confused
+/// with a response, since responses always have a `status`.
+#[derive(serde::Deserialize, Debug)]
+struct ArduinoEvent {
+    event: String,
+    #[serde(default)]
+    data: serde_json::Value,
+}
+
+/// The version of the LiVeACE protocol spoken by a board.
+///
+/// * LiVeACE 1.0 - Requests are sent as `{command}\n` and responses are matched
+///   to requests by the `command` they echo back, so only one request can be
+///   reliably outstanding at a time.
+/// * LiVeACE 1.1 - Requests are sent as `#{id} {command}\n` and the firmware
+///   echoes `id` back in its response, so responses can be matched to the
+///   exact request that produced them. Requests may also carry an idempotency
+///   key as `#{id} !{key} {command}\n`. The firmware remembers the outcome of
+///   recent keyed requests, replays it instead of executing a request whose key
+///   it has already seen, and reports it through `idempotencyKeyStatus {key}`.
+///   Remembered keys are lost when the board is reset, so newer firmware
+///   reports a `bootId` that changes on every reset, both in its response to
+///   `hello` and in its responses to `idempotencyKeyStatus`. Keys unknown to
+///   firmware that doesn't report it are never assumed to be unsent.
+///   1.1 firmware also accepts 1.0 requests.
+///
+/// Newer firmware describes itself and the features it supports in response to
+/// `hello`. Older firmware responds to `hello` with an error, so its protocol
+/// version is detected by probing instead.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum ProtocolVersion {
+    V1_0,
+    V1_1,
+}
+
+impl ProtocolVersion {
+    fn get_name(&self) -> &'static str {
+        match self {
+            Self::V1_0 => "1.0",
+            Self::V1_1 => "1.1",
+        }
+    }
+}
+
+/// An optional protocol feature that a board can advertise in its response to
+/// `hello`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+enum Capability {
+    /// Requests can be framed with a request id, as in LiVeACE 1.1.
+    RequestIds,
+    /// Requests can carry an idempotency key, and `idempotencyKeyStatus` is
+    /// supported, as in LiVeACE 1.1.
+    IdempotencyKeys,
+    /// The board sends unsolicited event lines, such as
+    /// `{"event": "inventoryChanged", "data": {"stepper": 0, "hasInventory": false}}`,
+    /// once `useEvents` has been sent. Event lines carry a checksum whenever
+    /// responses do.
+    Events,
+    /// Commands returned by `listCommands` can declare arguments, which are
+    /// sent after the command's name. Commands that declare arguments on
+    /// boards that don't advertise this are never sent.
+    Arguments,
+    /// Frames carry a checksum, as `{frame}*{crc}` where `crc` is the frame's
+    /// CRC-16/CCITT-FALSE as 4 uppercase hex digits. The board responds to
+    /// checksummed requests with checksummed responses. Once `useChecksums`
+    /// has been sent, the board also requires every request to carry a
+    /// checksum until it is reset. Requests whose checksum is missing or
+    /// doesn't match get a `checksumMismatch` error without being executed.
+    Checksums,
+    /// The board stores a device id in its EEPROM, which it reports as
+    /// `boardId` in its response to `hello`. `setDeviceId <id>` stores a new
+    /// id, and `getDeviceId` returns the stored one, or null if the board
+    /// hasn't been provisioned.
+    DeviceId,
+}
+
+impl Capability {
+    const ALL: [Capability; 6] = [
+        Capability::RequestIds,
+        Capability::IdempotencyKeys,
+        Capability::Events,
+        Capability::Arguments,
+        Capability::Checksums,
+        Capability::DeviceId,
+    ];
+
+    fn get_name(&self) -> &'static str {
+        match self {
+            Self::RequestIds => "requestIds",
+            Self::IdempotencyKeys => "idempotencyKeys",
+            Self::Events => "events",
+            Self::Arguments => "arguments",
+            Self::Checksums => "checksums",
+            Self::DeviceId => "deviceId",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|capability| capability.get_name() == name)
+    }
+}
+
+/// The response to `hello`.
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct HandshakeResponse {
+    protocol: String,
+    #[serde(default)]
+    firmware: Option<String>,
+    #[serde(default)]
+    build: Option<String>,
+    #[serde(default)]
+    board_id: Option<String>,
+    /// Changes every time the board is reset.
+    #[serde(default)]
+    boot_id: Option<String>,
+    /// May include capabilities that the server doesn't know about, which are
+    /// ignored.
+    #[serde(default)]
+    capabilities: Vec<String>,
+    /// The size of the board's serial receive buffer in bytes.
+    #[serde(default)]
+    rx_buffer_size: Option<usize>,
+}
+
+/// An entry in one of the command lists returned by `listCommands`. Commands
+/// without arguments are listed by name only, as in LiVeACE 1.0.
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum ListedCommand {
+    Name(String),
+    Detailed {
+        name: String,
+        #[serde(default)]
+        args: Vec<CommandArgumentSpec>,
+        #[serde(default)]
+        actuating: Option<bool>,
+    },
+}
+
+impl ListedCommand {
+    fn into_command_spec(self, value_type: CommandValueType) -> CommandSpec {
+        // LiVeACE 1.0 has no way of marking commands as actuating, but its
+        // actuating commands (such as moving a stepper motor) are exactly the
+        // ones that return null.
+        let default_actuating = value_type == CommandValueType::Null;
+        match self {
+            ListedCommand::Name(name) => CommandSpec {
+                name,
+                arguments: Vec::new(),
+                actuating: default_actuating,
+            },
+            ListedCommand::Detailed {
+                name,
+                args,
+                actuating,
+            } => CommandSpec {
+                name,
+                arguments: args,
+                actuating: actuating.unwrap_or(default_actuating),
+            },
+        }
+    }
+}
+
+/// The response to `idempotencyKeyStatus`, reporting whether the board has
+/// executed a command sent with a given idempotency key.
+#[derive(serde::Deserialize)]
+#[serde(tag = "state", rename_all = "camelCase")]
+enum IdempotencyKeyStatus {
+    Unknown {
+        /// The boot that doesn't know the key. A key that is unknown to a
+        /// later boot than the one that received it may have been executed
+        /// before the board was reset.
+        #[serde(default, rename = "bootId")]
+        boot_id: Option<String>,
+    },
+    Done {
+        ok: bool,
+        #[serde(default)]
+        code: Option<String>,
+        #[serde(default)]
+        response: Option<serde_json::Value>,
+    },
+}
+
 #[derive(Debug)]
 pub enum SerialError {
     Timeout,
     MalformedResponse,
-    NonOkStatus,
+    /// A frame failed its checksum, either on its way to the board or on its
+    /// way back. The request may have to be resent.
+    CorruptedFrame,
+    /// The board received the command but reported that it failed.
+    DeviceError {
+        /// Machine-readable error code reported by the board, or
+        /// `DEVICE_ERROR_CODE_UNKNOWN` if the board didn't report one.
+        code: String,
+        /// Human-readable error message reported by the board.
+        message: String,
+    },
     IoError(std::io::Error),
     SerialPortError(serialport::Error),
+    /// The board has no USB serial number that can be trusted, and its
+    /// firmware can't store a device id either, so it can't be given a stable
+    /// namespace.
+    NoDeviceId,
+    /// The board was reset while a command that must not run twice was
+    /// outstanding, or can't rule out that it was, so whether the command ran
+    /// can't be determined.
+    BoardReset,
 }
 
 impl std::fmt::Display for SerialError {
@@ -27,52 +291,138 @@ impl std::fmt::Display for SerialError {
         match self {
             Self::Timeout => write!(f, "Timeout"),
             Self::MalformedResponse => write!(f, "MalformedResponse"),
-            Self::NonOkStatus => write!(f, "NonOkStatus"),
+            Self::CorruptedFrame => write!(f, "CorruptedFrame"),
+            Self::DeviceError { code, message } => write!(f, "DeviceError({code}: {message})"),
             Self::IoError(io_err) => write!(f, "IoError({io_err})"),
             Self::SerialPortError(serial_port_err) => {
                 write!(f, "SerialPortError({serial_port_err})")
             }
+            Self::NoDeviceId => write!(f, "NoDeviceId"),
+            Self::BoardReset => write!(f, "BoardReset"),
         }
     }
 }
 
 impl std::error::Error for SerialError {}
 
+impl From<SerialError> for CommandExecutorError {
+    fn from(serial_error: SerialError) -> Self {
+        match serial_error {
+            SerialError::Timeout => Self::Timeout,
+            SerialError::BoardReset => Self::Indeterminate,
+            SerialError::MalformedResponse | SerialError::CorruptedFrame => Self::MalformedResponse,
+            SerialError::DeviceError { code, .. } if code == DEVICE_ERROR_CODE_BUSY => Self::Busy,
+            SerialError::DeviceError { code, message } => Self::DeviceError { code, message },
+            SerialError::IoError(_) | SerialError::SerialPortError(_) | SerialError::NoDeviceId => {
+                Self::ConnectionError(serial_error.to_string())
+            }
+        }
+    }
+}
+
+/// How persistently requests are resent when their responses don't arrive.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// How long to wait for a response before resending a request.
+    pub timeout_to_retry: Duration,
+    /// How many times a request is resent before giving up.
+    pub max_retries: u32,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            timeout_to_retry: Duration::from_millis(20000),
+            max_retries: 10,
+        }
+    }
+}
+
 pub struct LiVeAceSerialPort {
     port: Mutex<Box<dyn SerialPort>>,
     namespace: String,
-    null_commands: HashSet<String>,
-    bool_commands: HashSet<String>,
+    commands: HashMap<CommandValueType, HashMap<String, CommandSpec>>,
+    protocol_version: ProtocolVersion,
+    /// The optional protocol features that both the board and the server
+    /// support.
+    capabilities: HashSet<Capability>,
+    device_info: DeviceInfo,
+    /// The boot id that the board reported during the handshake, if its
+    /// firmware reports one.
+    boot_id: Option<String>,
+    stats: Arc<ExecutorStats>,
+    /// The size of the board's serial receive buffer, if the board reported
+    /// it during the handshake. Frames that fit are written all at once.
+    rx_buffer_size: Option<usize>,
+    /// The delay between bytes written to boards that didn't report their
+    /// receive buffer size. Starts at zero, and is raised whenever a request
+    /// goes unanswered in case the board dropped some of its bytes.
+    legacy_byte_delay: Duration,
+    /// The number of consecutive requests answered since `legacy_byte_delay`
+    /// last changed.
+    legacy_success_streak: u32,
+    next_request_id: u32,
+    /// Random prefix for idempotency keys, so that keys from a previous server
+    /// run can't be mistaken for new ones by a board that wasn't reset.
+    idempotency_key_prefix: u32,
+    next_idempotency_key: u32,
+    /// The lines sent for requests that haven't been answered yet, keyed by
+    /// request id. A request stays outstanding after its attempt times out
+    /// until the command it belongs to finishes, so that a late response to an
+    /// earlier attempt can still be recognized.
+    outstanding_requests: HashMap<u32, String>,
+    /// Responses to outstanding requests that arrived while waiting for a
+    /// different request.
+    unclaimed_responses: HashMap<u32, ArduinoCommandResponse>,
+    line_reader: LineReader,
     timeout_to_retry: Duration,
     max_retries: u32,
 }
 
 impl CommandExecutor for LiVeAceSerialPort {
-    fn get_null_commands(&self) -> Box<dyn Iterator<Item = &str> + '_> {
-        Box::from(self.null_commands.iter().map(|s| s.as_str()))
-    }
-
-    fn execute_null_command(&mut self, command: &str) -> Result<(), Box<dyn std::error::Error>> {
-        match self.execute_command_internal(command).map_err(Box::from) {
-            Ok(None) => Ok(()),
-            Err(err) => Err(err),
-            _ => Err(Box::from(SerialError::MalformedResponse)),
+    fn get_commands(
+        &self,
+        value_type: CommandValueType,
+    ) -> Box<dyn Iterator<Item = &CommandSpec> + '_> {
+        match self.commands.get(&value_type) {
+            Some(commands) => Box::from(commands.values()),
+            None => Box::from(std::iter::empty()),
         }
     }
 
-    fn get_bool_commands(&self) -> Box<dyn Iterator<Item = &str> + '_> {
-        Box::from(self.bool_commands.iter().map(|s| s.as_str()))
-    }
+    fn execute_command(
+        &mut self,
+        command: &str,
+        value_type: CommandValueType,
+        arguments: &CommandArguments,
+    ) -> Result<CommandValue, CommandExecutorError> {
+        let command_spec = match self
+            .commands
+            .get(&value_type)
+            .and_then(|commands| commands.get(command))
+        {
+            Some(command_spec) => command_spec,
+            None => return Err(CommandExecutorError::UnknownCommand),
+        };
+        if !command_spec.arguments.is_empty() && !self.capabilities.contains(&Capability::Arguments)
+        {
+            return Err(CommandExecutorError::InvalidArguments(String::from(
+                "The board doesn't support command arguments",
+            )));
+        }
+        let argument_values = command_spec
+            .validate_arguments(arguments)
+            .map_err(CommandExecutorError::InvalidArguments)?;
+        let encoded_command = encode_command(command, &argument_values);
 
-    fn execute_bool_command(&mut self, command: &str) -> Result<bool, Box<dyn std::error::Error>> {
-        let res = match self.execute_command_internal(command).map_err(Box::from) {
-            Ok(Some(res)) => res,
-            Ok(None) => return Err(Box::from(SerialError::MalformedResponse)),
-            Err(err) => return Err(err),
+        let res = if command_spec.actuating {
+            self.execute_actuating_command_internal(&encoded_command)?
+        } else {
+            self.execute_command_internal(&encoded_command)?
         };
-        match res {
-            serde_json::Value::Bool(b) => Ok(b),
-            _ => Err(Box::from(SerialError::MalformedResponse)),
+        match value_type.parse_value(res) {
+            Some(value) => Ok(value),
+            None => Err(CommandExecutorError::MalformedResponse),
         }
     }
 }
@@ -81,179 +431,1729 @@ impl NamespacedCommandExecutor for LiVeAceSerialPort {
     fn get_executor_namespace(&self) -> &str {
         &self.namespace
     }
+
+    fn get_device_info(&self) -> DeviceInfo {
+        self.device_info.clone()
+    }
+
+    fn get_stats(&self) -> Arc<ExecutorStats> {
+        self.stats.clone()
+    }
 }
 
 impl LiVeAceSerialPort {
+    /// Connects to the board on the given port. Its executor namespace is
+    /// derived from `board_serial_number`, or from the device id stored on the
+    /// board if the serial number is `None` because it's missing or can't be
+    /// trusted. Boards without a device id are assigned one. The handshake
+    /// follows `probe_policy`, which should give up much sooner than
+    /// `retry_policy`, since the device may not be a LiVeACE board at all.
     pub fn new(
         port: Box<dyn SerialPort>,
-        board_serial_number: String,
+        board_serial_number: Option<String>,
+        event_bus: Arc<EventBus>,
+        retry_policy: RetryPolicy,
+        probe_policy: RetryPolicy,
     ) -> Result<Self, SerialError> {
+        let stats = Arc::new(ExecutorStats::default());
+        let line_reader = LineReader::spawn(
+            port.try_clone().map_err(SerialError::SerialPortError)?,
+            event_bus,
+            stats.clone(),
+        )?;
         let mut p = Self {
             port: Mutex::from(port),
-            namespace: format!("arduino:{board_serial_number}"),
-            null_commands: HashSet::new(),
-            bool_commands: HashSet::new(),
-            timeout_to_retry: Duration::from_millis(20000),
-            max_retries: 10,
+            namespace: String::new(),
+            commands: HashMap::new(),
+            protocol_version: ProtocolVersion::V1_0,
+            capabilities: HashSet::new(),
+            device_info: DeviceInfo {
+                protocol_version: ProtocolVersion::V1_0.get_name().to_string(),
+                firmware_name: None,
+                firmware_build: None,
+                board_id: None,
+                capabilities: Vec::new(),
+            },
+            boot_id: None,
+            stats,
+            rx_buffer_size: None,
+            legacy_byte_delay: Duration::ZERO,
+            legacy_success_streak: 0,
+            next_request_id: 0,
+            idempotency_key_prefix: RandomState::new().build_hasher().finish() as u32,
+            next_idempotency_key: 0,
+            outstanding_requests: HashMap::new(),
+            unclaimed_responses: HashMap::new(),
+            line_reader,
+            timeout_to_retry: probe_policy.timeout_to_retry,
+            max_retries: probe_policy.max_retries,
         };
 
+        // Every LiVeACE version understands 1.0 requests, so commands are
+        // listed using 1.0 before negotiating which features to use. Listing
+        // commands is retried while the board boots, so the handshake can
+        // expect a prompt response.
         p.get_commands_internal()?;
+        p.negotiate_capabilities();
+
+        let device_id = match board_serial_number {
+            Some(board_serial_number) => board_serial_number,
+            None => p.get_or_assign_device_id()?,
+        };
+        p.namespace = format!("arduino:{device_id}");
+        p.line_reader.set_namespace(&p.namespace);
+        // Events are published under the executor's namespace, so they're only
+        // turned on once it's known.
+        p.enable_capability(Capability::Events, "useEvents");
 
+        p.timeout_to_retry = retry_policy.timeout_to_retry;
+        p.max_retries = retry_policy.max_retries;
         Ok(p)
     }
 
+    /// Performs the handshake and enables the features that the board
+    /// advertises. Falls back to detecting the protocol version if the board
+    /// doesn't support the handshake.
+    fn negotiate_capabilities(&mut self) {
+        match self.request_handshake() {
+            Ok(handshake) => {
+                self.capabilities = handshake
+                    .capabilities
+                    .iter()
+                    .filter_map(|name| Capability::from_name(name))
+                    .collect();
+                self.protocol_version = if self.capabilities.contains(&Capability::RequestIds) {
+                    ProtocolVersion::V1_1
+                } else {
+                    ProtocolVersion::V1_0
+                };
+                self.rx_buffer_size = handshake.rx_buffer_size.filter(|size| *size > 0);
+                self.boot_id = handshake.boot_id;
+                self.device_info = DeviceInfo {
+                    protocol_version: handshake.protocol,
+                    firmware_name: handshake.firmware,
+                    firmware_build: handshake.build,
+                    board_id: handshake.board_id,
+                    capabilities: handshake.capabilities,
+                };
+                // Checksums are enabled first, so that the rest of the
+                // negotiation is already checksummed.
+                self.enable_capability(Capability::Checksums, "useChecksums");
+                self.line_reader
+                    .set_checksums_required(self.capabilities.contains(&Capability::Checksums));
+            }
+            Err(_) => {
+                self.protocol_version = self.detect_protocol_version();
+                self.capabilities = match self.protocol_version {
+                    ProtocolVersion::V1_0 => HashSet::new(),
+                    ProtocolVersion::V1_1 => {
+                        HashSet::from([Capability::RequestIds, Capability::IdempotencyKeys])
+                    }
+                };
+                let mut capability_names: Vec<String> = self
+                    .capabilities
+                    .iter()
+                    .map(|capability| capability.get_name().to_string())
+                    .collect();
+                capability_names.sort();
+                self.device_info = DeviceInfo {
+                    protocol_version: self.protocol_version.get_name().to_string(),
+                    firmware_name: None,
+                    firmware_build: None,
+                    board_id: None,
+                    capabilities: capability_names,
+                };
+            }
+        }
+    }
+
+    /// Sends `hello` using LiVeACE 1.0, which every board understands, and
+    /// parses the board's description of itself.
+    fn request_handshake(&mut self) -> Result<HandshakeResponse, SerialError> {
+        let request_id = self.send_request("hello", ProtocolVersion::V1_0)?;
+        let res = self.wait_for_response(&[request_id], PROTOCOL_PROBE_TIMEOUT);
+        self.forget_requests(&[request_id]);
+
+        res?.response
+            .and_then(|handshake| serde_json::from_value::<HandshakeResponse>(handshake).ok())
+            .ok_or(SerialError::MalformedResponse)
+    }
+
+    /// Sends a command that turns on an optional feature of the board, if the
+    /// board advertised it. Features that change the board's behavior, such as
+    /// requiring a checksum on every request or pushing events, stay off until
+    /// the server asks for them, so that older servers keep working with newer
+    /// firmware. The feature is treated as unsupported if the command fails.
+    fn enable_capability(&mut self, capability: Capability, command: &str) {
+        if !self.capabilities.contains(&capability) {
+            return;
+        }
+        let res = self
+            .send_request(command, self.protocol_version)
+            .and_then(|request_id| {
+                let res = self.wait_for_response(&[request_id], PROTOCOL_PROBE_TIMEOUT);
+                self.forget_requests(&[request_id]);
+                res
+            });
+        if res.is_err() {
+            self.capabilities.remove(&capability);
+        }
+    }
+
+    /// Returns the device id that the board reported during the handshake, or
+    /// stores a new random one on the board if it hasn't been provisioned.
+    fn get_or_assign_device_id(&mut self) -> Result<String, SerialError> {
+        if let Some(board_id) = self
+            .device_info
+            .board_id
+            .clone()
+            .filter(|board_id| is_valid_device_id(board_id))
+        {
+            return Ok(board_id);
+        }
+        if !self.capabilities.contains(&Capability::DeviceId) {
+            return Err(SerialError::NoDeviceId);
+        }
+
+        let device_id = format!("{:016X}", RandomState::new().build_hasher().finish());
+        self.execute_command_internal(&format!("setDeviceId {device_id}"))?;
+        self.device_info.board_id = Some(device_id.clone());
+        Ok(device_id)
+    }
+
     fn get_commands_internal(&mut self) -> Result<(), SerialError> {
         let response_or = self.execute_command_internal("listCommands")?;
         let response = response_or.ok_or(SerialError::MalformedResponse)?;
         let response_object = response.as_object().ok_or(SerialError::MalformedResponse)?;
-        let raw_null_commands = response_object
-            .get("null")
-            .ok_or(SerialError::MalformedResponse)?
-            .as_array()
-            .ok_or(SerialError::MalformedResponse)?;
-        let raw_bool_commands = response_object
-            .get("boolean")
-            .ok_or(SerialError::MalformedResponse)?
-            .as_array()
-            .ok_or(SerialError::MalformedResponse)?;
 
-        for raw_command_value in raw_null_commands {
-            self.null_commands.insert(
-                raw_command_value
-                    .as_str()
-                    .ok_or(SerialError::MalformedResponse)?
-                    .to_string(),
-            );
-        }
+        for value_type in CommandValueType::ALL {
+            let raw_commands = match response_object.get(get_command_list_key(value_type)) {
+                Some(raw_commands) => raw_commands
+                    .as_array()
+                    .ok_or(SerialError::MalformedResponse)?,
+                // LiVeACE 1.0 firmware always reports null and boolean
+                // commands, but may omit any of the other types.
+                None => match value_type {
+                    CommandValueType::Null | CommandValueType::Boolean => {
+                        return Err(SerialError::MalformedResponse)
+                    }
+                    _ => continue,
+                },
+            };
 
-        for raw_command_value in raw_bool_commands {
-            self.bool_commands.insert(
-                raw_command_value
-                    .as_str()
-                    .ok_or(SerialError::MalformedResponse)?
-                    .to_string(),
-            );
+            let commands = self.commands.entry(value_type).or_default();
+            for raw_command_value in raw_commands {
+                let command_spec =
+                    serde_json::from_value::<ListedCommand>(raw_command_value.clone())
+                        .map_err(|_| SerialError::MalformedResponse)?
+                        .into_command_spec(value_type);
+                commands.insert(command_spec.name.clone(), command_spec);
+            }
         }
 
         Ok(())
     }
 
+    /// Sends a single LiVeACE 1.1 request and checks that the board echoes its
+    /// request id back. LiVeACE 1.0 firmware responds with an error instead,
+    /// since it treats the request id as part of the command.
+    fn detect_protocol_version(&mut self) -> ProtocolVersion {
+        let request_id = match self.send_request("listCommands", ProtocolVersion::V1_1) {
+            Ok(request_id) => request_id,
+            Err(_) => return ProtocolVersion::V1_0,
+        };
+        let res = self.wait_for_response(&[request_id], PROTOCOL_PROBE_TIMEOUT);
+        self.forget_requests(&[request_id]);
+
+        match res {
+            Ok(response) if response.id == Some(request_id) => ProtocolVersion::V1_1,
+            _ => ProtocolVersion::V1_0,
+        }
+    }
+
     fn execute_command_internal(
         &mut self,
         command: &str,
     ) -> Result<Option<serde_json::Value>, SerialError> {
+        let mut request_ids = Vec::new();
+        let res = self.execute_command_with_retries(command, &mut request_ids);
+
+        self.forget_requests(&request_ids);
+
+        res.map(|response| response.response)
+    }
+
+    fn execute_command_with_retries(
+        &mut self,
+        command: &str,
+        request_ids: &mut Vec<u32>,
+    ) -> Result<ArduinoCommandResponse, SerialError> {
         for _ in 0..self.max_retries {
-            if let Ok(response) = self.execute_command_give_up_after_timeout(command) {
-                return Ok(response.response);
+            if let Ok(response) = self.execute_command_give_up_after_timeout(command, request_ids) {
+                return Ok(response);
             }
         }
-        self.execute_command_give_up_after_timeout(command)
-            .map(|response| response.response)
+        self.execute_command_give_up_after_timeout(command, request_ids)
     }
 
-    fn execute_command_give_up_after_timeout(
+    /// Executes a command that must not run more than once, such as vending an
+    /// item. Unlike `execute_command_internal`, a command is only resent once
+    /// the board confirms that it never received it, from the same boot that
+    /// the handshake reported. Boards without support for request ids,
+    /// idempotency keys and boot ids can't confirm this, so the command is only
+    /// ever sent once to them.
+    fn execute_actuating_command_internal(
         &mut self,
         command: &str,
+    ) -> Result<Option<serde_json::Value>, SerialError> {
+        let mut request_ids = Vec::new();
+        let res = if self.protocol_version == ProtocolVersion::V1_1
+            && self.capabilities.contains(&Capability::IdempotencyKeys)
+        {
+            self.execute_command_at_most_once(command, &mut request_ids)
+        } else {
+            self.execute_command_give_up_after_timeout(command, &mut request_ids)
+        };
+
+        self.forget_requests(&request_ids);
+
+        res.map(|response| response.response)
+    }
+
+    fn execute_command_at_most_once(
+        &mut self,
+        command: &str,
+        request_ids: &mut Vec<u32>,
     ) -> Result<ArduinoCommandResponse, SerialError> {
-        let mut buffer = [0; 10000];
-        if let Err(err) = self
-            .port
-            .lock()
-            .unwrap()
-            .clear(serialport::ClearBuffer::All)
+        let idempotency_key = format!(
+            "{:08x}{:x}",
+            self.idempotency_key_prefix, self.next_idempotency_key
+        );
+        self.next_idempotency_key = self.next_idempotency_key.wrapping_add(1);
+        let keyed_command = format!("!{idempotency_key} {command}");
+
+        let mut res = Err(SerialError::Timeout);
+        for _ in 0..=self.max_retries {
+            res = self.execute_command_give_up_after_timeout(&keyed_command, request_ids);
+            match res {
+                Ok(_) => return res,
+                // The board received the command and reported that it failed,
+                // so resending it won't help.
+                Err(SerialError::DeviceError { .. }) => return res,
+                Err(_) => {}
+            }
+
+            // The command or its response may have been lost, so the board
+            // has to confirm that it never executed the command before it can
+            // be resent.
+            match self.get_idempotency_key_status(&idempotency_key, command, request_ids)? {
+                Some(response) => return Ok(response),
+                None => continue,
+            }
+        }
+        res
+    }
+
+    /// Asks the board whether it executed the command sent with
+    /// `idempotency_key`. Returns the command's response if it was executed
+    /// successfully, `None` if it was never executed and is safe to resend, or
+    /// an error if it failed or its status couldn't be determined. A late
+    /// response to any of the command's attempts in `request_ids` is accepted
+    /// as an answer as well.
+    fn get_idempotency_key_status(
+        &mut self,
+        idempotency_key: &str,
+        command: &str,
+        request_ids: &[u32],
+    ) -> Result<Option<ArduinoCommandResponse>, SerialError> {
+        let status_command = format!("idempotencyKeyStatus {idempotency_key}");
+        let mut status_request_ids = Vec::new();
+
+        let mut res = Err(SerialError::Timeout);
+        for _ in 0..=self.max_retries {
+            res = self.wait_for_idempotency_key_status(
+                &status_command,
+                command,
+                request_ids,
+                &mut status_request_ids,
+            );
+            match res {
+                Ok(_) | Err(SerialError::DeviceError { .. }) | Err(SerialError::BoardReset) => {
+                    break
+                }
+                Err(_) => {}
+            }
+        }
+
+        self.forget_requests(&status_request_ids);
+
+        res
+    }
+
+    fn wait_for_idempotency_key_status(
+        &mut self,
+        status_command: &str,
+        command: &str,
+        request_ids: &[u32],
+        status_request_ids: &mut Vec<u32>,
+    ) -> Result<Option<ArduinoCommandResponse>, SerialError> {
+        status_request_ids.push(self.send_request(status_command, ProtocolVersion::V1_1)?);
+        let awaited_request_ids: Vec<u32> = request_ids
+            .iter()
+            .chain(status_request_ids.iter())
+            .copied()
+            .collect();
+        let response = self.wait_for_response(&awaited_request_ids, self.timeout_to_retry)?;
+
+        if response
+            .id
+            .is_some_and(|request_id| request_ids.contains(&request_id))
         {
-            return Err(SerialError::SerialPortError(err));
+            return Ok(Some(response));
         }
-        let num_bytes_available = match self.port.lock().unwrap().bytes_to_read() {
-            Ok(num_bytes_available) => num_bytes_available,
-            Err(err) => return Err(SerialError::SerialPortError(err)),
-        };
-        // Clear out any potential leftover bytes.
-        if num_bytes_available > 0 {
-            if let Err(err) = self
-                .port
-                .lock()
-                .unwrap()
-                .read(&mut buffer[..(num_bytes_available as usize)])
+
+        let status = response
+            .response
+            .and_then(|status| serde_json::from_value::<IdempotencyKeyStatus>(status).ok())
+            .ok_or(SerialError::MalformedResponse)?;
+        match status {
+            // The key can only have been forgotten by a reset if the board
+            // has been reset since the handshake.
+            IdempotencyKeyStatus::Unknown { boot_id }
+                if boot_id.is_some() && boot_id == self.boot_id =>
             {
-                return Err(SerialError::IoError(err));
+                Ok(None)
             }
+            IdempotencyKeyStatus::Unknown { boot_id } => {
+                // The board may have forgotten the key because it was reset,
+                // possibly while executing the command. Later commands are
+                // keyed for the new boot, if the board reports it.
+                match &boot_id {
+                    Some(boot_id) => {
+                        tracing::warn!(boot_id = %boot_id, "Board was reset during a command")
+                    }
+                    None => tracing::warn!(
+                        "Board can't tell whether it was reset during a command, since it \
+                         doesn't report a boot id"
+                    ),
+                }
+                if boot_id.is_some() {
+                    self.boot_id = boot_id;
+                }
+                Err(SerialError::BoardReset)
+            }
+            IdempotencyKeyStatus::Done {
+                ok: false,
+                code,
+                response,
+            } => Err(get_device_error(code, response)),
+            IdempotencyKeyStatus::Done {
+                ok: true,
+                code,
+                response,
+            } => Ok(Some(ArduinoCommandResponse {
+                status: String::from("ok"),
+                command: command.to_string(),
+                id: None,
+                code,
+                response,
+            })),
         }
+    }
 
-        for char in format!("{command}\n").chars() {
-            if let Err(err) = self
-                .port
-                .lock()
-                .unwrap()
-                .write_all(format!("{char}").as_bytes())
-            {
-                return Err(SerialError::IoError(err));
+    /// Sends a new attempt of `command` and waits for a response to it or to
+    /// any of the command's previous attempts in `request_ids`, which the new
+    /// attempt's request id is appended to.
+    fn execute_command_give_up_after_timeout(
+        &mut self,
+        command: &str,
+        request_ids: &mut Vec<u32>,
+    ) -> Result<ArduinoCommandResponse, SerialError> {
+        // LiVeACE 1.0 responses can't be told apart from responses to previous
+        // requests for the same command, so any leftover data is discarded.
+        // LiVeACE 1.1 responses are matched by request id instead, which keeps
+        // late responses to earlier attempts usable.
+        if self.protocol_version == ProtocolVersion::V1_0 {
+            self.clear_input()?;
+        }
+
+        if !request_ids.is_empty() {
+            self.stats.request_retries.fetch_add(1, Ordering::Relaxed);
+            tracing::warn!(attempt = request_ids.len() + 1, "Retrying request");
+        }
+        request_ids.push(self.send_request(command, self.protocol_version)?);
+        let res = self.wait_for_response(request_ids, self.timeout_to_retry);
+        self.update_legacy_pacing(!matches!(res, Err(SerialError::Timeout)));
+        res
+    }
+
+    /// Adapts the delay between written bytes for boards that didn't report
+    /// their receive buffer size. Unanswered requests may have been dropped by
+    /// a board that can't keep up, so each one slows writes down, while a long
+    /// enough streak of answered requests speeds them back up.
+    fn update_legacy_pacing(&mut self, was_answered: bool) {
+        if self.rx_buffer_size.is_some() {
+            return;
+        }
+
+        if !was_answered {
+            self.legacy_byte_delay = (self.legacy_byte_delay * 2)
+                .max(Duration::from_millis(1))
+                .min(MAX_LEGACY_BYTE_DELAY);
+            self.legacy_success_streak = 0;
+            return;
+        }
+
+        self.legacy_success_streak += 1;
+        if self.legacy_success_streak >= LEGACY_PACING_RELAX_STREAK {
+            self.legacy_byte_delay /= 2;
+            if self.legacy_byte_delay < Duration::from_millis(1) {
+                self.legacy_byte_delay = Duration::ZERO;
+            }
+            self.legacy_success_streak = 0;
+        }
+    }
+
+    /// Stops tracking the given requests, so that any responses to them that
+    /// arrive from now on are discarded as stale.
+    fn forget_requests(&mut self, request_ids: &[u32]) {
+        for request_id in request_ids {
+            self.outstanding_requests.remove(request_id);
+            self.unclaimed_responses.remove(request_id);
+        }
+    }
+
+    fn clear_input(&mut self) -> Result<(), SerialError> {
+        self.port
+            .lock()
+            .unwrap()
+            .clear(serialport::ClearBuffer::Input)
+            .map_err(SerialError::SerialPortError)?;
+        self.line_reader.discard_pending_lines()
+    }
+
+    /// Writes a request for `command` framed according to `protocol_version`
+    /// and marks it as outstanding. Returns the request's id.
+    fn send_request(
+        &mut self,
+        command: &str,
+        protocol_version: ProtocolVersion,
+    ) -> Result<u32, SerialError> {
+        let request_id = self.next_request_id;
+        self.next_request_id = self.next_request_id.wrapping_add(1);
+
+        let line = match protocol_version {
+            ProtocolVersion::V1_0 => command.to_string(),
+            ProtocolVersion::V1_1 => format!("#{request_id} {command}"),
+        };
+
+        let frame = if self.capabilities.contains(&Capability::Checksums) {
+            append_checksum(&line)
+        } else {
+            line.clone()
+        };
+        tracing::debug!(request_id, frame = %frame, "Sending request");
+        self.write_frame(format!("{frame}\n").as_bytes())?;
+
+        self.outstanding_requests.insert(request_id, line);
+        Ok(request_id)
+    }
+
+    /// Writes a complete frame to the port. Frames that fit in the board's
+    /// receive buffer are written all at once. Larger frames are split into
+    /// buffer-sized chunks, each written once the previous one has had time to
+    /// reach the board. Boards that didn't report their receive buffer size get
+    /// one byte at a time, paced by `legacy_byte_delay`, once they have shown
+    /// that they can't keep up with whole frames.
+    fn write_frame(&mut self, frame: &[u8]) -> Result<(), SerialError> {
+        let mut port = self.port.lock().unwrap();
+
+        let chunk_size = match self.rx_buffer_size {
+            Some(rx_buffer_size) => rx_buffer_size,
+            None if self.legacy_byte_delay.is_zero() => frame.len(),
+            None => 1,
+        };
+        let chunk_delay = match self.rx_buffer_size {
+            Some(_) => {
+                // Roughly the time it takes to send a chunk, at 10 bits per
+                // byte to account for start and stop bits.
+                let baud_rate = port.baud_rate().unwrap_or(57600).max(1);
+                Duration::from_micros(chunk_size as u64 * 10 * 1_000_000 / baud_rate as u64)
             }
-            if let Err(err) = self.port.lock().unwrap().flush() {
-                return Err(SerialError::IoError(err));
+            None => self.legacy_byte_delay,
+        };
+
+        for (i, chunk) in frame.chunks(chunk_size.max(1)).enumerate() {
+            if i > 0 {
+                std::thread::sleep(chunk_delay);
             }
-            std::thread::sleep(Duration::from_millis(1));
+            port.write_all(chunk).map_err(SerialError::IoError)?;
+            port.flush().map_err(SerialError::IoError)?;
         }
-        self.wait_for_input(command)
+
+        Ok(())
     }
 
-    fn wait_for_input(&mut self, command: &str) -> Result<ArduinoCommandResponse, SerialError> {
-        let start_time = std::time::Instant::now();
-        let mut buffer = [0; 10000];
-        let mut stringified_buffer = String::new();
+    /// Waits for a response to any of the requests in `request_ids`. Responses
+    /// to other outstanding requests that arrive in the meantime are kept for
+    /// whoever waits for them next, and responses to requests that are no
+    /// longer outstanding are discarded.
+    fn wait_for_response(
+        &mut self,
+        request_ids: &[u32],
+        timeout: Duration,
+    ) -> Result<ArduinoCommandResponse, SerialError> {
+        let mut deadline = Instant::now() + timeout;
+        let mut timeout_error = SerialError::Timeout;
         loop {
-            // TODO - The above call to `bytes_to_read` is very similar and both calls can probably be extracted into a helper of some kind.
-            let num_bytes_available = match self.port.lock().unwrap().bytes_to_read() {
-                Ok(num_bytes_available) => num_bytes_available,
-                Err(err) => return Err(SerialError::SerialPortError(err)),
+            for request_id in request_ids {
+                if let Some(response) = self.unclaimed_responses.remove(request_id) {
+                    return self.check_response_status(response);
+                }
+            }
+
+            let Some(remaining_time) = deadline.checked_duration_since(Instant::now()) else {
+                return self.give_up_waiting(timeout_error);
+            };
+            let line = match self.line_reader.recv_timeout(remaining_time) {
+                Ok(line) => line,
+                Err(SerialError::Timeout) => return self.give_up_waiting(timeout_error),
+                Err(err) => return Err(err),
             };
-            if num_bytes_available > 0 {
-                let read_result = self
-                    .port
-                    .lock()
-                    .unwrap()
-                    .read(&mut buffer[..(num_bytes_available as usize)]);
-                match read_result {
-                    Ok(bytes_read) => {
-                        if let Ok(text) = String::from_utf8(Vec::from(&buffer[..bytes_read])) {
-                            stringified_buffer += &text;
-                        }
+
+            let payload = if self.capabilities.contains(&Capability::Checksums) {
+                match strip_checksum(&line) {
+                    Some(payload) => payload,
+                    None => {
+                        self.stats.corrupted_frames.fetch_add(1, Ordering::Relaxed);
+                        tracing::warn!(frame = %line, "Received a corrupted frame");
+                        timeout_error = SerialError::CorruptedFrame;
+                        deadline = deadline.min(Instant::now() + CORRUPTED_FRAME_GRACE_PERIOD);
+                        continue;
                     }
-                    Err(err) => println!("Got error: {err}"),
-                };
+                }
+            } else {
+                &line
+            };
+            let response = parse_json_response(payload).inspect_err(|_| {
+                self.stats.malformed_frames.fetch_add(1, Ordering::Relaxed);
+                tracing::warn!(frame = %line, "Received a malformed frame");
+            })?;
+            match self.get_response_request_id(&response, request_ids) {
+                Some(request_id) if request_ids.contains(&request_id) => {
+                    return self.check_response_status(response);
+                }
+                Some(request_id) => {
+                    self.unclaimed_responses.insert(request_id, response);
+                }
+                None => {}
             }
-            if stringified_buffer.ends_with('\n') {
-                for line in stringified_buffer.split('\n').map(|line| line.trim()) {
-                    let parsed_response = parse_json_response(line)?;
-                    if parsed_response.command == command {
-                        return Ok(parsed_response);
+        }
+    }
+
+    /// Returns the error for a wait that ran out of time, counting it if no
+    /// response arrived at all.
+    fn give_up_waiting(
+        &self,
+        timeout_error: SerialError,
+    ) -> Result<ArduinoCommandResponse, SerialError> {
+        if matches!(timeout_error, SerialError::Timeout) {
+            self.stats.request_timeouts.fetch_add(1, Ordering::Relaxed);
+        }
+        tracing::warn!(error = %timeout_error, "Gave up waiting for a response");
+        Err(timeout_error)
+    }
+
+    /// Turns error responses into errors, counting requests that the board
+    /// reports were corrupted on their way to it.
+    fn check_response_status(
+        &self,
+        response: ArduinoCommandResponse,
+    ) -> Result<ArduinoCommandResponse, SerialError> {
+        if response.status != "ok"
+            && response.code.as_deref() == Some(DEVICE_ERROR_CODE_CHECKSUM_MISMATCH)
+        {
+            self.stats.corrupted_frames.fetch_add(1, Ordering::Relaxed);
+            tracing::warn!("Board received a corrupted request");
+            return Err(SerialError::CorruptedFrame);
+        }
+
+        check_response_status(response)
+    }
+
+    /// Returns the id of the outstanding request that `response` answers, or
+    /// `None` if it doesn't answer any outstanding request. Responses without a
+    /// request id are matched by the line they echo back, preferring requests
+    /// in `preferred_request_ids` since they can't be told apart otherwise.
+    fn get_response_request_id(
+        &self,
+        response: &ArduinoCommandResponse,
+        preferred_request_ids: &[u32],
+    ) -> Option<u32> {
+        if let Some(request_id) = response.id {
+            return self
+                .outstanding_requests
+                .contains_key(&request_id)
+                .then_some(request_id);
+        }
+
+        let is_match = |request_id: &u32| {
+            self.outstanding_requests
+                .get(request_id)
+                .is_some_and(|line| *line == response.command)
+        };
+        preferred_request_ids
+            .iter()
+            .copied()
+            .find(is_match)
+            .or_else(|| self.outstanding_requests.keys().copied().find(is_match))
+    }
+}
+
+/// Reads lines from a port on a dedicated thread, which blocks until data is
+/// available instead of polling the port. The thread stops once the reader is
+/// dropped or the port fails.
+struct LineReader {
+    /// Complete lines read from the port, with surrounding whitespace trimmed,
+    /// or the error that stopped the thread. Events are published to the event
+    /// bus instead, since nobody may be waiting for a response when they
+    /// arrive.
+    lines: Mutex<mpsc::Receiver<std::io::Result<String>>>,
+    /// The namespace that events are published under, which is only known
+    /// once the board has been identified.
+    namespace: Arc<Mutex<String>>,
+    /// Whether checksums have been negotiated, after which event lines without
+    /// one are no longer trusted.
+    checksums_required: Arc<AtomicBool>,
+    /// Incremented whenever pending input is discarded, which tells the thread
+    /// to also discard the incomplete line it's holding.
+    discard_generation: Arc<AtomicU64>,
+    stop: Arc<AtomicBool>,
+}
+
+impl LineReader {
+    /// Starts reading lines from `port` on a background thread. If the port
+    /// stops being readable, the executor is marked as disconnected in
+    /// `stats`.
+    fn spawn(
+        mut port: Box<dyn SerialPort>,
+        event_bus: Arc<EventBus>,
+        stats: Arc<ExecutorStats>,
+    ) -> Result<Self, SerialError> {
+        port.set_timeout(READER_POLL_INTERVAL)
+            .map_err(SerialError::SerialPortError)?;
+
+        let (sender, receiver) = mpsc::channel();
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let namespace = Arc::new(Mutex::new(String::new()));
+        let thread_namespace = namespace.clone();
+        let checksums_required = Arc::new(AtomicBool::new(false));
+        let thread_checksums_required = checksums_required.clone();
+        let discard_generation = Arc::new(AtomicU64::new(0));
+        let thread_discard_generation = discard_generation.clone();
+        std::thread::spawn(move || {
+            let mut buffer = [0; 1024];
+            let mut line = Vec::new();
+            let mut last_discard_generation = 0;
+            while !thread_stop.load(Ordering::Relaxed) {
+                let bytes_read = match port.read(&mut buffer) {
+                    Ok(bytes_read) => bytes_read,
+                    Err(err) if err.kind() == std::io::ErrorKind::TimedOut => continue,
+                    Err(err) => {
+                        tracing::error!(
+                            namespace = %thread_namespace.lock().unwrap(),
+                            error = %err,
+                            "Unable to read from serial port"
+                        );
+                        stats.disconnected.store(true, Ordering::Relaxed);
+                        let _ = sender.send(Err(err));
+                        return;
+                    }
+                };
+                let discard_generation = thread_discard_generation.load(Ordering::Relaxed);
+                if discard_generation != last_discard_generation {
+                    line.clear();
+                    last_discard_generation = discard_generation;
+                }
+                for byte in &buffer[..bytes_read] {
+                    if *byte != b'\n' {
+                        line.push(*byte);
+                        continue;
+                    }
+                    let text = String::from_utf8_lossy(&line).trim().to_string();
+                    line.clear();
+                    tracing::trace!(line = %text, "Received line");
+                    if let Some(event) =
+                        parse_event(&text, thread_checksums_required.load(Ordering::Relaxed))
+                    {
+                        let namespace = thread_namespace.lock().unwrap().clone();
+                        event_bus.publish(&namespace, &event.event, event.data);
+                        continue;
+                    }
+                    if !text.is_empty() && sender.send(Ok(text)).is_err() {
+                        return;
                     }
                 }
             }
-            if std::time::Instant::now().duration_since(start_time) > self.timeout_to_retry {
-                return Err(SerialError::Timeout);
-            }
-            if let Err(err) = self
-                .port
-                .lock()
-                .unwrap()
-                .clear(serialport::ClearBuffer::Output)
-            {
-                return Err(SerialError::SerialPortError(err));
-            }
+        });
+
+        Ok(Self {
+            lines: Mutex::new(receiver),
+            namespace,
+            checksums_required,
+            discard_generation,
+            stop,
+        })
+    }
+
+    fn set_namespace(&self, namespace: &str) {
+        *self.namespace.lock().unwrap() = namespace.to_string();
+    }
+
+    fn set_checksums_required(&self, checksums_required: bool) {
+        self.checksums_required
+            .store(checksums_required, Ordering::Relaxed);
+    }
+
+    /// Waits for the next line for up to `timeout`.
+    fn recv_timeout(&self, timeout: Duration) -> Result<String, SerialError> {
+        match self.lines.lock().unwrap().recv_timeout(timeout) {
+            Ok(Ok(line)) => Ok(line),
+            Ok(Err(err)) => Err(SerialError::IoError(err)),
+            Err(mpsc::RecvTimeoutError::Timeout) => Err(SerialError::Timeout),
+            Err(mpsc::RecvTimeoutError::Disconnected) => Err(SerialError::IoError(
+                std::io::Error::new(std::io::ErrorKind::BrokenPipe, "Port reader stopped"),
+            )),
+        }
+    }
+
+    /// Discards lines that have been read but not received yet, along with
+    /// the start of any line that hasn't been read in full.
+    fn discard_pending_lines(&self) -> Result<(), SerialError> {
+        self.discard_generation.fetch_add(1, Ordering::Relaxed);
+        for res in self.lines.lock().unwrap().try_iter() {
+            res.map_err(SerialError::IoError)?;
         }
+        Ok(())
+    }
+}
+
+impl Drop for LineReader {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
     }
 }
 
+/// Encodes a command and its arguments as they are sent over the wire. Each
+/// argument is appended to the command name, separated by a single space.
+/// Booleans are sent as `1` or `0` so that firmware can parse every numeric
+/// argument the same way.
+fn encode_command(command: &str, argument_values: &[CommandArgumentValue]) -> String {
+    let mut encoded_command = command.to_string();
+    for argument_value in argument_values {
+        encoded_command.push(' ');
+        match argument_value {
+            CommandArgumentValue::Integer(i) => encoded_command.push_str(&i.to_string()),
+            CommandArgumentValue::Float(f) => encoded_command.push_str(&f.to_string()),
+            CommandArgumentValue::Boolean(b) => encoded_command.push(if *b { '1' } else { '0' }),
+            CommandArgumentValue::String(s) => encoded_command.push_str(s),
+        }
+    }
+    encoded_command
+}
+
+/// Returns the key under which commands returning values of the given type are
+/// listed in the response to `listCommands`.
+fn get_command_list_key(value_type: CommandValueType) -> &'static str {
+    match value_type {
+        CommandValueType::Null => "null",
+        CommandValueType::Boolean => "boolean",
+        CommandValueType::Integer => "integer",
+        CommandValueType::Float => "float",
+        CommandValueType::String => "string",
+        CommandValueType::Json => "json",
+    }
+}
+
+/// Returns the CRC-16/CCITT-FALSE of `data`.
+fn get_crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+fn append_checksum(payload: &str) -> String {
+    format!("{payload}*{:04X}", get_crc16(payload.as_bytes()))
+}
+
+/// Returns the payload of a checksummed frame, or `None` if the frame has no
+/// checksum or its checksum doesn't match.
+fn strip_checksum(frame: &str) -> Option<&str> {
+    let (payload, checksum) = frame.rsplit_once('*')?;
+    if checksum.len() != 4 || !checksum.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let checksum = u16::from_str_radix(checksum, 16).ok()?;
+    (get_crc16(payload.as_bytes()) == checksum).then_some(payload)
+}
+
+/// Parses an event line, which must carry a checksum if `checksums_required`.
+/// Returns `None` for anything else, including event lines whose checksum
+/// doesn't match or is missing, which are treated like any other corrupted
+/// line.
+fn parse_event(line: &str, checksums_required: bool) -> Option<ArduinoEvent> {
+    let payload = match strip_checksum(line) {
+        Some(payload) => payload,
+        None if checksums_required => return None,
+        None => line,
+    };
+    serde_json::from_str::<ArduinoEvent>(payload).ok()
+}
+
+/// Returns whether a device id reported by a board can be used in its
+/// executor's namespace. Firmware accepts the same ids in `setDeviceId`.
+fn is_valid_device_id(device_id: &str) -> bool {
+    !device_id.is_empty()
+        && device_id.len() <= MAX_DEVICE_ID_LENGTH
+        && device_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
 fn parse_json_response(response: &str) -> Result<ArduinoCommandResponse, SerialError> {
-    match serde_json::from_str::<ArduinoCommandResponse>(response) {
-        Ok(response) => {
-            if response.status != "ok" {
-                return Err(SerialError::NonOkStatus);
+    serde_json::from_str::<ArduinoCommandResponse>(response)
+        .map_err(|_| SerialError::MalformedResponse)
+}
+
+fn check_response_status(
+    response: ArduinoCommandResponse,
+) -> Result<ArduinoCommandResponse, SerialError> {
+    if response.status != "ok" {
+        return Err(get_device_error(response.code, response.response));
+    }
+
+    Ok(response)
+}
+
+fn get_device_error(code: Option<String>, response: Option<serde_json::Value>) -> SerialError {
+    let message = match response {
+        Some(serde_json::Value::String(message)) => message,
+        Some(response) => response.to_string(),
+        None => String::new(),
+    };
+
+    SerialError::DeviceError {
+        code: code.unwrap_or_else(|| DEVICE_ERROR_CODE_UNKNOWN.to_string()),
+        message,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::liveace_simulator::{
+        SimulatedArgument, SimulatedBoard, SimulatedCommand, SimulatedSerialPort, SimulatorConfig,
+    };
+
+    fn get_test_config() -> SimulatorConfig {
+        SimulatorConfig {
+            vend_duration: Duration::from_millis(50),
+            homing_timeout: Duration::from_millis(50),
+            command_duration: Duration::from_millis(1),
+            boot_duration: Duration::ZERO,
+            ..Default::default()
+        }
+    }
+
+    fn connect(config: SimulatorConfig) -> (LiVeAceSerialPort, Arc<Mutex<SimulatedBoard>>) {
+        connect_with_event_bus(config, Arc::new(EventBus::new()))
+    }
+
+    fn connect_with_event_bus(
+        config: SimulatorConfig,
+        event_bus: Arc<EventBus>,
+    ) -> (LiVeAceSerialPort, Arc<Mutex<SimulatedBoard>>) {
+        let port = SimulatedSerialPort::new(config);
+        let board = port.get_board();
+        let retry_policy = RetryPolicy {
+            timeout_to_retry: Duration::from_millis(200),
+            ..Default::default()
+        };
+        let liveace_port = LiVeAceSerialPort::new(
+            Box::new(port),
+            Some(String::from("simulated")),
+            event_bus,
+            retry_policy,
+            retry_policy,
+        )
+        .unwrap();
+        (liveace_port, board)
+    }
+
+    fn execute(
+        liveace_port: &mut LiVeAceSerialPort,
+        command: &str,
+        value_type: CommandValueType,
+    ) -> Result<CommandValue, CommandExecutorError> {
+        liveace_port.execute_command(command, value_type, &CommandArguments::new())
+    }
+
+    #[test]
+    fn negotiates_capabilities_with_handshake() {
+        let (liveace_port, _) = connect(get_test_config());
+
+        assert_eq!(liveace_port.protocol_version, ProtocolVersion::V1_1);
+        assert!(liveace_port
+            .capabilities
+            .contains(&Capability::IdempotencyKeys));
+        let device_info = liveace_port.get_device_info();
+        assert_eq!(device_info.protocol_version, "1.1");
+        assert_eq!(
+            device_info.firmware_name.as_deref(),
+            Some("liveace_simulator")
+        );
+    }
+
+    #[test]
+    fn writes_whole_frames_to_firmware_that_reports_rx_buffer_size() {
+        let (mut liveace_port, board) = connect(get_test_config());
+        board
+            .lock()
+            .unwrap()
+            .set_min_byte_interval(Duration::from_millis(1));
+
+        assert_eq!(liveace_port.rx_buffer_size, Some(64));
+        // The whole frame arrives at once, so the board only keeps its first
+        // byte and never answers.
+        assert!(matches!(
+            execute(
+                &mut liveace_port,
+                "stepper0HasInventory",
+                CommandValueType::Boolean
+            ),
+            Err(CommandExecutorError::Timeout)
+        ));
+        assert_eq!(liveace_port.legacy_byte_delay, Duration::ZERO);
+    }
+
+    #[test]
+    fn paces_writes_to_legacy_firmware_that_drops_bytes() {
+        let (mut liveace_port, board) = connect(SimulatorConfig {
+            supports_handshake: false,
+            ..get_test_config()
+        });
+        liveace_port.timeout_to_retry = Duration::from_millis(50);
+        board
+            .lock()
+            .unwrap()
+            .set_min_byte_interval(Duration::from_micros(500));
+
+        assert_eq!(liveace_port.rx_buffer_size, None);
+        assert_eq!(liveace_port.legacy_byte_delay, Duration::ZERO);
+        assert_eq!(
+            execute(
+                &mut liveace_port,
+                "stepper0HasInventory",
+                CommandValueType::Boolean
+            )
+            .unwrap(),
+            CommandValue::Boolean(true)
+        );
+        assert!(!liveace_port.legacy_byte_delay.is_zero());
+    }
+
+    #[test]
+    fn detects_liveace_1_1_firmware_without_handshake() {
+        let (liveace_port, _) = connect(SimulatorConfig {
+            supports_handshake: false,
+            ..get_test_config()
+        });
+
+        assert_eq!(liveace_port.protocol_version, ProtocolVersion::V1_1);
+        let device_info = liveace_port.get_device_info();
+        assert_eq!(device_info.firmware_name, None);
+        assert_eq!(
+            device_info.capabilities,
+            vec![String::from("idempotencyKeys"), String::from("requestIds")]
+        );
+    }
+
+    #[test]
+    fn detects_liveace_1_0_firmware() {
+        let (mut liveace_port, _) = connect(SimulatorConfig {
+            supports_handshake: false,
+            supports_request_ids: false,
+            ..get_test_config()
+        });
+
+        assert_eq!(liveace_port.protocol_version, ProtocolVersion::V1_0);
+        assert!(liveace_port.capabilities.is_empty());
+        assert_eq!(
+            execute(
+                &mut liveace_port,
+                "stepper0HasInventory",
+                CommandValueType::Boolean
+            )
+            .unwrap(),
+            CommandValue::Boolean(true)
+        );
+    }
+
+    #[test]
+    fn discards_incomplete_lines_when_clearing_input() {
+        let (mut liveace_port, board) = connect(SimulatorConfig {
+            supports_handshake: false,
+            supports_request_ids: false,
+            ..get_test_config()
+        });
+        board
+            .lock()
+            .unwrap()
+            .write_raw_output(b"{\"status\": \"ok\", \"comm", Instant::now());
+        std::thread::sleep(READER_POLL_INTERVAL * 2);
+
+        assert_eq!(
+            execute(
+                &mut liveace_port,
+                "stepper0HasInventory",
+                CommandValueType::Boolean
+            )
+            .unwrap(),
+            CommandValue::Boolean(true)
+        );
+        assert_eq!(
+            liveace_port.stats.malformed_frames.load(Ordering::Relaxed),
+            0
+        );
+    }
+
+    #[test]
+    fn lists_commands() {
+        let (liveace_port, board) = connect(SimulatorConfig {
+            initial_inventory: vec![10; 3],
+            ..get_test_config()
+        });
+
+        let mut null_commands: Vec<&CommandSpec> =
+            liveace_port.get_commands(CommandValueType::Null).collect();
+        null_commands.sort_by(|a, b| a.name.cmp(&b.name));
+        assert_eq!(
+            null_commands.len(),
+            board.lock().unwrap().get_stepper_count()
+        );
+        assert_eq!(null_commands[0].name, "stepper0");
+        assert!(null_commands[0].actuating);
+        assert_eq!(
+            liveace_port.get_commands(CommandValueType::Boolean).count(),
+            6
+        );
+        assert_eq!(
+            liveace_port.get_commands(CommandValueType::Integer).count(),
+            0
+        );
+    }
+
+    #[test]
+    fn reads_inventory_sensors() {
+        let (mut liveace_port, board) = connect(get_test_config());
+        board.lock().unwrap().set_inventory(1, 0);
+
+        assert_eq!(
+            execute(
+                &mut liveace_port,
+                "stepper1HasInventory",
+                CommandValueType::Boolean
+            )
+            .unwrap(),
+            CommandValue::Boolean(false)
+        );
+        assert_eq!(
+            execute(
+                &mut liveace_port,
+                "stepper1OutOfInventory",
+                CommandValueType::Boolean
+            )
+            .unwrap(),
+            CommandValue::Boolean(true)
+        );
+    }
+
+    fn get_typed_commands(typed_commands: &[(&str, &str, &str)]) -> Vec<SimulatedCommand> {
+        typed_commands
+            .iter()
+            .map(|(name, value_type, response)| SimulatedCommand {
+                name: name.to_string(),
+                value_type: value_type.to_string(),
+                args: Vec::new(),
+                response: response.to_string(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn executes_typed_commands() {
+        let (mut liveace_port, _) = connect(SimulatorConfig {
+            extra_commands: get_typed_commands(&[
+                ("coinCount", "integer", "-42"),
+                ("temperature", "float", "21.5"),
+                ("roundedTemperature", "float", "22"),
+                ("firmwareVariant", "string", "\"sticker\""),
+                ("calibration", "json", "{\"offset\": [1, 2]}"),
+            ]),
+            ..get_test_config()
+        });
+
+        assert_eq!(
+            liveace_port.get_commands(CommandValueType::Float).count(),
+            2
+        );
+        assert_eq!(
+            execute(&mut liveace_port, "coinCount", CommandValueType::Integer).unwrap(),
+            CommandValue::Integer(-42)
+        );
+        assert_eq!(
+            execute(&mut liveace_port, "temperature", CommandValueType::Float).unwrap(),
+            CommandValue::Float(21.5)
+        );
+        assert_eq!(
+            execute(
+                &mut liveace_port,
+                "roundedTemperature",
+                CommandValueType::Float
+            )
+            .unwrap(),
+            CommandValue::Float(22.0)
+        );
+        assert_eq!(
+            execute(
+                &mut liveace_port,
+                "firmwareVariant",
+                CommandValueType::String
+            )
+            .unwrap(),
+            CommandValue::String(String::from("sticker"))
+        );
+        assert_eq!(
+            execute(&mut liveace_port, "calibration", CommandValueType::Json).unwrap(),
+            CommandValue::Json(serde_json::json!({"offset": [1, 2]}))
+        );
+    }
+
+    #[test]
+    fn rejects_responses_of_the_wrong_type() {
+        let (mut liveace_port, _) = connect(SimulatorConfig {
+            extra_commands: get_typed_commands(&[
+                ("coinCount", "integer", "1.5"),
+                ("coinCountText", "integer", "\"42\""),
+                ("firmwareVariant", "string", "42"),
+                ("temperature", "float", "{"),
+            ]),
+            ..get_test_config()
+        });
+
+        for (command, value_type) in [
+            ("coinCount", CommandValueType::Integer),
+            ("coinCountText", CommandValueType::Integer),
+            ("firmwareVariant", CommandValueType::String),
+            // Not even valid JSON.
+            ("temperature", CommandValueType::Float),
+        ] {
+            assert!(matches!(
+                execute(&mut liveace_port, command, value_type),
+                Err(CommandExecutorError::MalformedResponse)
+            ));
+        }
+    }
+
+    #[test]
+    fn passes_arguments_to_the_board() {
+        let arg = |name: &str, argument_type: &str, min: Option<f64>, max: Option<f64>| {
+            SimulatedArgument {
+                name: name.to_string(),
+                argument_type: argument_type.to_string(),
+                min,
+                max,
             }
+        };
+        let (mut liveace_port, _) = connect(SimulatorConfig {
+            extra_commands: vec![
+                SimulatedCommand {
+                    name: String::from("printLabel"),
+                    value_type: String::from("json"),
+                    args: vec![
+                        arg("sku", "string", None, None),
+                        arg("copies", "integer", Some(1.0), Some(10.0)),
+                        arg("scale", "float", None, None),
+                        arg("mirrored", "boolean", None, None),
+                    ],
+                    response: String::from(
+                        "{\"sku\": \"{sku}\", \"copies\": {copies}, \"scale\": {scale}, \
+                         \"mirrored\": {mirrored}}",
+                    ),
+                },
+                SimulatedCommand {
+                    name: String::from("wrongArgumentCount"),
+                    value_type: String::from("null"),
+                    args: vec![arg("stepper", "integer", None, None)],
+                    response: String::from("null"),
+                },
+            ],
+            ..get_test_config()
+        });
+        let arguments = |arguments: serde_json::Value| arguments.as_object().unwrap().clone();
 
-            Ok(response)
+        let command_spec = liveace_port
+            .get_commands(CommandValueType::Json)
+            .find(|command_spec| command_spec.name == "printLabel")
+            .unwrap();
+        assert_eq!(command_spec.arguments.len(), 4);
+        assert_eq!(command_spec.arguments[1].min, Some(1.0));
+        assert_eq!(command_spec.arguments[1].max, Some(10.0));
+        assert!(liveace_port
+            .get_device_info()
+            .capabilities
+            .contains(&String::from("arguments")));
+
+        assert_eq!(
+            liveace_port
+                .execute_command(
+                    "printLabel",
+                    CommandValueType::Json,
+                    &arguments(serde_json::json!({
+                        "mirrored": true,
+                        "scale": 0.5,
+                        "copies": 3,
+                        "sku": "SKU-42_b"
+                    }))
+                )
+                .unwrap(),
+            CommandValue::Json(serde_json::json!({
+                "sku": "SKU-42_b",
+                "copies": 3,
+                "scale": 0.5,
+                "mirrored": 1
+            }))
+        );
+        assert!(matches!(
+            liveace_port.execute_command(
+                "printLabel",
+                CommandValueType::Json,
+                &arguments(serde_json::json!({
+                    "mirrored": true,
+                    "scale": 0.5,
+                    "copies": 11,
+                    "sku": "SKU-42_b"
+                }))
+            ),
+            Err(CommandExecutorError::InvalidArguments(_))
+        ));
+
+        // Boards that didn't advertise arguments aren't trusted to parse them.
+        liveace_port.capabilities.remove(&Capability::Arguments);
+        assert!(matches!(
+            liveace_port.execute_command(
+                "printLabel",
+                CommandValueType::Json,
+                &arguments(serde_json::json!({
+                    "mirrored": true,
+                    "scale": 0.5,
+                    "copies": 3,
+                    "sku": "SKU-42_b"
+                }))
+            ),
+            Err(CommandExecutorError::InvalidArguments(_))
+        ));
+
+        // The board's own check of the argument count is reported as a device
+        // error, in case the server and firmware disagree.
+        liveace_port
+            .commands
+            .get_mut(&CommandValueType::Null)
+            .unwrap()
+            .get_mut("wrongArgumentCount")
+            .unwrap()
+            .arguments
+            .clear();
+        assert!(matches!(
+            execute(
+                &mut liveace_port,
+                "wrongArgumentCount",
+                CommandValueType::Null
+            ),
+            Err(CommandExecutorError::DeviceError { code, .. }) if code == "invalidArguments"
+        ));
+    }
+
+    #[test]
+    fn encodes_command_arguments() {
+        assert_eq!(encode_command("stepper0", &[]), "stepper0");
+        assert_eq!(
+            encode_command(
+                "printLabel",
+                &[
+                    CommandArgumentValue::String(String::from("SKU-42_b")),
+                    CommandArgumentValue::Integer(-3),
+                    CommandArgumentValue::Float(0.5),
+                    CommandArgumentValue::Float(2.0),
+                    CommandArgumentValue::Boolean(true),
+                    CommandArgumentValue::Boolean(false),
+                ]
+            ),
+            "printLabel SKU-42_b -3 0.5 2 1 0"
+        );
+        // Floats are never written in exponent notation, which firmware may
+        // not parse.
+        assert_eq!(
+            encode_command("scale", &[CommandArgumentValue::Float(1e-7)]),
+            "scale 0.0000001"
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_commands_without_contacting_the_board() {
+        let (mut liveace_port, _) = connect(get_test_config());
+
+        assert!(matches!(
+            execute(&mut liveace_port, "stepper2", CommandValueType::Null),
+            Err(CommandExecutorError::UnknownCommand)
+        ));
+        assert!(matches!(
+            execute(&mut liveace_port, "stepper0", CommandValueType::Boolean),
+            Err(CommandExecutorError::UnknownCommand)
+        ));
+    }
+
+    #[test]
+    fn vends() {
+        let (mut liveace_port, board) = connect(get_test_config());
+
+        assert_eq!(
+            execute(&mut liveace_port, "stepper0", CommandValueType::Null).unwrap(),
+            CommandValue::Null
+        );
+        assert_eq!(board.lock().unwrap().get_vend_count(0), 1);
+        assert_eq!(board.lock().unwrap().get_vend_count(1), 0);
+    }
+
+    #[test]
+    fn reports_homing_failures_as_device_errors() {
+        let (mut liveace_port, board) = connect(get_test_config());
+        board.lock().unwrap().set_homing_switch_broken(0, true);
+
+        match execute(&mut liveace_port, "stepper0", CommandValueType::Null) {
+            Err(CommandExecutorError::DeviceError { code, message }) => {
+                assert_eq!(code, "homingSwitchNotTriggered");
+                assert_eq!(message, "stepper0 homing switch not triggered.");
+            }
+            res => panic!("Unexpected result: {res:?}"),
         }
-        Err(_) => Err(SerialError::MalformedResponse),
+        assert_eq!(board.lock().unwrap().get_vend_count(0), 0);
+    }
+
+    #[test]
+    fn retries_read_only_commands_when_response_is_lost() {
+        let (mut liveace_port, board) = connect(get_test_config());
+        board.lock().unwrap().drop_next_responses(2);
+
+        assert_eq!(
+            execute(
+                &mut liveace_port,
+                "stepper0HasInventory",
+                CommandValueType::Boolean
+            )
+            .unwrap(),
+            CommandValue::Boolean(true)
+        );
+    }
+
+    #[test]
+    fn vends_at_most_once_when_response_is_lost() {
+        let (mut liveace_port, board) = connect(get_test_config());
+        board.lock().unwrap().drop_next_responses(1);
+
+        assert_eq!(
+            execute(&mut liveace_port, "stepper0", CommandValueType::Null).unwrap(),
+            CommandValue::Null
+        );
+        assert_eq!(board.lock().unwrap().get_vend_count(0), 1);
+    }
+
+    #[test]
+    fn does_not_resend_vends_after_the_board_resets() {
+        let (mut liveace_port, board) = connect(SimulatorConfig {
+            vend_duration: Duration::from_millis(150),
+            ..get_test_config()
+        });
+
+        // Resets the board after it started vending, but before the vend
+        // times out and its idempotency key status is asked for.
+        let resetting_board = board.clone();
+        let reset_thread = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            resetting_board.lock().unwrap().reboot(Instant::now());
+        });
+        assert!(matches!(
+            execute(&mut liveace_port, "stepper0", CommandValueType::Null),
+            Err(CommandExecutorError::Indeterminate)
+        ));
+        reset_thread.join().unwrap();
+        assert_eq!(board.lock().unwrap().get_vend_count(0), 1);
+
+        // Commands sent after the reset are keyed for the new boot.
+        board.lock().unwrap().drop_next_responses(1);
+        assert_eq!(
+            execute(&mut liveace_port, "stepper0", CommandValueType::Null).unwrap(),
+            CommandValue::Null
+        );
+        assert_eq!(board.lock().unwrap().get_vend_count(0), 2);
+    }
+
+    #[test]
+    fn does_not_resend_vends_to_boards_without_boot_ids() {
+        // Without a boot id, from the board or from a handshake, a key that the
+        // board doesn't know can't be told apart from one it forgot in a reset.
+        for config in [
+            SimulatorConfig {
+                supports_boot_id: false,
+                ..get_test_config()
+            },
+            SimulatorConfig {
+                supports_handshake: false,
+                ..get_test_config()
+            },
+        ] {
+            let (mut liveace_port, board) = connect(SimulatorConfig {
+                vend_duration: Duration::from_millis(150),
+                ..config
+            });
+            assert_eq!(liveace_port.boot_id, None);
+
+            let resetting_board = board.clone();
+            let reset_thread = std::thread::spawn(move || {
+                std::thread::sleep(Duration::from_millis(50));
+                resetting_board.lock().unwrap().reboot(Instant::now());
+            });
+            assert!(matches!(
+                execute(&mut liveace_port, "stepper0", CommandValueType::Null),
+                Err(CommandExecutorError::Indeterminate)
+            ));
+            reset_thread.join().unwrap();
+            assert_eq!(board.lock().unwrap().get_vend_count(0), 1);
+        }
+    }
+
+    #[test]
+    fn does_not_resend_vends_to_liveace_1_0_firmware() {
+        let (mut liveace_port, board) = connect(SimulatorConfig {
+            supports_handshake: false,
+            supports_request_ids: false,
+            ..get_test_config()
+        });
+        board.lock().unwrap().drop_next_responses(1);
+
+        assert!(matches!(
+            execute(&mut liveace_port, "stepper0", CommandValueType::Null),
+            Err(CommandExecutorError::Timeout)
+        ));
+        assert_eq!(board.lock().unwrap().get_vend_count(0), 1);
+    }
+
+    #[test]
+    fn computes_crc16() {
+        assert_eq!(get_crc16(b"123456789"), 0x29B1);
+        assert_eq!(append_checksum("#1 hello"), "#1 hello*95A5");
+        assert_eq!(strip_checksum("#1 hello*95A5"), Some("#1 hello"));
+        assert_eq!(strip_checksum("#1 hellp*95A5"), None);
+        assert_eq!(strip_checksum("#1 hello"), None);
+    }
+
+    #[test]
+    fn requires_checksums_on_events_once_negotiated() {
+        let line = r#"{"event": "doorOpened", "data": null}"#;
+        assert!(parse_event(line, false).is_some());
+        assert!(parse_event(line, true).is_none());
+        assert!(parse_event(&append_checksum(line), true).is_some());
+        assert!(parse_event(&append_checksum(line).replace("door", "dorr"), false).is_none());
+    }
+
+    #[test]
+    fn rejects_corrupted_responses() {
+        let (mut liveace_port, board) = connect(get_test_config());
+        liveace_port.timeout_to_retry = Duration::from_millis(50);
+        assert!(liveace_port.capabilities.contains(&Capability::Checksums));
+        board.lock().unwrap().set_byte_corruption_rate(0.01);
+
+        for i in 0..20 {
+            let has_inventory = i % 2 == 0;
+            board.lock().unwrap().set_inventory(0, has_inventory as u32);
+            assert_eq!(
+                execute(
+                    &mut liveace_port,
+                    "stepper0HasInventory",
+                    CommandValueType::Boolean
+                )
+                .unwrap(),
+                CommandValue::Boolean(has_inventory)
+            );
+        }
+        assert!(liveace_port.stats.corrupted_frames.load(Ordering::Relaxed) > 0);
+    }
+
+    #[test]
+    fn vends_at_most_once_despite_corrupted_frames() {
+        let (mut liveace_port, board) = connect(get_test_config());
+        liveace_port.timeout_to_retry = Duration::from_millis(100);
+        board.lock().unwrap().set_byte_corruption_rate(0.004);
+        board.lock().unwrap().set_byte_drop_rate(0.004);
+
+        for _ in 0..5 {
+            assert_eq!(
+                execute(&mut liveace_port, "stepper0", CommandValueType::Null).unwrap(),
+                CommandValue::Null
+            );
+        }
+        assert_eq!(board.lock().unwrap().get_vend_count(0), 5);
+        assert!(liveace_port.stats.corrupted_frames.load(Ordering::Relaxed) > 0);
+    }
+
+    #[test]
+    fn publishes_device_events() {
+        let event_bus = Arc::new(EventBus::new());
+        let (mut liveace_port, board) =
+            connect_with_event_bus(get_test_config(), event_bus.clone());
+        assert!(liveace_port.capabilities.contains(&Capability::Events));
+
+        // Events that arrive while a command is running don't get in its way.
+        board.lock().unwrap().set_inventory(0, 1);
+        board.lock().unwrap().set_homing_switch_broken(1, true);
+        assert_eq!(
+            execute(&mut liveace_port, "stepper0", CommandValueType::Null).unwrap(),
+            CommandValue::Null
+        );
+        assert!(execute(&mut liveace_port, "stepper1", CommandValueType::Null).is_err());
+        assert_eq!(
+            execute(
+                &mut liveace_port,
+                "stepper0HasInventory",
+                CommandValueType::Boolean
+            )
+            .unwrap(),
+            CommandValue::Boolean(false)
+        );
+
+        let events: Vec<(String, serde_json::Value)> = event_bus
+            .get_recent_events(Some("arduino:simulated"))
+            .into_iter()
+            .map(|device_event| (device_event.event, device_event.data))
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                (
+                    String::from("inventoryChanged"),
+                    serde_json::json!({"stepper": 0, "hasInventory": false})
+                ),
+                (
+                    String::from("homingSwitchNotTriggered"),
+                    serde_json::json!({"stepper": 1})
+                ),
+            ]
+        );
+        assert!(event_bus
+            .get_recent_events(Some("arduino:other"))
+            .is_empty());
+    }
+
+    #[test]
+    fn tolerates_dropped_bytes() {
+        let (mut liveace_port, board) = connect(get_test_config());
+        liveace_port.timeout_to_retry = Duration::from_millis(50);
+        board.lock().unwrap().set_byte_drop_rate(0.005);
+
+        for _ in 0..10 {
+            assert_eq!(
+                execute(
+                    &mut liveace_port,
+                    "stepper0HasInventory",
+                    CommandValueType::Boolean
+                )
+                .unwrap(),
+                CommandValue::Boolean(true)
+            );
+        }
+    }
+
+    #[test]
+    fn gives_up_on_silent_devices_after_the_probe_policy() {
+        let port = SimulatedSerialPort::new(get_test_config());
+        port.get_board()
+            .lock()
+            .unwrap()
+            .drop_next_responses(u32::MAX);
+
+        let start_time = Instant::now();
+        let res = LiVeAceSerialPort::new(
+            Box::new(port),
+            Some(String::from("silent")),
+            Arc::new(EventBus::new()),
+            RetryPolicy::default(),
+            RetryPolicy {
+                timeout_to_retry: Duration::from_millis(100),
+                max_retries: 1,
+            },
+        );
+        assert!(matches!(res, Err(SerialError::Timeout)));
+        assert!(start_time.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn names_boards_without_serial_number_after_their_device_id() {
+        let connect_without_serial_number = |config: SimulatorConfig| {
+            let port = SimulatedSerialPort::new(config);
+            let board = port.get_board();
+            LiVeAceSerialPort::new(
+                Box::new(port),
+                None,
+                Arc::new(EventBus::new()),
+                RetryPolicy::default(),
+                RetryPolicy::default(),
+            )
+            .map(|liveace_port| (liveace_port, board))
+        };
+
+        // Unprovisioned boards are assigned a device id.
+        let (liveace_port, board) = connect_without_serial_number(get_test_config()).unwrap();
+        let device_id = board.lock().unwrap().get_device_id().unwrap().to_string();
+        assert!(is_valid_device_id(&device_id));
+        assert_eq!(
+            liveace_port.get_executor_namespace(),
+            format!("arduino:{device_id}")
+        );
+        assert_eq!(liveace_port.get_device_info().board_id, Some(device_id));
+
+        let (liveace_port, _) = connect_without_serial_number(SimulatorConfig {
+            device_id: Some(String::from("left-column")),
+            ..get_test_config()
+        })
+        .unwrap();
+        assert_eq!(liveace_port.get_executor_namespace(), "arduino:left-column");
+
+        assert!(matches!(
+            connect_without_serial_number(SimulatorConfig {
+                supports_device_id: false,
+                ..get_test_config()
+            }),
+            Err(SerialError::NoDeviceId)
+        ));
     }
 }
@@ -1,34 +1,482 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock};
+use std::time::{Duration, Instant};
 pub mod liveace;
 
+use crate::audit::{AuditEntry, AuditLog, CallerContext};
+use crate::events::EventBus;
+use crate::metrics::Metrics;
+use crate::util::get_unix_timestamp_millis;
+
+/// The type of value that a command returns when executed.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum CommandValueType {
+    Null,
+    Boolean,
+    Integer,
+    Float,
+    String,
+    Json,
+}
+
+impl CommandValueType {
+    pub const ALL: [CommandValueType; 6] = [
+        CommandValueType::Null,
+        CommandValueType::Boolean,
+        CommandValueType::Integer,
+        CommandValueType::Float,
+        CommandValueType::String,
+        CommandValueType::Json,
+    ];
+
+    /// Converts a raw value returned by a command into a `CommandValue` of this
+    /// type. Returns `None` if the raw value isn't of this type. A missing
+    /// value is treated as null.
+    pub fn parse_value(&self, raw_value: Option<serde_json::Value>) -> Option<CommandValue> {
+        let raw_value = raw_value.unwrap_or(serde_json::Value::Null);
+        match self {
+            Self::Null => raw_value.is_null().then_some(CommandValue::Null),
+            Self::Boolean => raw_value.as_bool().map(CommandValue::Boolean),
+            Self::Integer => raw_value.as_i64().map(CommandValue::Integer),
+            Self::Float => raw_value.as_f64().map(CommandValue::Float),
+            Self::String => match raw_value {
+                serde_json::Value::String(s) => Some(CommandValue::String(s)),
+                _ => None,
+            },
+            Self::Json => Some(CommandValue::Json(raw_value)),
+        }
+    }
+}
+
+/// A value returned by executing a command.
+#[derive(Clone, PartialEq, Debug, serde::Serialize)]
+#[serde(untagged)]
+pub enum CommandValue {
+    Null,
+    Boolean(bool),
+    Integer(i64),
+    Float(f64),
+    String(String),
+    Json(serde_json::Value),
+}
+
+/// Named arguments passed by a caller when executing a command.
+pub type CommandArguments = serde_json::Map<String, serde_json::Value>;
+
+/// The type of an argument accepted by a command.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum CommandArgumentType {
+    Integer,
+    Float,
+    Boolean,
+    String,
+}
+
+/// Describes a single argument accepted by a command.
+#[derive(Clone, PartialEq, Debug, serde::Serialize, serde::Deserialize)]
+pub struct CommandArgumentSpec {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub argument_type: CommandArgumentType,
+    /// Inclusive lower bound. Only applies to numeric arguments.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min: Option<f64>,
+    /// Inclusive upper bound. Only applies to numeric arguments.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max: Option<f64>,
+}
+
+/// A validated argument value, ready to be passed to a command.
+#[derive(Clone, PartialEq, Debug)]
+pub enum CommandArgumentValue {
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+    String(String),
+}
+
+/// Describes a command that an executor can run.
+#[derive(Clone, PartialEq, Debug)]
+pub struct CommandSpec {
+    pub name: String,
+    /// The arguments that the command requires, in the order that they are
+    /// passed to the command. Empty for commands that take no arguments.
+    pub arguments: Vec<CommandArgumentSpec>,
+    /// Whether executing the command acts on the physical world (e.g. moving a
+    /// motor to vend an item) rather than just reading state. Executors must
+    /// never execute an actuating command more than once per call, so they
+    /// can't blindly retry it.
+    pub actuating: bool,
+}
+
+impl CommandSpec {
+    /// Checks that `arguments` contains exactly the arguments that this command
+    /// requires, each of the correct type and within bounds. Returns the
+    /// argument values in the order that they should be passed to the command.
+    pub fn validate_arguments(
+        &self,
+        arguments: &CommandArguments,
+    ) -> Result<Vec<CommandArgumentValue>, String> {
+        if let Some(unknown_argument) = arguments.keys().find(|argument| {
+            !self
+                .arguments
+                .iter()
+                .any(|argument_spec| &argument_spec.name == *argument)
+        }) {
+            return Err(format!(
+                "Unknown argument '{unknown_argument}' for command '{}'",
+                self.name
+            ));
+        }
+
+        self.arguments
+            .iter()
+            .map(|argument_spec| {
+                let raw_value = arguments.get(&argument_spec.name).ok_or_else(|| {
+                    format!(
+                        "Missing argument '{}' for command '{}'",
+                        argument_spec.name, self.name
+                    )
+                })?;
+                argument_spec.validate(raw_value)
+            })
+            .collect()
+    }
+}
+
+impl CommandArgumentSpec {
+    fn validate(&self, raw_value: &serde_json::Value) -> Result<CommandArgumentValue, String> {
+        let value = match self.argument_type {
+            CommandArgumentType::Integer => raw_value.as_i64().map(CommandArgumentValue::Integer),
+            CommandArgumentType::Float => raw_value.as_f64().map(CommandArgumentValue::Float),
+            CommandArgumentType::Boolean => raw_value.as_bool().map(CommandArgumentValue::Boolean),
+            CommandArgumentType::String => raw_value
+                .as_str()
+                .map(|s| CommandArgumentValue::String(s.to_string())),
+        }
+        .ok_or_else(|| {
+            format!(
+                "Argument '{}' must be of type {:?}",
+                self.name, self.argument_type
+            )
+        })?;
+
+        let numeric_value = match &value {
+            CommandArgumentValue::Integer(i) => Some(*i as f64),
+            CommandArgumentValue::Float(f) => Some(*f),
+            _ => None,
+        };
+        if let Some(numeric_value) = numeric_value {
+            if self.min.is_some_and(|min| numeric_value < min)
+                || self.max.is_some_and(|max| numeric_value > max)
+            {
+                return Err(format!(
+                    "Argument '{}' is out of bounds (min: {:?}, max: {:?})",
+                    self.name, self.min, self.max
+                ));
+            }
+        }
+
+        // Arguments are sent over line-based protocols separated by spaces, so
+        // strings are limited to a single printable ASCII word. `*` is reserved
+        // for frame checksums.
+        if let CommandArgumentValue::String(s) = &value {
+            if s.is_empty() || !s.chars().all(|c| c.is_ascii_graphic() && c != '*') {
+                return Err(format!(
+                    "Argument '{}' must be a non-empty string of printable ASCII characters without whitespace or '*'",
+                    self.name
+                ));
+            }
+        }
+
+        Ok(value)
+    }
+}
+
+/// Describes the device behind an executor, as reported by the device itself.
+#[derive(Clone, PartialEq, Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceInfo {
+    /// The version of the protocol spoken by the device, such as `1.1`.
+    pub protocol_version: String,
+    /// `None` if the device didn't report it.
+    pub firmware_name: Option<String>,
+    /// `None` if the device didn't report it.
+    pub firmware_build: Option<String>,
+    /// `None` if the device didn't report it.
+    pub board_id: Option<String>,
+    /// The optional protocol features that the device supports, such as
+    /// `requestIds`.
+    pub capabilities: Vec<String>,
+}
+
+/// Counters describing the health of an executor's connection to its device.
+/// Shared between the executor and its manager, so that they can be read while
+/// the executor is busy running a command.
+#[derive(Default, Debug)]
+pub struct ExecutorStats {
+    /// Frames received from the device that failed their integrity check and
+    /// were discarded.
+    pub corrupted_frames: AtomicU64,
+    /// Frames received from the device that couldn't be parsed.
+    pub malformed_frames: AtomicU64,
+    /// Requests that were resent because the previous attempt went
+    /// unanswered or was corrupted.
+    pub request_retries: AtomicU64,
+    /// Request attempts that got no response in time.
+    pub request_timeouts: AtomicU64,
+    /// Commands that failed in a row since the last one that succeeded.
+    /// Commands rejected for being unknown or having invalid arguments don't
+    /// count, since they say nothing about the device.
+    pub consecutive_failures: AtomicU64,
+    /// Whether the most recent command timed out or got a garbled or no
+    /// response, as opposed to an error reported by the device itself.
+    pub unresponsive: AtomicBool,
+    /// Whether the connection to the device is gone for good, such as when its
+    /// port can no longer be read. Disconnected executors are removed by
+    /// whoever added them.
+    pub disconnected: AtomicBool,
+    pub last_success: Mutex<Option<CommandOutcome>>,
+    pub last_error: Mutex<Option<CommandOutcome>>,
+}
+
+impl ExecutorStats {
+    /// Returns a JSON snapshot of the counters for API clients.
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "corruptedFrames": self.corrupted_frames.load(Ordering::Relaxed),
+            "malformedFrames": self.malformed_frames.load(Ordering::Relaxed),
+            "requestRetries": self.request_retries.load(Ordering::Relaxed),
+            "requestTimeouts": self.request_timeouts.load(Ordering::Relaxed),
+            "consecutiveFailures": self.consecutive_failures.load(Ordering::Relaxed)
+        })
+    }
+
+    /// Returns the counters that grow while requests are exchanged with the
+    /// device.
+    fn get_request_counters(&self) -> RequestCounters {
+        RequestCounters {
+            retries: self.request_retries.load(Ordering::Relaxed),
+            timeouts: self.request_timeouts.load(Ordering::Relaxed),
+            corrupted_frames: self.corrupted_frames.load(Ordering::Relaxed),
+            malformed_frames: self.malformed_frames.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Records the result of a command that ran on the executor.
+    fn record_result(
+        &self,
+        command: &str,
+        namespace: &str,
+        res: &Result<CommandValue, CommandExecutorError>,
+    ) {
+        match res {
+            Ok(_) => {
+                self.consecutive_failures.store(0, Ordering::Relaxed);
+                self.unresponsive.store(false, Ordering::Relaxed);
+                *self.last_success.lock().unwrap() = Some(CommandOutcome {
+                    command: command.to_string(),
+                    timestamp: get_unix_timestamp_millis(),
+                    error: None,
+                });
+            }
+            Err(
+                CommandExecutorError::UnknownCommand | CommandExecutorError::InvalidArguments(_),
+            ) => {}
+            Err(err) => {
+                self.consecutive_failures.fetch_add(1, Ordering::Relaxed);
+                self.unresponsive.store(
+                    matches!(
+                        err,
+                        CommandExecutorError::Timeout
+                            | CommandExecutorError::MalformedResponse
+                            | CommandExecutorError::ConnectionError(_)
+                    ),
+                    Ordering::Relaxed,
+                );
+                *self.last_error.lock().unwrap() = Some(CommandOutcome {
+                    command: command.to_string(),
+                    timestamp: get_unix_timestamp_millis(),
+                    error: Some(err.to_json(Some(namespace))),
+                });
+            }
+        }
+    }
+}
+
+/// How often requests had to be resent or got bad responses, either in total
+/// or while running a single command.
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct RequestCounters {
+    pub retries: u64,
+    pub timeouts: u64,
+    pub corrupted_frames: u64,
+    pub malformed_frames: u64,
+}
+
+impl RequestCounters {
+    /// Returns how much each counter grew since `earlier`.
+    fn since(&self, earlier: &RequestCounters) -> RequestCounters {
+        RequestCounters {
+            retries: self.retries.saturating_sub(earlier.retries),
+            timeouts: self.timeouts.saturating_sub(earlier.timeouts),
+            corrupted_frames: self
+                .corrupted_frames
+                .saturating_sub(earlier.corrupted_frames),
+            malformed_frames: self
+                .malformed_frames
+                .saturating_sub(earlier.malformed_frames),
+        }
+    }
+}
+
+/// A command that ran on an executor, and how it went.
+#[derive(Clone, Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CommandOutcome {
+    /// The command as it was requested, which may use a namespace alias.
+    pub command: String,
+    /// When the command finished, in milliseconds since the Unix epoch.
+    pub timestamp: u64,
+    /// `None` if the command succeeded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<serde_json::Value>,
+}
+
+/// Whether an executor's device can currently be talked to.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ConnectionState {
+    Connected,
+    /// The device is connected, but its most recent command timed out or got
+    /// a garbled or no response.
+    Unresponsive,
+    /// No executor is registered under an aliased namespace, such as when its
+    /// board is unplugged.
+    Disconnected,
+}
+
+/// A snapshot of an executor's health, for monitoring.
+#[derive(Clone, Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExecutorStatus {
+    pub connection_state: ConnectionState,
+    /// The aliases that refer to the executor's namespace.
+    pub aliases: Vec<String>,
+    /// `None` if the executor is disconnected.
+    pub device_info: Option<DeviceInfo>,
+    pub last_success: Option<CommandOutcome>,
+    pub last_error: Option<CommandOutcome>,
+    pub consecutive_failures: u64,
+}
+
+/// An error returned when executing a command.
+#[derive(Debug)]
+pub enum CommandExecutorError {
+    /// No command with the given name returns the given value type.
+    UnknownCommand,
+    /// The arguments passed to the command don't match its spec.
+    InvalidArguments(String),
+    /// The executor didn't respond in time. The command may or may not have
+    /// been executed.
+    Timeout,
+    /// The executor lost track of whether the command was executed, such as
+    /// when the board was reset while running it. The command may or may not
+    /// have been executed, and the executor can no longer tell.
+    Indeterminate,
+    /// The executor responded with something that couldn't be understood.
+    MalformedResponse,
+    /// The executor can't accept the command right now, but may be able to
+    /// later.
+    Busy,
+    /// The executor received the command but reported that it failed, such as
+    /// when a motor is jammed.
+    DeviceError {
+        /// Machine-readable error code reported by the executor.
+        code: String,
+        /// Human-readable error message reported by the executor.
+        message: String,
+    },
+    /// The executor couldn't be communicated with, such as when its USB cable
+    /// has been unplugged.
+    ConnectionError(String),
+}
+
+impl CommandExecutorError {
+    /// Returns a stable, machine-readable name for the kind of error.
+    pub fn get_kind(&self) -> &'static str {
+        match self {
+            Self::UnknownCommand => "unknownCommand",
+            Self::InvalidArguments(_) => "invalidArguments",
+            Self::Timeout => "timeout",
+            Self::Indeterminate => "indeterminate",
+            Self::MalformedResponse => "malformedResponse",
+            Self::Busy => "busy",
+            Self::DeviceError { .. } => "deviceError",
+            Self::ConnectionError(_) => "connectionError",
+        }
+    }
+
+    /// Returns a JSON description of the error for API clients. `namespace` is
+    /// the namespace of the executor that the failed command was sent to, if
+    /// any.
+    pub fn to_json(&self, namespace: Option<&str>) -> serde_json::Value {
+        let mut error_json = serde_json::json!({
+            "kind": self.get_kind(),
+            "message": self.to_string(),
+            "namespace": namespace
+        });
+        if let Self::DeviceError { code, .. } = self {
+            error_json["code"] = serde_json::json!(code);
+        }
+        error_json
+    }
+}
+
+impl std::fmt::Display for CommandExecutorError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::UnknownCommand => write!(f, "Unknown command"),
+            Self::InvalidArguments(message) => write!(f, "Invalid arguments: {message}"),
+            Self::Timeout => write!(f, "Timed out waiting for a response"),
+            Self::Indeterminate => write!(f, "Lost track of whether the command was executed"),
+            Self::MalformedResponse => write!(f, "Received a malformed response"),
+            Self::Busy => write!(f, "Executor is busy"),
+            Self::DeviceError { code, message } => write!(f, "Device error ({code}): {message}"),
+            Self::ConnectionError(message) => write!(f, "Connection error: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for CommandExecutorError {}
+
 pub trait CommandExecutor: Send + Sync {
-    /// Returns all available commands for this executor that return null/void
-    /// in no particular order. Available commands are immutable and will not
-    /// change after the creation of the `CommandExecutor` instance, so calling
-    /// this repeatedly will always yield the same results - although not
-    /// necessarily in the same order.
-    fn get_null_commands(&self) -> Box<dyn Iterator<Item = &str> + '_>;
-
-    /// Executes a given command that returns null/void. Guaranteed to return an
-    /// error if `command` is not a value returned by `get_commands`. Command is
-    /// not guaranteed to have fully executed unless an `Ok` result is returned.
-    /// However, the action may have still been executed if an `Err` result is
-    /// returned.
-    fn execute_null_command(&mut self, command: &str) -> Result<(), Box<dyn std::error::Error>>;
-
-    /// Returns all available commands for this executor that return a boolean
-    /// in no particular order. Available commands are immutable and will not
-    /// change after the creation of the `CommandExecutor` instance, so calling
-    /// this repeatedly will always yield the same results - although not
-    /// necessarily in the same order.
-    fn get_bool_commands(&self) -> Box<dyn Iterator<Item = &str> + '_>;
-
-    /// Executes a given command that returns a boolean. Guaranteed to return an
-    /// error if `command` is not a value returned by `get_commands`. Command is
-    /// not guaranteed to have fully executed unless an `Ok` result is returned.
-    /// However, the action may have still been executed if an `Err` result is
-    /// returned.
-    fn execute_bool_command(&mut self, command: &str) -> Result<bool, Box<dyn std::error::Error>>;
+    /// Returns all available commands for this executor that return a value of
+    /// type `value_type` in no particular order. Available commands are
+    /// immutable and will not change after the creation of the
+    /// `CommandExecutor` instance, so calling this repeatedly will always yield
+    /// the same results - although not necessarily in the same order.
+    fn get_commands(
+        &self,
+        value_type: CommandValueType,
+    ) -> Box<dyn Iterator<Item = &CommandSpec> + '_>;
+
+    /// Executes a given command that returns a value of type `value_type`.
+    /// Guaranteed to return an error if `command` is not the name of a command
+    /// returned by `get_commands` for the same `value_type`, or if `arguments`
+    /// don't match the command's arguments. Guaranteed to return a value of
+    /// type `value_type` on success. Command is not guaranteed to have fully
+    /// executed unless an `Ok` result is returned. However, the action may have
+    /// still been executed if an `Err` result is returned.
+    fn execute_command(
+        &mut self,
+        command: &str,
+        value_type: CommandValueType,
+        arguments: &CommandArguments,
+    ) -> Result<CommandValue, CommandExecutorError>;
 }
 
 pub trait NamespacedCommandExecutor: CommandExecutor {
@@ -36,103 +484,1351 @@ pub trait NamespacedCommandExecutor: CommandExecutor {
     /// any commands that need to be passed to this executor. Must be an
     /// immutable value for the lifetime of the `CommandExecutor`.
     fn get_executor_namespace(&self) -> &str;
+
+    /// Returns information about the device behind this executor. Must be an
+    /// immutable value for the lifetime of the `CommandExecutor`.
+    fn get_device_info(&self) -> DeviceInfo;
+
+    /// Returns the executor's counters. Must return the same instance for the
+    /// lifetime of the `CommandExecutor`.
+    fn get_stats(&self) -> Arc<ExecutorStats>;
+}
+
+/// A command registered with a `CommandExecutorManager`.
+struct RegisteredCommand {
+    namespace: String,
+    subcommand: String,
+    /// The command's spec, named with its fully namespaced name.
+    spec: CommandSpec,
+}
+
+/// An executor registered with a `CommandExecutorManager`. Each executor has
+/// its own lock, so that commands on different executors can run concurrently
+/// while commands on the same executor run one at a time.
+struct SharedCommandExecutor {
+    executor: Mutex<Box<dyn NamespacedCommandExecutor>>,
+    /// Whether a command holds the executor. Commands wait on `freed` for it
+    /// to be cleared, which unlike `executor`'s lock can be done with a
+    /// timeout.
+    busy: Mutex<bool>,
+    freed: Condvar,
+}
+
+/// Marks a `SharedCommandExecutor` as busy until dropped.
+struct BusyExecutorGuard<'a>(&'a SharedCommandExecutor);
+
+impl Drop for BusyExecutorGuard<'_> {
+    fn drop(&mut self) {
+        *self.0.busy.lock().unwrap() = false;
+        self.0.freed.notify_one();
+    }
+}
+
+impl SharedCommandExecutor {
+    fn new(executor: Box<dyn NamespacedCommandExecutor>) -> Self {
+        Self {
+            executor: Mutex::new(executor),
+            busy: Mutex::new(false),
+            freed: Condvar::new(),
+        }
+    }
+
+    /// Waits for the executor to be free and marks it as busy, failing with
+    /// `Busy` if the command it's running doesn't finish within `max_wait`, or
+    /// waiting indefinitely if `max_wait` is `None`.
+    fn acquire(
+        &self,
+        max_wait: Option<Duration>,
+    ) -> Result<BusyExecutorGuard<'_>, CommandExecutorError> {
+        let busy = self.busy.lock().unwrap();
+        let mut busy = match max_wait {
+            Some(max_wait) => {
+                let (busy, wait_result) = self
+                    .freed
+                    .wait_timeout_while(busy, max_wait, |busy| *busy)
+                    .unwrap();
+                if wait_result.timed_out() {
+                    return Err(CommandExecutorError::Busy);
+                }
+                busy
+            }
+            None => self.freed.wait_while(busy, |busy| *busy).unwrap(),
+        };
+        *busy = true;
+        Ok(BusyExecutorGuard(self))
+    }
+
+    /// Locks the executor once it has been acquired. An executor whose last
+    /// command panicked may have been left in any state, so it isn't used
+    /// again, and is marked as disconnected so that it gets replaced.
+    fn lock(
+        &self,
+        _busy: &BusyExecutorGuard,
+    ) -> Result<MutexGuard<'_, Box<dyn NamespacedCommandExecutor>>, CommandExecutorError> {
+        self.executor.lock().map_err(|err| {
+            err.into_inner()
+                .get_stats()
+                .disconnected
+                .store(true, Ordering::Relaxed);
+            CommandExecutorError::ConnectionError(String::from(
+                "Executor failed while running a previous command",
+            ))
+        })
+    }
+}
+
+/// How long `CommandExecutorManager::execute_command` waits for the command
+/// that an executor is running to finish. Long enough for quick reads to queue
+/// up behind each other, but not for a vend.
+const MAX_EXECUTOR_WAIT: Duration = Duration::from_millis(500);
+
+#[derive(Default)]
+struct CommandExecutorRegistry {
+    command_executors_by_namespace: HashMap<String, Arc<SharedCommandExecutor>>,
+    /// Cached when executors are registered, so that it can be read without
+    /// waiting for the executor to finish running a command.
+    device_info_by_namespace: HashMap<String, DeviceInfo>,
+    stats_by_namespace: HashMap<String, Arc<ExecutorStats>>,
+    commands_by_value_type: HashMap<CommandValueType, HashMap<String, RegisteredCommand>>,
+    /// Maps logical names, such as `left-column`, to the namespaces of the
+    /// executors that they currently stand for. `left-column:stepper0` then
+    /// runs `stepper0` on that executor, so that replacing a board only
+    /// requires updating its alias.
+    namespace_aliases: HashMap<String, String>,
+}
+
+impl CommandExecutorRegistry {
+    /// Looks up a command by its namespaced name, or by a name that starts with
+    /// a namespace alias instead.
+    fn get_command(
+        &self,
+        command: &str,
+        value_type: CommandValueType,
+    ) -> Option<&RegisteredCommand> {
+        let commands = self.commands_by_value_type.get(&value_type)?;
+        if let Some(registered_command) = commands.get(command) {
+            return Some(registered_command);
+        }
+
+        let (alias, subcommand) = command.split_once(':')?;
+        let namespace = self.namespace_aliases.get(alias)?;
+        commands.get(&format!("{namespace}:{subcommand}"))
+    }
 }
 
+/// Routes namespaced commands to the executors that run them. All methods take
+/// `&self`, so a single manager can be shared between threads. The registry of
+/// executors and commands is only locked briefly, and never while a command is
+/// executing.
 pub struct CommandExecutorManager {
-    command_executors_by_namespace: HashMap<String, Box<dyn NamespacedCommandExecutor>>,
-    null_commands_to_namespace_and_subcommand: HashMap<String, (String, String)>,
-    bool_commands_to_namespace_and_subcommand: HashMap<String, (String, String)>,
+    registry: RwLock<CommandExecutorRegistry>,
+    /// Receives an event whenever an executor is added or removed, and
+    /// whenever a command starts or finishes.
+    event_bus: Arc<EventBus>,
+    metrics: Metrics,
+    /// Receives a record of every command that reaches an executor when it
+    /// starts and when it's done, if set.
+    audit_log: RwLock<Option<Arc<AuditLog>>>,
 }
 
 impl CommandExecutorManager {
-    pub fn new(command_executors: Vec<Box<dyn NamespacedCommandExecutor>>) -> Result<Self, String> {
-        let mut command_executors_by_namespace: HashMap<
-            String,
-            Box<dyn NamespacedCommandExecutor>,
-        > = HashMap::new();
-        let mut null_commands_to_namespace_and_subcommand: HashMap<String, (String, String)> =
-            HashMap::new();
-        let mut bool_commands_to_namespace_and_subcommand: HashMap<String, (String, String)> =
-            HashMap::new();
+    pub fn new(
+        command_executors: Vec<Box<dyn NamespacedCommandExecutor>>,
+        event_bus: Arc<EventBus>,
+    ) -> Result<Self, String> {
+        let command_executor_manager = Self {
+            registry: RwLock::new(CommandExecutorRegistry::default()),
+            event_bus,
+            metrics: Metrics::new(),
+            audit_log: RwLock::new(None),
+        };
 
         for ce in command_executors {
-            let namespace = ce.get_executor_namespace();
-            if command_executors_by_namespace.contains_key(namespace) {
-                return Err(format!("Duplicate executor namespace '{namespace}'"));
+            command_executor_manager.add_executor(ce)?;
+        }
+
+        Ok(command_executor_manager)
+    }
+
+    /// Returns the namespaces of all registered executors in no particular
+    /// order.
+    pub fn get_executor_namespaces(&self) -> Vec<String> {
+        self.registry
+            .read()
+            .unwrap()
+            .command_executors_by_namespace
+            .keys()
+            .cloned()
+            .collect()
+    }
+
+    /// Returns information about the device behind every registered executor,
+    /// keyed by executor namespace.
+    pub fn get_device_info_by_namespace(&self) -> HashMap<String, DeviceInfo> {
+        self.registry
+            .read()
+            .unwrap()
+            .device_info_by_namespace
+            .clone()
+    }
+
+    /// Returns a JSON snapshot of every registered executor's counters, keyed
+    /// by executor namespace.
+    pub fn get_stats_by_namespace(&self) -> serde_json::Map<String, serde_json::Value> {
+        self.registry
+            .read()
+            .unwrap()
+            .stats_by_namespace
+            .iter()
+            .map(|(namespace, stats)| (namespace.clone(), stats.to_json()))
+            .collect()
+    }
+
+    /// Returns the command metrics of every executor that ever ran a command,
+    /// in the Prometheus text format.
+    pub fn get_metrics_text(&self) -> String {
+        let executor_count = self
+            .registry
+            .read()
+            .unwrap()
+            .command_executors_by_namespace
+            .len();
+        self.metrics.get_text(executor_count)
+    }
+
+    /// Returns the health of every registered executor, and of every aliased
+    /// namespace that has no executor registered, keyed by namespace.
+    pub fn get_executor_statuses(&self) -> BTreeMap<String, ExecutorStatus> {
+        let registry = self.registry.read().unwrap();
+        let get_aliases = |namespace: &str| {
+            let mut aliases: Vec<String> = registry
+                .namespace_aliases
+                .iter()
+                .filter(|(_, aliased_namespace)| *aliased_namespace == namespace)
+                .map(|(alias, _)| alias.clone())
+                .collect();
+            aliases.sort();
+            aliases
+        };
+
+        let mut executor_statuses: BTreeMap<String, ExecutorStatus> = registry
+            .stats_by_namespace
+            .iter()
+            .map(|(namespace, stats)| {
+                (
+                    namespace.clone(),
+                    ExecutorStatus {
+                        connection_state: if stats.disconnected.load(Ordering::Relaxed) {
+                            ConnectionState::Disconnected
+                        } else if stats.unresponsive.load(Ordering::Relaxed) {
+                            ConnectionState::Unresponsive
+                        } else {
+                            ConnectionState::Connected
+                        },
+                        aliases: get_aliases(namespace),
+                        device_info: registry.device_info_by_namespace.get(namespace).cloned(),
+                        last_success: stats.last_success.lock().unwrap().clone(),
+                        last_error: stats.last_error.lock().unwrap().clone(),
+                        consecutive_failures: stats.consecutive_failures.load(Ordering::Relaxed),
+                    },
+                )
+            })
+            .collect();
+        for namespace in registry.namespace_aliases.values() {
+            if !executor_statuses.contains_key(namespace) {
+                executor_statuses.insert(
+                    namespace.clone(),
+                    ExecutorStatus {
+                        connection_state: ConnectionState::Disconnected,
+                        aliases: get_aliases(namespace),
+                        device_info: None,
+                        last_success: None,
+                        last_error: None,
+                        consecutive_failures: 0,
+                    },
+                );
             }
-            command_executors_by_namespace.insert(namespace.to_string(), ce);
         }
+        executor_statuses
+    }
+
+    /// Returns whether the executor with the given namespace has lost its
+    /// connection to its device for good.
+    pub fn is_executor_disconnected(&self, namespace: &str) -> bool {
+        self.registry
+            .read()
+            .unwrap()
+            .stats_by_namespace
+            .get(namespace)
+            .is_some_and(|stats| stats.disconnected.load(Ordering::Relaxed))
+    }
+
+    /// Registers a new executor, making its commands available immediately.
+    /// Returns an error without modifying the manager if the executor's
+    /// namespace or any of its commands are already registered.
+    pub fn add_executor(&self, ce: Box<dyn NamespacedCommandExecutor>) -> Result<(), String> {
+        let mut registry = self.registry.write().unwrap();
+
+        let namespace = ce.get_executor_namespace().to_string();
+        if registry
+            .command_executors_by_namespace
+            .contains_key(&namespace)
+        {
+            return Err(format!("Duplicate executor namespace '{namespace}'"));
+        }
+
+        let mut new_commands_by_value_type = Vec::new();
+        for value_type in CommandValueType::ALL {
+            new_commands_by_value_type.push((
+                value_type,
+                Self::get_namespaced_commands(
+                    &namespace,
+                    ce.get_commands(value_type),
+                    registry.commands_by_value_type.get(&value_type),
+                )?,
+            ));
+        }
+
+        for (value_type, new_commands) in new_commands_by_value_type {
+            registry
+                .commands_by_value_type
+                .entry(value_type)
+                .or_default()
+                .extend(new_commands);
+        }
+        let device_info = ce.get_device_info();
+        self.event_bus.publish(
+            &namespace,
+            "executorConnected",
+            serde_json::json!(device_info),
+        );
+        registry
+            .device_info_by_namespace
+            .insert(namespace.clone(), device_info);
+        registry
+            .stats_by_namespace
+            .insert(namespace.clone(), ce.get_stats());
+        registry
+            .command_executors_by_namespace
+            .insert(namespace, Arc::new(SharedCommandExecutor::new(ce)));
+
+        Ok(())
+    }
+
+    /// Unregisters the executor with the given namespace along with all of its
+    /// commands. Returns whether the executor was registered. Commands that
+    /// are already executing on the executor are allowed to finish, and the
+    /// executor is dropped once they have.
+    pub fn remove_executor(&self, namespace: &str) -> bool {
+        let mut registry = self.registry.write().unwrap();
+
+        if registry
+            .command_executors_by_namespace
+            .remove(namespace)
+            .is_none()
+        {
+            return false;
+        }
+        registry.device_info_by_namespace.remove(namespace);
+        registry.stats_by_namespace.remove(namespace);
+        self.event_bus
+            .publish(namespace, "executorDisconnected", serde_json::Value::Null);
 
-        for ce in command_executors_by_namespace.values() {
-            let namespace = ce.get_executor_namespace();
+        for commands in registry.commands_by_value_type.values_mut() {
+            commands.retain(|_, registered_command| registered_command.namespace != namespace);
+        }
+
+        true
+    }
 
-            for subcommand in ce.get_null_commands() {
-                let command = format!("{namespace}:{subcommand}");
-                if null_commands_to_namespace_and_subcommand.contains_key(&command) {
-                    return Err(format!("Duplicate command '{command}'"));
+    /// Replaces every namespace alias. Aliases may refer to executors that
+    /// aren't registered yet, and take effect once they are.
+    pub fn set_namespace_aliases(&self, namespace_aliases: HashMap<String, String>) {
+        self.registry.write().unwrap().namespace_aliases = namespace_aliases;
+    }
+
+    /// Starts recording every executed command to `audit_log`.
+    pub fn set_audit_log(&self, audit_log: Arc<AuditLog>) {
+        *self.audit_log.write().unwrap() = Some(audit_log);
+    }
+
+    /// Returns the namespace aliases, keyed by alias.
+    pub fn get_namespace_aliases(&self) -> HashMap<String, String> {
+        self.registry.read().unwrap().namespace_aliases.clone()
+    }
+
+    /// Returns all available commands that return a value of type
+    /// `value_type` in no particular order. Commands of aliased executors are
+    /// returned under both their namespaced and their aliased names.
+    pub fn get_commands(&self, value_type: CommandValueType) -> Vec<CommandSpec> {
+        let registry = self.registry.read().unwrap();
+        let Some(commands) = registry.commands_by_value_type.get(&value_type) else {
+            return Vec::new();
+        };
+
+        let mut command_specs = Vec::new();
+        for registered_command in commands.values() {
+            command_specs.push(registered_command.spec.clone());
+            for (alias, namespace) in &registry.namespace_aliases {
+                if *namespace == registered_command.namespace {
+                    command_specs.push(CommandSpec {
+                        name: format!("{alias}:{}", registered_command.subcommand),
+                        ..registered_command.spec.clone()
+                    });
                 }
-                null_commands_to_namespace_and_subcommand
-                    .insert(command, (namespace.to_string(), subcommand.to_string()));
             }
+        }
+        command_specs
+    }
 
-            for subcommand in ce.get_bool_commands() {
-                let command = format!("{namespace}:{subcommand}");
-                if bool_commands_to_namespace_and_subcommand.contains_key(&command) {
-                    return Err(format!("Duplicate command '{command}'"));
-                }
-                bool_commands_to_namespace_and_subcommand
-                    .insert(command, (namespace.to_string(), subcommand.to_string()));
+    /// Returns the namespace of the executor that runs the given command, or
+    /// `None` if there is no such command.
+    pub fn get_command_namespace(
+        &self,
+        command: &str,
+        value_type: CommandValueType,
+    ) -> Option<String> {
+        self.registry
+            .read()
+            .unwrap()
+            .get_command(command, value_type)
+            .map(|registered_command| registered_command.namespace.clone())
+    }
+
+    /// Executes a namespaced or aliased command. Has the same guarantees as
+    /// `CommandExecutor::execute_command`. Fails with `Busy` if another command
+    /// on the same executor, such as a vend, doesn't finish within
+    /// `MAX_EXECUTOR_WAIT`. Commands on other executors don't hold it up.
+    pub fn execute_command(
+        &self,
+        command: &str,
+        value_type: CommandValueType,
+        arguments: &CommandArguments,
+        caller: &CallerContext,
+    ) -> Result<CommandValue, CommandExecutorError> {
+        self.execute_command_on_executor(
+            command,
+            value_type,
+            arguments,
+            caller,
+            Some(MAX_EXECUTOR_WAIT),
+            || {},
+        )
+    }
+
+    /// Same as `execute_command`, but waits for as long as other commands on
+    /// the same executor take, and calls `on_start` once the executor starts
+    /// executing this one. Publishes `commandStarted` when the command starts,
+    /// and `commandFinished` or `commandFailed` when it's done.
+    pub fn execute_command_with_start_callback(
+        &self,
+        command: &str,
+        value_type: CommandValueType,
+        arguments: &CommandArguments,
+        caller: &CallerContext,
+        on_start: impl FnOnce(),
+    ) -> Result<CommandValue, CommandExecutorError> {
+        self.execute_command_on_executor(command, value_type, arguments, caller, None, on_start)
+    }
+
+    /// Executes a command once its executor is free, waiting at most
+    /// `max_wait` for it, or indefinitely if `max_wait` is `None`.
+    fn execute_command_on_executor(
+        &self,
+        command: &str,
+        value_type: CommandValueType,
+        arguments: &CommandArguments,
+        caller: &CallerContext,
+        max_wait: Option<Duration>,
+        on_start: impl FnOnce(),
+    ) -> Result<CommandValue, CommandExecutorError> {
+        let (ce, stats, namespace, subcommand) = {
+            let registry = self.registry.read().unwrap();
+
+            let registered_command = match registry.get_command(command, value_type) {
+                Some(registered_command) => registered_command,
+                None => return Err(CommandExecutorError::UnknownCommand),
+            };
+
+            match (
+                registry
+                    .command_executors_by_namespace
+                    .get(&registered_command.namespace),
+                registry
+                    .stats_by_namespace
+                    .get(&registered_command.namespace),
+            ) {
+                (Some(ce), Some(stats)) => (
+                    ce.clone(),
+                    stats.clone(),
+                    registered_command.namespace.clone(),
+                    registered_command.subcommand.clone(),
+                ),
+                _ => return Err(CommandExecutorError::UnknownCommand),
             }
+        };
+
+        let _span =
+            tracing::info_span!("command", namespace = %namespace, command = %subcommand).entered();
+        let busy = ce.acquire(max_wait).inspect_err(|_| {
+            tracing::info!("Executor is busy with another command");
+        })?;
+        let mut ce = ce.lock(&busy).inspect_err(|err| {
+            tracing::error!(error = %err, "Executor is unusable");
+        })?;
+        on_start();
+        tracing::debug!(?value_type, ?arguments, "Executing command");
+        // Recorded before anything is sent, so that commands that never finish
+        // because the server crashed or the device hung are still on record.
+        let started_sequence = self.record_audit_entry(&AuditEntry {
+            timestamp: get_unix_timestamp_millis(),
+            duration_ms: 0,
+            namespace: &namespace,
+            command,
+            value_type,
+            arguments,
+            request_id: caller.request_id.as_deref(),
+            context: caller.context.as_deref(),
+            attempts: 0,
+            outcome: "started",
+            started_sequence: None,
+            result: None,
+            error: None,
+        });
+        let start_time = Instant::now();
+        let request_counters = stats.get_request_counters();
+        self.event_bus.publish(
+            &namespace,
+            "commandStarted",
+            serde_json::json!({"command": command, "type": value_type}),
+        );
+        let res = ce.execute_command(&subcommand, value_type, arguments);
+        let elapsed = start_time.elapsed();
+        stats.record_result(command, &namespace, &res);
+        match &res {
+            Ok(_) => tracing::info!(elapsed_ms = elapsed.as_millis() as u64, "Command succeeded"),
+            Err(err) => tracing::warn!(
+                elapsed_ms = elapsed.as_millis() as u64,
+                error = %err.to_json(Some(&namespace)),
+                "Command failed"
+            ),
+        }
+        let outcome = match &res {
+            Ok(_) => "success",
+            Err(err) => err.get_kind(),
+        };
+        let request_counters = stats.get_request_counters().since(&request_counters);
+        self.metrics
+            .record_command(&namespace, &subcommand, outcome, elapsed, &request_counters);
+        self.record_audit_entry(&AuditEntry {
+            timestamp: get_unix_timestamp_millis(),
+            duration_ms: elapsed.as_millis() as u64,
+            namespace: &namespace,
+            command,
+            value_type,
+            arguments,
+            request_id: caller.request_id.as_deref(),
+            context: caller.context.as_deref(),
+            attempts: request_counters.retries + 1,
+            outcome,
+            started_sequence,
+            result: res.as_ref().ok(),
+            error: res.as_ref().err().map(|err| err.to_json(Some(&namespace))),
+        });
+        match &res {
+            Ok(value) => self.event_bus.publish(
+                &namespace,
+                "commandFinished",
+                serde_json::json!({"command": command, "type": value_type, "result": value}),
+            ),
+            Err(err) => self.event_bus.publish(
+                &namespace,
+                "commandFailed",
+                serde_json::json!({
+                    "command": command,
+                    "type": value_type,
+                    "error": err.to_json(Some(&namespace))
+                }),
+            ),
         }
+        res
+    }
 
-        Ok(Self {
-            command_executors_by_namespace,
-            null_commands_to_namespace_and_subcommand,
-            bool_commands_to_namespace_and_subcommand,
-        })
+    /// Appends an entry to the audit log, if there is one. Returns the
+    /// record's sequence, or `None` if it couldn't be recorded.
+    fn record_audit_entry(&self, audit_entry: &AuditEntry) -> Option<u64> {
+        let audit_log = self.audit_log.read().unwrap();
+        audit_log
+            .as_ref()?
+            .record(audit_entry)
+            .inspect_err(|err| {
+                tracing::error!(error = %err, "Unable to record command to the audit log");
+            })
+            .ok()
+    }
+
+    fn get_namespaced_commands<'a>(
+        namespace: &str,
+        subcommands: impl Iterator<Item = &'a CommandSpec>,
+        existing_commands: Option<&HashMap<String, RegisteredCommand>>,
+    ) -> Result<HashMap<String, RegisteredCommand>, String> {
+        let mut namespaced_commands = HashMap::new();
+
+        for subcommand in subcommands {
+            let command = format!("{namespace}:{}", subcommand.name);
+            if existing_commands
+                .is_some_and(|existing_commands| existing_commands.contains_key(&command))
+                || namespaced_commands.contains_key(&command)
+            {
+                return Err(format!("Duplicate command '{command}'"));
+            }
+            namespaced_commands.insert(
+                command.clone(),
+                RegisteredCommand {
+                    namespace: namespace.to_string(),
+                    subcommand: subcommand.name.clone(),
+                    spec: CommandSpec {
+                        name: command,
+                        ..subcommand.clone()
+                    },
+                },
+            );
+        }
+
+        Ok(namespaced_commands)
     }
 }
 
-impl CommandExecutor for CommandExecutorManager {
-    fn get_null_commands(&self) -> Box<dyn Iterator<Item = &str> + '_> {
-        Box::from(
-            self.null_commands_to_namespace_and_subcommand
-                .keys()
-                .map(|s| s.as_str()),
+#[cfg(test)]
+mod tests {
+    use super::liveace::{LiVeAceSerialPort, RetryPolicy};
+    use super::*;
+    use crate::events::EventBus;
+    use crate::liveace_simulator::{SimulatedBoard, SimulatedSerialPort, SimulatorConfig};
+    use std::hash::{BuildHasher, Hasher};
+    use std::time::Duration;
+
+    fn get_simulated_executor(
+        board_serial_number: &str,
+        vend_duration: Duration,
+    ) -> Box<dyn NamespacedCommandExecutor> {
+        let port = SimulatedSerialPort::new(SimulatorConfig {
+            vend_duration,
+            command_duration: Duration::from_millis(1),
+            boot_duration: Duration::ZERO,
+            ..Default::default()
+        });
+        Box::new(
+            LiVeAceSerialPort::new(
+                Box::new(port),
+                Some(board_serial_number.to_string()),
+                Arc::new(EventBus::new()),
+                RetryPolicy::default(),
+                RetryPolicy::default(),
+            )
+            .unwrap(),
         )
     }
 
-    fn execute_null_command(&mut self, command: &str) -> Result<(), Box<dyn std::error::Error>> {
-        let (namespace, subcommand) =
-            match self.null_commands_to_namespace_and_subcommand.get(command) {
-                Some((namespace, subcommand)) => (namespace, subcommand),
-                None => return Err(Box::from(String::from("Unknown command"))),
-            };
+    #[test]
+    fn parses_command_values() {
+        let parse = |value_type: CommandValueType, raw_value: serde_json::Value| {
+            value_type.parse_value(Some(raw_value))
+        };
 
-        match self.command_executors_by_namespace.get_mut(namespace) {
-            Some(ce) => ce.execute_null_command(subcommand),
-            None => Err(Box::from(String::from("Unknown command"))),
+        assert_eq!(
+            CommandValueType::Null.parse_value(None),
+            Some(CommandValue::Null)
+        );
+        assert_eq!(
+            parse(CommandValueType::Boolean, serde_json::json!(true)),
+            Some(CommandValue::Boolean(true))
+        );
+        assert_eq!(
+            parse(CommandValueType::Integer, serde_json::json!(-7)),
+            Some(CommandValue::Integer(-7))
+        );
+        assert_eq!(
+            parse(CommandValueType::Float, serde_json::json!(0.25)),
+            Some(CommandValue::Float(0.25))
+        );
+        // Whole numbers are valid floats, but fractions aren't integers.
+        assert_eq!(
+            parse(CommandValueType::Float, serde_json::json!(3)),
+            Some(CommandValue::Float(3.0))
+        );
+        assert_eq!(
+            parse(CommandValueType::Integer, serde_json::json!(3.5)),
+            None
+        );
+        assert_eq!(
+            parse(CommandValueType::String, serde_json::json!("ok")),
+            Some(CommandValue::String(String::from("ok")))
+        );
+        assert_eq!(
+            parse(CommandValueType::Json, serde_json::json!({"a": [1]})),
+            Some(CommandValue::Json(serde_json::json!({"a": [1]})))
+        );
+
+        assert_eq!(
+            parse(CommandValueType::Null, serde_json::json!(false)),
+            None
+        );
+        assert_eq!(parse(CommandValueType::Boolean, serde_json::json!(1)), None);
+        assert_eq!(
+            parse(CommandValueType::Integer, serde_json::json!("1")),
+            None
+        );
+        assert_eq!(
+            parse(CommandValueType::Float, serde_json::json!(null)),
+            None
+        );
+        assert_eq!(parse(CommandValueType::String, serde_json::json!(1)), None);
+        assert_eq!(CommandValueType::String.parse_value(None), None);
+    }
+
+    fn get_argument_spec(
+        name: &str,
+        argument_type: CommandArgumentType,
+        min: Option<f64>,
+        max: Option<f64>,
+    ) -> CommandArgumentSpec {
+        CommandArgumentSpec {
+            name: name.to_string(),
+            argument_type,
+            min,
+            max,
         }
     }
 
-    fn get_bool_commands(&self) -> Box<dyn Iterator<Item = &str> + '_> {
-        Box::from(
-            self.bool_commands_to_namespace_and_subcommand
-                .keys()
-                .map(|s| s.as_str()),
+    #[test]
+    fn validates_argument_types_and_bounds() {
+        let integer = get_argument_spec("n", CommandArgumentType::Integer, Some(0.0), Some(10.0));
+        assert_eq!(
+            integer.validate(&serde_json::json!(0)),
+            Ok(CommandArgumentValue::Integer(0))
+        );
+        assert_eq!(
+            integer.validate(&serde_json::json!(10)),
+            Ok(CommandArgumentValue::Integer(10))
+        );
+        assert!(integer.validate(&serde_json::json!(-1)).is_err());
+        assert!(integer.validate(&serde_json::json!(11)).is_err());
+        assert!(integer.validate(&serde_json::json!(2.5)).is_err());
+        assert!(integer.validate(&serde_json::json!("2")).is_err());
+
+        let float = get_argument_spec("x", CommandArgumentType::Float, Some(-0.5), Some(0.5));
+        assert_eq!(
+            float.validate(&serde_json::json!(0.5)),
+            Ok(CommandArgumentValue::Float(0.5))
+        );
+        assert_eq!(
+            float.validate(&serde_json::json!(0)),
+            Ok(CommandArgumentValue::Float(0.0))
+        );
+        assert!(float.validate(&serde_json::json!(0.51)).is_err());
+        assert!(float.validate(&serde_json::json!(-1)).is_err());
+        assert!(float.validate(&serde_json::json!(true)).is_err());
+
+        let boolean = get_argument_spec("b", CommandArgumentType::Boolean, None, None);
+        assert_eq!(
+            boolean.validate(&serde_json::json!(false)),
+            Ok(CommandArgumentValue::Boolean(false))
+        );
+        assert!(boolean.validate(&serde_json::json!(0)).is_err());
+
+        // Strings are sent as single words, so anything that would split them
+        // or be taken for a checksum is rejected rather than escaped.
+        let string = get_argument_spec("s", CommandArgumentType::String, None, None);
+        assert_eq!(
+            string.validate(&serde_json::json!("SKU-42_b")),
+            Ok(CommandArgumentValue::String(String::from("SKU-42_b")))
+        );
+        for invalid_string in ["", "two words", "tab\there", "line\n", "a*1B2C", "ünï"] {
+            assert!(string.validate(&serde_json::json!(invalid_string)).is_err());
+        }
+        assert!(string.validate(&serde_json::json!(42)).is_err());
+    }
+
+    #[test]
+    fn validates_command_arguments() {
+        let command_spec = CommandSpec {
+            name: String::from("printLabel"),
+            arguments: vec![
+                get_argument_spec("sku", CommandArgumentType::String, None, None),
+                get_argument_spec("copies", CommandArgumentType::Integer, Some(1.0), None),
+            ],
+            actuating: true,
+        };
+        let arguments = |arguments: serde_json::Value| arguments.as_object().unwrap().clone();
+
+        // Values are returned in the order of the spec, not of the request.
+        assert_eq!(
+            command_spec.validate_arguments(&arguments(serde_json::json!({
+                "copies": 2,
+                "sku": "A1"
+            }))),
+            Ok(vec![
+                CommandArgumentValue::String(String::from("A1")),
+                CommandArgumentValue::Integer(2)
+            ])
+        );
+        assert!(command_spec
+            .validate_arguments(&arguments(serde_json::json!({"sku": "A1"})))
+            .unwrap_err()
+            .contains("Missing argument 'copies'"));
+        assert!(command_spec
+            .validate_arguments(&arguments(serde_json::json!({
+                "sku": "A1",
+                "copies": 2,
+                "color": "red"
+            })))
+            .unwrap_err()
+            .contains("Unknown argument 'color'"));
+        assert!(command_spec
+            .validate_arguments(&arguments(serde_json::json!({"sku": "A1", "copies": 0})))
+            .is_err());
+
+        let no_arguments = CommandSpec {
+            name: String::from("stepper0"),
+            arguments: Vec::new(),
+            actuating: true,
+        };
+        assert_eq!(
+            no_arguments.validate_arguments(&CommandArguments::new()),
+            Ok(Vec::new())
+        );
+        assert!(no_arguments
+            .validate_arguments(&arguments(serde_json::json!({"speed": 1})))
+            .is_err());
+    }
+
+    #[test]
+    fn routes_commands_by_namespace() {
+        let manager = CommandExecutorManager::new(
+            vec![
+                get_simulated_executor("a", Duration::ZERO),
+                get_simulated_executor("b", Duration::ZERO),
+            ],
+            Arc::new(EventBus::new()),
         )
+        .unwrap();
+
+        let mut namespaces = manager.get_executor_namespaces();
+        namespaces.sort();
+        assert_eq!(namespaces, vec!["arduino:a", "arduino:b"]);
+        assert_eq!(manager.get_commands(CommandValueType::Null).len(), 4);
+        assert_eq!(
+            manager.get_command_namespace("arduino:b:stepper1", CommandValueType::Null),
+            Some(String::from("arduino:b"))
+        );
+        assert_eq!(
+            manager
+                .execute_command(
+                    "arduino:a:stepper0HasInventory",
+                    CommandValueType::Boolean,
+                    &CommandArguments::new(),
+                    &CallerContext::default()
+                )
+                .unwrap(),
+            CommandValue::Boolean(true)
+        );
+        assert!(matches!(
+            manager.execute_command(
+                "stepper0HasInventory",
+                CommandValueType::Boolean,
+                &CommandArguments::new(),
+                &CallerContext::default()
+            ),
+            Err(CommandExecutorError::UnknownCommand)
+        ));
     }
 
-    fn execute_bool_command(&mut self, command: &str) -> Result<bool, Box<dyn std::error::Error>> {
-        let (namespace, subcommand) =
-            match self.bool_commands_to_namespace_and_subcommand.get(command) {
-                Some((namespace, subcommand)) => (namespace, subcommand),
-                None => return Err(Box::from(String::from("Unknown command"))),
-            };
+    #[test]
+    fn rejects_duplicate_namespaces() {
+        let manager = CommandExecutorManager::new(
+            vec![get_simulated_executor("a", Duration::ZERO)],
+            Arc::new(EventBus::new()),
+        )
+        .unwrap();
+
+        assert!(manager
+            .add_executor(get_simulated_executor("a", Duration::ZERO))
+            .is_err());
+        assert_eq!(manager.get_commands(CommandValueType::Null).len(), 2);
+    }
+
+    #[test]
+    fn removes_executors_and_their_commands() {
+        let manager = CommandExecutorManager::new(
+            vec![
+                get_simulated_executor("a", Duration::ZERO),
+                get_simulated_executor("b", Duration::ZERO),
+            ],
+            Arc::new(EventBus::new()),
+        )
+        .unwrap();
+
+        assert!(manager.remove_executor("arduino:a"));
+        assert!(!manager.remove_executor("arduino:a"));
+        assert_eq!(manager.get_executor_namespaces(), vec!["arduino:b"]);
+        assert!(!manager
+            .get_device_info_by_namespace()
+            .contains_key("arduino:a"));
+        assert!(matches!(
+            manager.execute_command(
+                "arduino:a:stepper0",
+                CommandValueType::Null,
+                &CommandArguments::new(),
+                &CallerContext::default()
+            ),
+            Err(CommandExecutorError::UnknownCommand)
+        ));
+    }
+
+    #[test]
+    fn publishes_executor_and_command_events() {
+        let event_bus = Arc::new(EventBus::new());
+        let manager = CommandExecutorManager::new(
+            vec![get_simulated_executor("a", Duration::ZERO)],
+            event_bus.clone(),
+        )
+        .unwrap();
+        let mut receiver = event_bus.subscribe();
+
+        manager
+            .execute_command(
+                "arduino:a:stepper0HasInventory",
+                CommandValueType::Boolean,
+                &CommandArguments::new(),
+                &CallerContext::default(),
+            )
+            .unwrap();
+        manager.remove_executor("arduino:a");
+
+        let events: Vec<String> = event_bus
+            .get_recent_events(Some("arduino:a"))
+            .into_iter()
+            .map(|device_event| device_event.event)
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                "executorConnected",
+                "commandStarted",
+                "commandFinished",
+                "executorDisconnected"
+            ]
+        );
+        let command_started = receiver.try_recv().unwrap();
+        assert_eq!(command_started.event, "commandStarted");
+        let command_finished = receiver.try_recv().unwrap();
+        assert_eq!(
+            command_finished.data,
+            serde_json::json!({
+                "command": "arduino:a:stepper0HasInventory",
+                "type": "boolean",
+                "result": true
+            })
+        );
+    }
+
+    #[test]
+    fn routes_commands_through_namespace_aliases() {
+        let manager = CommandExecutorManager::new(
+            vec![get_simulated_executor("a", Duration::ZERO)],
+            Arc::new(EventBus::new()),
+        )
+        .unwrap();
+        manager.set_namespace_aliases(HashMap::from([
+            (String::from("left-column"), String::from("arduino:a")),
+            (String::from("right-column"), String::from("arduino:b")),
+        ]));
+
+        let mut null_commands: Vec<String> = manager
+            .get_commands(CommandValueType::Null)
+            .into_iter()
+            .map(|command_spec| command_spec.name)
+            .collect();
+        null_commands.sort();
+        assert_eq!(
+            null_commands,
+            vec![
+                "arduino:a:stepper0",
+                "arduino:a:stepper1",
+                "left-column:stepper0",
+                "left-column:stepper1"
+            ]
+        );
+        assert_eq!(
+            manager.get_command_namespace("left-column:stepper0", CommandValueType::Null),
+            Some(String::from("arduino:a"))
+        );
+        assert_eq!(
+            manager
+                .execute_command(
+                    "left-column:stepper0HasInventory",
+                    CommandValueType::Boolean,
+                    &CommandArguments::new(),
+                    &CallerContext::default()
+                )
+                .unwrap(),
+            CommandValue::Boolean(true)
+        );
+
+        // Aliases start working as soon as their executor is added.
+        assert!(matches!(
+            manager.execute_command(
+                "right-column:stepper0",
+                CommandValueType::Null,
+                &CommandArguments::new(),
+                &CallerContext::default()
+            ),
+            Err(CommandExecutorError::UnknownCommand)
+        ));
+        manager
+            .add_executor(get_simulated_executor("b", Duration::ZERO))
+            .unwrap();
+        assert_eq!(
+            manager.get_command_namespace("right-column:stepper0", CommandValueType::Null),
+            Some(String::from("arduino:b"))
+        );
+    }
+
+    /// Returns a manager with a single simulated executor in the `arduino:a`
+    /// namespace, along with the executor's board.
+    fn get_manager_with_simulated_board() -> (CommandExecutorManager, Arc<Mutex<SimulatedBoard>>) {
+        let port = SimulatedSerialPort::new(SimulatorConfig {
+            vend_duration: Duration::ZERO,
+            homing_timeout: Duration::ZERO,
+            command_duration: Duration::from_millis(1),
+            boot_duration: Duration::ZERO,
+            ..Default::default()
+        });
+        let board = port.get_board();
+        let retry_policy = RetryPolicy {
+            timeout_to_retry: Duration::from_millis(200),
+            ..Default::default()
+        };
+        let executor = LiVeAceSerialPort::new(
+            Box::new(port),
+            Some(String::from("a")),
+            Arc::new(EventBus::new()),
+            retry_policy,
+            retry_policy,
+        )
+        .unwrap();
+        let manager =
+            CommandExecutorManager::new(vec![Box::new(executor)], Arc::new(EventBus::new()))
+                .unwrap();
+        (manager, board)
+    }
+
+    #[test]
+    fn tracks_executor_health() {
+        let (manager, board) = get_manager_with_simulated_board();
+        manager.set_namespace_aliases(HashMap::from([(
+            String::from("right-column"),
+            String::from("arduino:b"),
+        )]));
+        let execute = |command: &str| {
+            manager.execute_command(
+                command,
+                CommandValueType::Null,
+                &CommandArguments::new(),
+                &CallerContext::default(),
+            )
+        };
+
+        board.lock().unwrap().set_homing_switch_broken(0, true);
+        assert!(execute("arduino:a:stepper0").is_err());
+        assert!(execute("arduino:a:stepper0").is_err());
+        // Unknown commands say nothing about the device's health.
+        assert!(execute("arduino:a:stepper9").is_err());
+
+        let executor_statuses = manager.get_executor_statuses();
+        let executor_status = &executor_statuses["arduino:a"];
+        // The board reported the failure itself, so it's still responsive.
+        assert_eq!(executor_status.connection_state, ConnectionState::Connected);
+        assert_eq!(executor_status.consecutive_failures, 2);
+        assert!(executor_status.last_success.is_none());
+        let last_error = executor_status.last_error.as_ref().unwrap();
+        assert_eq!(last_error.command, "arduino:a:stepper0");
+        assert_eq!(last_error.error.as_ref().unwrap()["kind"], "deviceError");
+        assert_eq!(
+            executor_statuses["arduino:b"].connection_state,
+            ConnectionState::Disconnected
+        );
+        assert_eq!(executor_statuses["arduino:b"].aliases, vec!["right-column"]);
+
+        assert!(execute("arduino:a:stepper1").is_ok());
+        let executor_status = &manager.get_executor_statuses()["arduino:a"];
+        assert_eq!(executor_status.consecutive_failures, 0);
+        assert_eq!(
+            executor_status.last_success.as_ref().unwrap().command,
+            "arduino:a:stepper1"
+        );
+        assert!(executor_status.last_error.is_some());
+    }
+
+    #[test]
+    fn exports_command_metrics() {
+        let (manager, board) = get_manager_with_simulated_board();
+
+        board.lock().unwrap().drop_next_responses(1);
+        manager
+            .execute_command(
+                "arduino:a:stepper0HasInventory",
+                CommandValueType::Boolean,
+                &CommandArguments::new(),
+                &CallerContext::default(),
+            )
+            .unwrap();
+
+        let metrics_text = manager.get_metrics_text();
+        for line in [
+            "liveace_commands_total{command=\"stepper0HasInventory\",namespace=\"arduino:a\",result=\"success\"} 1",
+            "liveace_command_duration_seconds_count{command=\"stepper0HasInventory\",namespace=\"arduino:a\"} 1",
+            "liveace_request_retries_total{command=\"stepper0HasInventory\",namespace=\"arduino:a\"} 1",
+            "liveace_request_timeouts_total{command=\"stepper0HasInventory\",namespace=\"arduino:a\"} 1",
+            "liveace_corrupted_frames_total{command=\"stepper0HasInventory\",namespace=\"arduino:a\"} 0",
+            "liveace_connected_executors 1",
+        ] {
+            assert!(
+                metrics_text.lines().any(|metrics_line| metrics_line == line),
+                "Missing '{line}' in:\n{metrics_text}"
+            );
+        }
+    }
+
+    #[test]
+    fn records_commands_to_audit_log() {
+        let (manager, board) = get_manager_with_simulated_board();
+        let audit_log_path = std::env::temp_dir().join(format!(
+            "audit_log_{:016X}.jsonl",
+            std::collections::hash_map::RandomState::new()
+                .build_hasher()
+                .finish()
+        ));
+        let audit_log = Arc::new(AuditLog::open(&audit_log_path).unwrap());
+        manager.set_audit_log(audit_log.clone());
+        let caller = CallerContext {
+            request_id: Some(String::from("request-1")),
+            context: Some(String::from("lnbc1invoice")),
+        };
 
-        match self.command_executors_by_namespace.get_mut(namespace) {
-            Some(ce) => ce.execute_bool_command(subcommand),
-            None => Err(Box::from(String::from("Unknown command"))),
+        board.lock().unwrap().drop_next_responses(1);
+        manager
+            .execute_command(
+                "arduino:a:stepper0HasInventory",
+                CommandValueType::Boolean,
+                &CommandArguments::new(),
+                &caller,
+            )
+            .unwrap();
+        board.lock().unwrap().set_homing_switch_broken(0, true);
+        assert!(manager
+            .execute_command(
+                "arduino:a:stepper0",
+                CommandValueType::Null,
+                &CommandArguments::new(),
+                &caller,
+            )
+            .is_err());
+
+        let result = audit_log
+            .query(&crate::audit::AuditQuery::default())
+            .unwrap();
+        assert!(result.chain_intact);
+        assert_eq!(result.records.len(), 4);
+        assert_eq!(result.records[0]["outcome"], "started");
+        assert_eq!(result.records[0]["context"], "lnbc1invoice");
+        assert_eq!(result.records[1]["namespace"], "arduino:a");
+        assert_eq!(result.records[1]["requestId"], "request-1");
+        assert_eq!(result.records[1]["context"], "lnbc1invoice");
+        assert_eq!(result.records[1]["attempts"], 2);
+        assert_eq!(result.records[1]["outcome"], "success");
+        assert_eq!(result.records[1]["startedSequence"], 0);
+        assert_eq!(result.records[1]["result"], true);
+        assert_eq!(result.records[2]["outcome"], "started");
+        assert_eq!(result.records[3]["outcome"], "deviceError");
+        assert_eq!(result.records[3]["startedSequence"], 2);
+        assert!(result.records[3]["error"]["message"].is_string());
+
+        std::fs::remove_file(&audit_log_path).unwrap();
+    }
+
+    #[test]
+    fn runs_commands_on_different_executors_concurrently() {
+        let vend_duration = Duration::from_millis(300);
+        let manager = Arc::new(
+            CommandExecutorManager::new(
+                vec![
+                    get_simulated_executor("a", vend_duration),
+                    get_simulated_executor("b", vend_duration),
+                ],
+                Arc::new(EventBus::new()),
+            )
+            .unwrap(),
+        );
+
+        let start_time = Instant::now();
+        let handles: Vec<_> = ["arduino:a:stepper0", "arduino:b:stepper0"]
+            .into_iter()
+            .map(|command| {
+                let manager = manager.clone();
+                std::thread::spawn(move || {
+                    manager.execute_command(
+                        command,
+                        CommandValueType::Null,
+                        &CommandArguments::new(),
+                        &CallerContext::default(),
+                    )
+                })
+            })
+            .collect();
+        for handle in handles {
+            assert_eq!(handle.join().unwrap().unwrap(), CommandValue::Null);
         }
+
+        assert!(start_time.elapsed() < vend_duration * 2);
+    }
+
+    #[test]
+    fn reports_executors_busy_with_a_vend() {
+        let vend_duration = MAX_EXECUTOR_WAIT * 2;
+        let manager = Arc::new(
+            CommandExecutorManager::new(
+                vec![get_simulated_executor("a", vend_duration)],
+                Arc::new(EventBus::new()),
+            )
+            .unwrap(),
+        );
+
+        let vending_manager = manager.clone();
+        let vend_handle = std::thread::spawn(move || {
+            vending_manager.execute_command(
+                "arduino:a:stepper0",
+                CommandValueType::Null,
+                &CommandArguments::new(),
+                &CallerContext::default(),
+            )
+        });
+        std::thread::sleep(MAX_EXECUTOR_WAIT / 5);
+
+        let start_time = Instant::now();
+        assert!(matches!(
+            manager.execute_command(
+                "arduino:a:stepper0HasInventory",
+                CommandValueType::Boolean,
+                &CommandArguments::new(),
+                &CallerContext::default(),
+            ),
+            Err(CommandExecutorError::Busy)
+        ));
+        assert!(start_time.elapsed() >= MAX_EXECUTOR_WAIT);
+        assert!(start_time.elapsed() < vend_duration);
+
+        // Jobs wait for the executor instead.
+        assert_eq!(
+            manager
+                .execute_command_with_start_callback(
+                    "arduino:a:stepper0HasInventory",
+                    CommandValueType::Boolean,
+                    &CommandArguments::new(),
+                    &CallerContext::default(),
+                    || {},
+                )
+                .unwrap(),
+            CommandValue::Boolean(true)
+        );
+        assert_eq!(vend_handle.join().unwrap().unwrap(), CommandValue::Null);
+    }
+
+    /// An executor whose only command panics, like one with a bug.
+    struct PanickingExecutor {
+        commands: Vec<CommandSpec>,
+        stats: Arc<ExecutorStats>,
+    }
+
+    impl CommandExecutor for PanickingExecutor {
+        fn get_commands(
+            &self,
+            value_type: CommandValueType,
+        ) -> Box<dyn Iterator<Item = &CommandSpec> + '_> {
+            match value_type {
+                CommandValueType::Null => Box::new(self.commands.iter()),
+                _ => Box::new(std::iter::empty()),
+            }
+        }
+
+        fn execute_command(
+            &mut self,
+            _command: &str,
+            _value_type: CommandValueType,
+            _arguments: &CommandArguments,
+        ) -> Result<CommandValue, CommandExecutorError> {
+            panic!("Simulated executor bug");
+        }
+    }
+
+    impl NamespacedCommandExecutor for PanickingExecutor {
+        fn get_executor_namespace(&self) -> &str {
+            "panicking"
+        }
+
+        fn get_device_info(&self) -> DeviceInfo {
+            DeviceInfo {
+                protocol_version: String::from("1.0"),
+                firmware_name: None,
+                firmware_build: None,
+                board_id: None,
+                capabilities: Vec::new(),
+            }
+        }
+
+        fn get_stats(&self) -> Arc<ExecutorStats> {
+            self.stats.clone()
+        }
+    }
+
+    #[test]
+    fn stops_using_executors_that_panicked() {
+        let manager = Arc::new(
+            CommandExecutorManager::new(
+                vec![Box::new(PanickingExecutor {
+                    commands: vec![CommandSpec {
+                        name: String::from("crash"),
+                        arguments: Vec::new(),
+                        actuating: false,
+                    }],
+                    stats: Arc::new(ExecutorStats::default()),
+                })],
+                Arc::new(EventBus::new()),
+            )
+            .unwrap(),
+        );
+        let execute = |manager: &CommandExecutorManager| {
+            manager.execute_command(
+                "panicking:crash",
+                CommandValueType::Null,
+                &CommandArguments::new(),
+                &CallerContext::default(),
+            )
+        };
+
+        let panicking_manager = manager.clone();
+        assert!(std::thread::spawn(move || execute(&panicking_manager))
+            .join()
+            .is_err());
+        assert!(!manager.is_executor_disconnected("panicking"));
+
+        let start_time = Instant::now();
+        assert!(matches!(
+            execute(&manager),
+            Err(CommandExecutorError::ConnectionError(_))
+        ));
+        assert!(start_time.elapsed() < MAX_EXECUTOR_WAIT);
+        assert!(manager.is_executor_disconnected("panicking"));
     }
 }
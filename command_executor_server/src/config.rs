@@ -0,0 +1,525 @@
+//! Server configuration, read from a TOML file with environment variable
+//! overrides. See `config.example.toml` for every option and its default.
+//!
+//! The file is read from the path in `LIVEACE_CONFIG`, or from
+//! `command_executor_server.toml` in the working directory if that isn't set.
+//! A missing file is fine, since every option has a default. Any option can be
+//! overridden with a `LIVEACE_` environment variable whose name is the option's
+//! path with sections separated by double underscores, such as
+//! `LIVEACE_SERVER__PORT=21001` or
+//! `LIVEACE_DISCOVERY__INCLUDE_PORTS=[/tmp/liveace0]`. `LIVEACE_` variables
+//! that don't name a section, such as those of other tools, are ignored.
+
+use crate::discovery::ArduinoBoardType;
+use rocket::figment::providers::{Env, Format, Serialized, Toml};
+use rocket::figment::Figment;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Duration;
+
+/// The file that configuration is read from when `LIVEACE_CONFIG` isn't set.
+const DEFAULT_CONFIG_PATH: &str = "command_executor_server.toml";
+
+/// The top-level sections of `Config`. Only `LIVEACE_` variables for one of
+/// these are read as overrides, so that variables meant for other tools, such
+/// as `LIVEACE_EXTRA_PORTS`, don't fail validation as unknown options.
+const CONFIG_SECTIONS: [&str; 7] = [
+    "server",
+    "logging",
+    "audit",
+    "discovery",
+    "serial",
+    "devices",
+    "aliases",
+];
+
+#[derive(Clone, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub logging: LoggingConfig,
+    pub audit: AuditConfig,
+    pub discovery: DiscoveryConfig,
+    /// Serial settings for every device, unless overridden in `devices`.
+    pub serial: SerialConfig,
+    /// Serial settings for individual devices, keyed by the device's USB serial
+    /// number or by the path of its port. Unset options fall back to `serial`.
+    pub devices: HashMap<String, DeviceSerialConfig>,
+    /// Logical names for executor namespaces, such as
+    /// `left-column = "arduino:85735313932351F0B1C1"`, so that commands can be
+    /// referred to as `left-column:stepper0` regardless of which board is
+    /// installed.
+    pub aliases: HashMap<String, String>,
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// The IP address that the HTTP server listens on.
+    pub address: String,
+    pub port: u16,
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// Which messages are logged, as a `tracing` filter such as `info` or
+    /// `info,command_executor_server::command_executor=debug`. Rocket's own
+    /// messages are logged under the `rocket` target.
+    pub level: String,
+    pub format: LogFormat,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human-readable lines.
+    Text,
+    /// One JSON object per line, for log collectors.
+    Json,
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditConfig {
+    /// The file that every executed command is appended to, relative to the
+    /// working directory unless absolute. Created if it doesn't exist.
+    pub path: String,
+    /// Whether `/audit` can be queried from other hosts. Records include
+    /// caller context such as invoices, so only local clients can query them
+    /// by default.
+    pub allow_remote_queries: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiscoveryConfig {
+    /// How often serial ports are enumerated to find boards that were plugged
+    /// in or unplugged.
+    pub scan_interval_ms: u64,
+    /// How long the handshake with a newly found port waits for a response
+    /// before resending, which must cover the board's reset when the port is
+    /// opened.
+    pub probe_timeout_ms: u64,
+    /// How many times the handshake is resent before the port is skipped. Kept
+    /// low, since ports that are probed may belong to other devices using the
+    /// same USB-serial chips.
+    pub probe_retries: u32,
+    /// USB devices to probe in addition to the known Arduinos and USB-serial
+    /// chips used by clones. Entries here take precedence over the built-in
+    /// ones.
+    pub usb_devices: Vec<UsbDeviceConfig>,
+    /// If not empty, only USB devices with one of these vendor ids are probed.
+    pub usb_vendor_ids: Vec<u16>,
+    /// If not empty, only USB devices with one of these product ids are
+    /// probed.
+    pub usb_product_ids: Vec<u16>,
+    /// USB serial numbers that are shared by many devices, such as the ones
+    /// burned into counterfeit chips. Boards with one of these, or with no
+    /// serial number at all, are named after the device id stored in their
+    /// firmware instead, which the server assigns if necessary.
+    pub untrusted_serial_numbers: Vec<String>,
+    /// Paths of ports to probe in addition to USB devices, such as emulated
+    /// boards on pseudo-terminals. These are probed whenever they exist,
+    /// regardless of their type.
+    pub include_ports: Vec<String>,
+    /// Paths of ports that are never probed, even if they look like boards.
+    pub exclude_ports: Vec<String>,
+}
+
+/// Identifies a kind of USB device as a board that may run LiVeACE firmware.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UsbDeviceConfig {
+    pub vendor_id: u16,
+    /// If not set, every product of the vendor matches.
+    #[serde(default)]
+    pub product_id: Option<u16>,
+    #[serde(default = "get_unknown_board_type")]
+    pub board_type: ArduinoBoardType,
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SerialConfig {
+    pub baud_rate: u32,
+    /// How long writes to the port may block.
+    pub port_timeout_ms: u64,
+    /// How long to wait for a response before resending a request.
+    pub retry_timeout_ms: u64,
+    /// How many times a request is resent before giving up.
+    pub max_retries: u32,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeviceSerialConfig {
+    pub baud_rate: Option<u32>,
+    pub port_timeout_ms: Option<u64>,
+    pub retry_timeout_ms: Option<u64>,
+    pub max_retries: Option<u32>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            address: String::from("127.0.0.1"),
+            port: 21000,
+        }
+    }
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: String::from("info,rocket=warn"),
+            format: LogFormat::Text,
+        }
+    }
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self {
+            path: String::from("audit_log.jsonl"),
+            allow_remote_queries: false,
+        }
+    }
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            scan_interval_ms: 1000,
+            probe_timeout_ms: 3000,
+            probe_retries: 2,
+            usb_devices: Vec::new(),
+            usb_vendor_ids: Vec::new(),
+            usb_product_ids: Vec::new(),
+            untrusted_serial_numbers: Vec::new(),
+            include_ports: Vec::new(),
+            exclude_ports: Vec::new(),
+        }
+    }
+}
+
+impl Default for SerialConfig {
+    fn default() -> Self {
+        Self {
+            baud_rate: 57600,
+            port_timeout_ms: 1,
+            retry_timeout_ms: 20000,
+            max_retries: 10,
+        }
+    }
+}
+
+impl Config {
+    /// Reads the configuration file and environment variables. Returns a
+    /// description of the problem if the configuration is invalid.
+    pub fn load() -> Result<Self, String> {
+        let config_path =
+            std::env::var("LIVEACE_CONFIG").unwrap_or_else(|_| DEFAULT_CONFIG_PATH.to_string());
+        let mut config = Self::from_figment(
+            Figment::from(Serialized::defaults(Config::default()))
+                .merge(Toml::file(&config_path))
+                .merge(get_env_overrides()),
+        )
+        .map_err(|err| format!("{err} (reading {config_path} and LIVEACE_ variables)"))?;
+
+        // Older deployments list extra ports as comma-separated paths.
+        if let Ok(extra_ports) = std::env::var("LIVEACE_EXTRA_PORTS") {
+            config.discovery.include_ports.extend(
+                extra_ports
+                    .split(',')
+                    .map(|port_path| port_path.trim().to_string())
+                    .filter(|port_path| !port_path.is_empty()),
+            );
+            config.validate()?;
+        }
+
+        Ok(config)
+    }
+
+    fn from_figment(figment: Figment) -> Result<Self, String> {
+        let config: Self = figment.extract().map_err(|err| err.to_string())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        self.server.address.parse::<IpAddr>().map_err(|_| {
+            format!(
+                "server.address '{}' isn't an IP address",
+                self.server.address
+            )
+        })?;
+        if self.server.port == 0 {
+            return Err(String::from("server.port must not be 0"));
+        }
+        tracing_subscriber::EnvFilter::try_new(&self.logging.level)
+            .map_err(|err| format!("logging.level '{}' is invalid: {err}", self.logging.level))?;
+        if self.audit.path.is_empty() {
+            return Err(String::from("audit.path must not be empty"));
+        }
+        if self.discovery.scan_interval_ms == 0 {
+            return Err(String::from("discovery.scan_interval_ms must be positive"));
+        }
+        if self.discovery.probe_timeout_ms == 0 {
+            return Err(String::from("discovery.probe_timeout_ms must be positive"));
+        }
+        if let Some(port_path) = self
+            .discovery
+            .include_ports
+            .iter()
+            .find(|port_path| self.discovery.exclude_ports.contains(port_path))
+        {
+            return Err(format!(
+                "Port '{port_path}' is in both discovery.include_ports and discovery.exclude_ports"
+            ));
+        }
+
+        for (alias, namespace) in &self.aliases {
+            if alias.is_empty()
+                || alias
+                    .chars()
+                    .any(|c| c == ':' || c.is_whitespace() || c.is_control())
+            {
+                return Err(format!(
+                    "Alias '{alias}' must be non-empty and must not contain ':' or whitespace"
+                ));
+            }
+            if namespace.is_empty() {
+                return Err(format!("aliases.{alias} must not be empty"));
+            }
+        }
+
+        validate_serial_config(&self.serial, "serial")?;
+        for device in self.devices.keys() {
+            if device.is_empty() {
+                return Err(String::from("devices must not contain an empty key"));
+            }
+            validate_serial_config(
+                &self.get_serial_config(device, ""),
+                &format!("devices.{device}"),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the serial settings for the device with the given serial number
+    /// on the given port, applying any overrides for it. Overrides keyed by
+    /// serial number take precedence over those keyed by port path, since a
+    /// device can move between ports.
+    pub fn get_serial_config(&self, serial_number: &str, port_path: &str) -> SerialConfig {
+        let mut serial_config = self.serial.clone();
+        for key in [port_path, serial_number] {
+            let Some(device) = self.devices.get(key) else {
+                continue;
+            };
+            serial_config.baud_rate = device.baud_rate.unwrap_or(serial_config.baud_rate);
+            serial_config.port_timeout_ms = device
+                .port_timeout_ms
+                .unwrap_or(serial_config.port_timeout_ms);
+            serial_config.retry_timeout_ms = device
+                .retry_timeout_ms
+                .unwrap_or(serial_config.retry_timeout_ms);
+            serial_config.max_retries = device.max_retries.unwrap_or(serial_config.max_retries);
+        }
+        serial_config
+    }
+}
+
+impl DiscoveryConfig {
+    pub fn get_scan_interval(&self) -> Duration {
+        Duration::from_millis(self.scan_interval_ms)
+    }
+
+    pub fn get_probe_timeout(&self) -> Duration {
+        Duration::from_millis(self.probe_timeout_ms)
+    }
+
+    /// Returns whether a USB device with the given ids passes the vendor and
+    /// product id filters. The device must also be identified as a board to be
+    /// probed.
+    pub fn allows_usb_device(&self, vendor_id: u16, product_id: u16) -> bool {
+        (self.usb_vendor_ids.is_empty() || self.usb_vendor_ids.contains(&vendor_id))
+            && (self.usb_product_ids.is_empty() || self.usb_product_ids.contains(&product_id))
+    }
+}
+
+impl SerialConfig {
+    pub fn get_port_timeout(&self) -> Duration {
+        Duration::from_millis(self.port_timeout_ms)
+    }
+
+    pub fn get_retry_timeout(&self) -> Duration {
+        Duration::from_millis(self.retry_timeout_ms)
+    }
+}
+
+fn get_unknown_board_type() -> ArduinoBoardType {
+    ArduinoBoardType::Unknown
+}
+
+fn validate_serial_config(serial_config: &SerialConfig, section: &str) -> Result<(), String> {
+    if serial_config.baud_rate == 0 {
+        return Err(format!("{section}.baud_rate must be positive"));
+    }
+    if serial_config.port_timeout_ms == 0 {
+        return Err(format!("{section}.port_timeout_ms must be positive"));
+    }
+    if serial_config.retry_timeout_ms == 0 {
+        return Err(format!("{section}.retry_timeout_ms must be positive"));
+    }
+    Ok(())
+}
+
+/// Returns the `LIVEACE_` environment variables that override options.
+fn get_env_overrides() -> Env {
+    // Keys are only lowercased once they've been filtered.
+    Env::prefixed("LIVEACE_").split("__").filter(|key| {
+        key.as_str().split('.').next().is_some_and(|section| {
+            CONFIG_SECTIONS
+                .iter()
+                .any(|config_section| config_section.eq_ignore_ascii_case(section))
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(toml: &str) -> Result<Config, String> {
+        Config::from_figment(
+            Figment::from(Serialized::defaults(Config::default())).merge(Toml::string(toml)),
+        )
+    }
+
+    #[test]
+    fn defaults_match_previous_hardcoded_values() {
+        let config = parse("").unwrap();
+
+        assert_eq!(config, Config::default());
+        assert_eq!(config.server.port, 21000);
+        assert_eq!(config.serial.baud_rate, 57600);
+        assert!(config.discovery.allows_usb_device(0x2341, 0x0042));
+        assert!(config.discovery.allows_usb_device(0x1a86, 0x7523));
+    }
+
+    #[test]
+    fn parses_usb_devices() {
+        let config = parse(
+            r#"
+            [discovery]
+            usb_vendor_ids = [0x2341, 0x1a86]
+
+            [[discovery.usb_devices]]
+            vendor_id = 0x1a86
+            product_id = 0x7522
+            board_type = "ch340Clone"
+
+            [[discovery.usb_devices]]
+            vendor_id = 0x16c0
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.discovery.usb_devices,
+            vec![
+                UsbDeviceConfig {
+                    vendor_id: 0x1a86,
+                    product_id: Some(0x7522),
+                    board_type: ArduinoBoardType::Ch340Clone,
+                },
+                UsbDeviceConfig {
+                    vendor_id: 0x16c0,
+                    product_id: None,
+                    board_type: ArduinoBoardType::Unknown,
+                },
+            ]
+        );
+        assert!(config.discovery.allows_usb_device(0x1a86, 0x7522));
+        assert!(!config.discovery.allows_usb_device(0x0403, 0x6001));
+    }
+
+    #[test]
+    fn example_config_matches_defaults() {
+        assert_eq!(
+            parse(include_str!("../config.example.toml")).unwrap(),
+            Config::default()
+        );
+    }
+
+    #[test]
+    fn applies_device_overrides() {
+        let config = parse(
+            r#"
+            [serial]
+            max_retries = 3
+
+            [devices."/dev/ttyACM0"]
+            baud_rate = 115200
+            retry_timeout_ms = 500
+
+            [devices.ABC123]
+            retry_timeout_ms = 1000
+            "#,
+        )
+        .unwrap();
+
+        let serial_config = config.get_serial_config("ABC123", "/dev/ttyACM0");
+        assert_eq!(serial_config.baud_rate, 115200);
+        assert_eq!(serial_config.retry_timeout_ms, 1000);
+        assert_eq!(serial_config.max_retries, 3);
+        assert_eq!(
+            config.get_serial_config("XYZ", "/dev/ttyACM1"),
+            config.serial
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_config() {
+        assert!(parse("[server]\nport = \"http\"").is_err());
+        assert!(parse("[server]\naddress = \"localhost\"").is_err());
+        assert!(parse("[serial]\nbaud_rate = 0").is_err());
+        assert!(parse("[logging]\nlevel = \"info,=\"").is_err());
+        assert!(parse("[audit]\npath = \"\"").is_err());
+        assert!(parse("[logging]\nformat = \"xml\"").is_err());
+        assert!(parse("[devices.ABC123]\nretry_timeout_ms = 0").is_err());
+        assert!(parse("[discovery]\nusb_vendor_ids = [70000]").is_err());
+        assert!(parse("[[discovery.usb_devices]]\nproduct_id = 0x7523").is_err());
+        assert!(parse("[[discovery.usb_devices]]\nvendor_id = 1\nboard_type = \"mega\"").is_err());
+        assert!(parse("[discovery]\ninclude_ports = [\"/a\"]\nexclude_ports = [\"/a\"]").is_err());
+        // Catches typos, which would otherwise be silently ignored.
+        assert!(parse("[serial]\nbaudrate = 9600").is_err());
+        assert!(parse("[aliases]\n\"left:column\" = \"arduino:a\"").is_err());
+    }
+
+    #[test]
+    fn only_reads_env_overrides_for_config_sections() {
+        let sections = serde_json::to_value(Config::default()).unwrap();
+        let mut section_names: Vec<&str> = sections
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(String::as_str)
+            .collect();
+        section_names.sort();
+        let mut config_sections = CONFIG_SECTIONS.to_vec();
+        config_sections.sort();
+        assert_eq!(section_names, config_sections);
+
+        std::env::set_var("LIVEACE_SERIAL__MAX_RETRIES", "3");
+        std::env::set_var("LIVEACE_EMULATOR_LINK", "/tmp/liveace0");
+        let config = Config::from_figment(
+            Figment::from(Serialized::defaults(Config::default())).merge(get_env_overrides()),
+        );
+        std::env::remove_var("LIVEACE_SERIAL__MAX_RETRIES");
+        std::env::remove_var("LIVEACE_EMULATOR_LINK");
+        assert_eq!(config.unwrap().serial.max_retries, 3);
+    }
+}
@@ -0,0 +1,567 @@
+use crate::command_executor::liveace::{LiVeAceSerialPort, RetryPolicy, SerialError};
+use crate::command_executor::{CommandExecutorManager, NamespacedCommandExecutor};
+use crate::config::{Config, UsbDeviceConfig};
+use crate::events::EventBus;
+use serialport::{SerialPortInfo, SerialPortType};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// What kind of board a USB serial device is, going by its vendor and product
+/// ids. Clones can't be told apart from any other device using the same
+/// USB-serial chip, so they are identified by their chip alone.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ArduinoBoardType {
+    Mega2560,
+    Uno,
+    Leonardo,
+    /// A genuine Arduino whose product id isn't in `KNOWN_USB_DEVICES`.
+    OtherArduino,
+    /// A clone with a WCH CH340 or CH341 USB-serial chip.
+    Ch340Clone,
+    /// A clone with a WCH CH9102 USB-serial chip.
+    Ch9102Clone,
+    /// A clone, or an older board such as the Duemilanove, with an FTDI
+    /// USB-serial chip.
+    FtdiClone,
+    /// A clone with a Silicon Labs CP210x USB-serial chip.
+    Cp210xClone,
+    /// A clone with a Prolific PL2303 USB-serial chip.
+    Pl2303Clone,
+    /// A device added in `discovery.usb_devices` without a board type.
+    Unknown,
+}
+
+/// The USB devices that are probed for LiVeACE boards out of the box. Entries
+/// without a product id match any product of their vendor, but entries with
+/// one take precedence.
+const KNOWN_USB_DEVICES: &[UsbDeviceConfig] = &[
+    usb_device(0x2341, Some(0x0010), ArduinoBoardType::Mega2560),
+    usb_device(0x2341, Some(0x0042), ArduinoBoardType::Mega2560),
+    usb_device(0x2341, Some(0x0001), ArduinoBoardType::Uno),
+    usb_device(0x2341, Some(0x0043), ArduinoBoardType::Uno),
+    usb_device(0x2341, Some(0x0036), ArduinoBoardType::Leonardo),
+    usb_device(0x2341, Some(0x8036), ArduinoBoardType::Leonardo),
+    usb_device(0x2341, None, ArduinoBoardType::OtherArduino),
+    // Boards made by arduino.org before it merged back into Arduino.
+    usb_device(0x2a03, Some(0x0042), ArduinoBoardType::Mega2560),
+    usb_device(0x2a03, Some(0x0043), ArduinoBoardType::Uno),
+    usb_device(0x2a03, None, ArduinoBoardType::OtherArduino),
+    usb_device(0x1a86, Some(0x7523), ArduinoBoardType::Ch340Clone),
+    usb_device(0x1a86, Some(0x5523), ArduinoBoardType::Ch340Clone),
+    usb_device(0x1a86, Some(0x55d4), ArduinoBoardType::Ch9102Clone),
+    usb_device(0x0403, Some(0x6001), ArduinoBoardType::FtdiClone),
+    usb_device(0x0403, Some(0x6015), ArduinoBoardType::FtdiClone),
+    usb_device(0x10c4, Some(0xea60), ArduinoBoardType::Cp210xClone),
+    usb_device(0x067b, Some(0x2303), ArduinoBoardType::Pl2303Clone),
+];
+
+const fn usb_device(
+    vendor_id: u16,
+    product_id: Option<u16>,
+    board_type: ArduinoBoardType,
+) -> UsbDeviceConfig {
+    UsbDeviceConfig {
+        vendor_id,
+        product_id,
+        board_type,
+    }
+}
+
+/// How long to wait before probing a port again after its first failed probe.
+/// The delay doubles with every further failure.
+const PROBE_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// How many times in a row a port may fail its probe before it's left alone
+/// until it's unplugged. Opening a port resets whatever is behind it, so
+/// devices that aren't LiVeACE boards aren't probed forever.
+const MAX_FAILED_PROBES: u32 = 6;
+
+/// What the watcher made of a serial port.
+#[derive(Clone, Debug, PartialEq, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PortStatus {
+    /// The port's USB vendor and product ids, such as `2341:0042`, if it's a
+    /// USB device.
+    pub usb_id: Option<String>,
+    /// What kind of board the port's USB ids identify, if any.
+    pub board_type: Option<ArduinoBoardType>,
+    /// The namespace of the executor running on the port, if it's a LiVeACE
+    /// board.
+    pub namespace: Option<String>,
+    /// Why no executor is running on the port, if none is.
+    pub skipped_reason: Option<String>,
+    /// Whether the port is being probed right now.
+    pub probing: bool,
+    /// How many times in a row probing the port has failed.
+    pub failed_probes: u32,
+    /// When the current probe started, which tells its result apart from the
+    /// results of probes of a port that was unplugged in the meantime.
+    #[serde(skip)]
+    probe_started_at: Option<Instant>,
+    /// When the port is probed again, if its last probe failed in a way that
+    /// may not last, such as a board that was still booting.
+    #[serde(skip)]
+    next_probe_at: Option<Instant>,
+}
+
+/// The status of every port seen during the last scan, keyed by port name.
+/// Shared between the watcher's thread and the HTTP server.
+#[derive(Default)]
+pub struct PortStatuses {
+    ports: Mutex<BTreeMap<String, PortStatus>>,
+}
+
+impl PortStatuses {
+    pub fn get_snapshot(&self) -> BTreeMap<String, PortStatus> {
+        self.ports.lock().unwrap().clone()
+    }
+
+    /// Returns the name and status of the port that each connected executor
+    /// runs on, keyed by executor namespace.
+    pub fn get_ports_by_namespace(&self) -> HashMap<String, (String, PortStatus)> {
+        self.ports
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|(port_name, port_status)| {
+                let namespace = port_status.namespace.clone()?;
+                Some((namespace, (port_name.clone(), port_status.clone())))
+            })
+            .collect()
+    }
+}
+
+/// Keeps a `CommandExecutorManager` in sync with the LiVeACE boards that are
+/// currently plugged in by periodically enumerating serial ports.
+#[derive(Clone)]
+pub struct PortWatcher {
+    command_executor_manager: Arc<CommandExecutorManager>,
+    /// Receives the events pushed by every board that the watcher connects to.
+    event_bus: Arc<EventBus>,
+    /// Every port seen during the last scan. Ports are probed when they first
+    /// appear, and again after a failed probe or a lost connection, but not
+    /// otherwise, since opening a port resets the Arduino behind it.
+    known_ports: Arc<PortStatuses>,
+    /// Decides which ports are probed and how boards are talked to.
+    config: Arc<Config>,
+}
+
+impl PortWatcher {
+    pub fn new(
+        command_executor_manager: Arc<CommandExecutorManager>,
+        event_bus: Arc<EventBus>,
+        config: Arc<Config>,
+    ) -> Self {
+        Self {
+            command_executor_manager,
+            event_bus,
+            known_ports: Arc::new(PortStatuses::default()),
+            config,
+        }
+    }
+
+    /// Returns the statuses of the ports found by this watcher, which stay up
+    /// to date after the watcher is spawned.
+    pub fn get_port_statuses(&self) -> Arc<PortStatuses> {
+        self.known_ports.clone()
+    }
+
+    /// Enumerates serial ports once. Unregisters executors whose ports have
+    /// disappeared or whose connections were lost, and starts probing new
+    /// ports and ports whose previous probe failed. Probes run on their own
+    /// threads, so a slow device doesn't hold up later scans.
+    pub fn scan(&self) {
+        let mut serial_ports = match serialport::available_ports() {
+            Ok(serial_ports) => serial_ports,
+            Err(err) => {
+                tracing::error!(error = %err, "Unable to enumerate serial ports");
+                return;
+            }
+        };
+        for extra_port_path in &self.config.discovery.include_ports {
+            if std::path::Path::new(extra_port_path).exists()
+                && !serial_ports
+                    .iter()
+                    .any(|serial_port| &serial_port.port_name == extra_port_path)
+            {
+                serial_ports.push(SerialPortInfo {
+                    port_name: extra_port_path.clone(),
+                    port_type: SerialPortType::Unknown,
+                });
+            }
+        }
+
+        let current_port_names: HashSet<&str> = serial_ports
+            .iter()
+            .map(|serial_port| serial_port.port_name.as_str())
+            .collect();
+        let mut known_ports = self.known_ports.ports.lock().unwrap();
+        let removed_port_names: Vec<String> = known_ports
+            .keys()
+            .filter(|port_name| !current_port_names.contains(port_name.as_str()))
+            .cloned()
+            .collect();
+        for port_name in removed_port_names {
+            let Some(port_status) = known_ports.remove(&port_name) else {
+                continue;
+            };
+            if let Some(namespace) = port_status.namespace {
+                self.command_executor_manager.remove_executor(&namespace);
+                tracing::info!(
+                    namespace = %namespace,
+                    port = %port_name,
+                    "Removed LiVeACE Arduino (port disconnected)"
+                );
+            }
+        }
+
+        // A port whose reader died, such as an emulator's pseudo-terminal that
+        // was recreated, is probed again as if it had just been plugged in.
+        for (port_name, port_status) in known_ports.iter_mut() {
+            let Some(namespace) = port_status.namespace.clone() else {
+                continue;
+            };
+            if self
+                .command_executor_manager
+                .is_executor_disconnected(&namespace)
+            {
+                self.command_executor_manager.remove_executor(&namespace);
+                tracing::warn!(
+                    namespace = %namespace,
+                    port = %port_name,
+                    "Removed LiVeACE Arduino (connection lost)"
+                );
+                port_status.namespace = None;
+                port_status.skipped_reason = Some(String::from("Connection lost"));
+                port_status.next_probe_at = Some(Instant::now());
+            }
+        }
+
+        let now = Instant::now();
+        for serial_port in serial_ports {
+            let port_status = match known_ports.get(&serial_port.port_name) {
+                None => {
+                    tracing::debug!(
+                        port = %serial_port.port_name,
+                        port_type = ?serial_port.port_type,
+                        "Discovered serial port"
+                    );
+                    get_port_status(&serial_port, &self.config)
+                }
+                Some(port_status)
+                    if !port_status.probing
+                        && port_status
+                            .next_probe_at
+                            .is_some_and(|next_probe_at| next_probe_at <= now) =>
+                {
+                    port_status.clone()
+                }
+                Some(_) => continue,
+            };
+            let port_name = serial_port.port_name.clone();
+            let port_status = self.start_probe(serial_port, port_status);
+            known_ports.insert(port_name, port_status);
+        }
+    }
+
+    /// Starts probing a port on a new thread if it may be a LiVeACE board, and
+    /// returns its updated status.
+    fn start_probe(&self, serial_port: SerialPortInfo, mut port_status: PortStatus) -> PortStatus {
+        let board_serial_number =
+            match check_port(&serial_port, port_status.board_type, &self.config) {
+                Ok(board_serial_number) => board_serial_number,
+                Err(reason) => {
+                    tracing::info!(port = %serial_port.port_name, reason = %reason, "Skipped port");
+                    port_status.skipped_reason = Some(reason);
+                    port_status.next_probe_at = None;
+                    return port_status;
+                }
+            };
+
+        let probe_started_at = Instant::now();
+        port_status.probing = true;
+        port_status.probe_started_at = Some(probe_started_at);
+        port_status.next_probe_at = None;
+
+        let port_watcher = self.clone();
+        std::thread::spawn(move || {
+            let port_name = serial_port.port_name;
+            let _span = tracing::info_span!("probe", port = %port_name).entered();
+            let port_or = get_liveace_serial_port(
+                &port_name,
+                board_serial_number,
+                &port_watcher.config,
+                port_watcher.event_bus.clone(),
+            );
+            port_watcher.finish_probe(&port_name, probe_started_at, port_or);
+        });
+        port_status
+    }
+
+    /// Registers the executor for a probed port, or schedules the port to be
+    /// probed again if that failed.
+    fn finish_probe(
+        &self,
+        port_name: &str,
+        probe_started_at: Instant,
+        port_or: Result<LiVeAceSerialPort, String>,
+    ) {
+        let mut known_ports = self.known_ports.ports.lock().unwrap();
+        let Some(port_status) = known_ports
+            .get_mut(port_name)
+            .filter(|port_status| port_status.probe_started_at == Some(probe_started_at))
+        else {
+            tracing::info!(port = %port_name, "Discarded probe of a port that was unplugged");
+            return;
+        };
+        port_status.probing = false;
+        port_status.probe_started_at = None;
+
+        let namespace_or = port_or.and_then(|port| {
+            let namespace = port.get_executor_namespace().to_string();
+            self.command_executor_manager
+                .add_executor(Box::from(port))
+                .map(|()| namespace)
+                .map_err(|err| format!("Unable to add executor: {err}"))
+        });
+        match namespace_or {
+            Ok(namespace) => {
+                tracing::info!(
+                    namespace = %namespace,
+                    port = %port_name,
+                    "Added LiVeACE Arduino"
+                );
+                port_status.namespace = Some(namespace);
+                port_status.skipped_reason = None;
+                port_status.failed_probes = 0;
+            }
+            Err(reason) => {
+                port_status.failed_probes += 1;
+                port_status.next_probe_at = (port_status.failed_probes < MAX_FAILED_PROBES)
+                    .then(|| Instant::now() + get_probe_retry_delay(port_status.failed_probes));
+                tracing::info!(
+                    port = %port_name,
+                    reason = %reason,
+                    failed_probes = port_status.failed_probes,
+                    will_retry = port_status.next_probe_at.is_some(),
+                    "Skipped port"
+                );
+                port_status.skipped_reason = Some(reason);
+            }
+        }
+        drop(known_ports);
+        tracing::info!(
+            count = self
+                .command_executor_manager
+                .get_executor_namespaces()
+                .len(),
+            "Connected LiVeACE Arduinos"
+        );
+    }
+
+    /// Moves the watcher onto a background thread that scans serial ports
+    /// right away, and then rescans them for the lifetime of the process.
+    pub fn spawn(self) -> std::thread::JoinHandle<()> {
+        std::thread::spawn(move || loop {
+            self.scan();
+            std::thread::sleep(self.config.discovery.get_scan_interval());
+        })
+    }
+}
+
+/// Returns how long to wait before probing a port again after it failed
+/// `failed_probes` probes in a row.
+fn get_probe_retry_delay(failed_probes: u32) -> Duration {
+    PROBE_RETRY_DELAY * 2u32.saturating_pow(failed_probes.saturating_sub(1))
+}
+
+/// Returns the status of a port that hasn't been probed yet.
+fn get_port_status(serial_port_info: &SerialPortInfo, config: &Config) -> PortStatus {
+    let usb_ids = match &serial_port_info.port_type {
+        SerialPortType::UsbPort(usb_port_info) => Some((usb_port_info.vid, usb_port_info.pid)),
+        _ => None,
+    };
+    PortStatus {
+        usb_id: usb_ids.map(|(vendor_id, product_id)| format!("{vendor_id:04x}:{product_id:04x}")),
+        board_type: usb_ids
+            .and_then(|(vendor_id, product_id)| get_board_type(vendor_id, product_id, config)),
+        namespace: None,
+        skipped_reason: None,
+        probing: false,
+        failed_probes: 0,
+        probe_started_at: None,
+        next_probe_at: None,
+    }
+}
+
+/// Identifies a USB device by its ids, using `discovery.usb_devices` and then
+/// `KNOWN_USB_DEVICES`. Returns `None` if the device isn't a known board or
+/// USB-serial chip.
+pub fn get_board_type(
+    vendor_id: u16,
+    product_id: u16,
+    config: &Config,
+) -> Option<ArduinoBoardType> {
+    let usb_devices = || {
+        config
+            .discovery
+            .usb_devices
+            .iter()
+            .chain(KNOWN_USB_DEVICES)
+            .filter(|usb_device| usb_device.vendor_id == vendor_id)
+    };
+    usb_devices()
+        .find(|usb_device| usb_device.product_id == Some(product_id))
+        .or_else(|| usb_devices().find(|usb_device| usb_device.product_id.is_none()))
+        .map(|usb_device| usb_device.board_type)
+}
+
+/// Decides whether the given port may be a LiVeACE board, returning the
+/// board's trusted USB serial number if it has one, or why the port is skipped
+/// for good otherwise. Included ports are assumed to be LiVeACE boards, so
+/// they are probed even if they aren't known USB devices.
+fn check_port(
+    serial_port_info: &SerialPortInfo,
+    board_type: Option<ArduinoBoardType>,
+    config: &Config,
+) -> Result<Option<String>, String> {
+    let port_name = &serial_port_info.port_name;
+    if config.discovery.exclude_ports.contains(port_name) {
+        return Err(String::from("Listed in discovery.exclude_ports"));
+    }
+    if config.discovery.include_ports.contains(port_name) {
+        return Ok(Some(get_extra_port_serial_number(port_name)));
+    }
+
+    let usb_port_info = match &serial_port_info.port_type {
+        SerialPortType::UsbPort(usb_port_info) => usb_port_info,
+        _ => return Err(String::from("Not a USB device")),
+    };
+    let usb_id = format!("{:04x}:{:04x}", usb_port_info.vid, usb_port_info.pid);
+
+    if board_type.is_none() {
+        return Err(format!(
+            "Unrecognized USB device {usb_id}, which can be added to discovery.usb_devices"
+        ));
+    }
+    if !config
+        .discovery
+        .allows_usb_device(usb_port_info.vid, usb_port_info.pid)
+    {
+        return Err(format!(
+            "USB device {usb_id} isn't allowed by discovery.usb_vendor_ids and discovery.usb_product_ids"
+        ));
+    }
+
+    Ok(usb_port_info.serial_number.clone().filter(|serial_number| {
+        !config
+            .discovery
+            .untrusted_serial_numbers
+            .contains(serial_number)
+    }))
+}
+
+/// Opens the given port and connects to the LiVeACE board behind it. Returns
+/// why that failed otherwise. Boards whose USB serial number is missing or
+/// untrusted are named after the device id stored in their firmware instead.
+fn get_liveace_serial_port(
+    port_name: &str,
+    board_serial_number: Option<String>,
+    config: &Config,
+    event_bus: Arc<EventBus>,
+) -> Result<LiVeAceSerialPort, String> {
+    let serial_config =
+        config.get_serial_config(board_serial_number.as_deref().unwrap_or(""), port_name);
+    let port_builder = serialport::new(port_name, serial_config.baud_rate)
+        .timeout(serial_config.get_port_timeout())
+        .data_bits(serialport::DataBits::Eight);
+
+    let port = port_builder
+        .open()
+        .map_err(|err| format!("Unable to open port: {err}"))?;
+
+    LiVeAceSerialPort::new(
+        port,
+        board_serial_number,
+        event_bus,
+        RetryPolicy {
+            timeout_to_retry: serial_config.get_retry_timeout(),
+            max_retries: serial_config.max_retries,
+        },
+        RetryPolicy {
+            timeout_to_retry: config.discovery.get_probe_timeout(),
+            max_retries: config.discovery.probe_retries,
+        },
+    )
+    .map_err(|err| match err {
+        SerialError::NoDeviceId => {
+            String::from("No trusted USB serial number, and the firmware can't store a device id")
+        }
+        err => format!("No LiVeACE handshake: {err}"),
+    })
+}
+
+/// Derives a stand-in serial number from the path of an extra port, since
+/// ports that aren't USB devices don't have one. Characters that can't appear
+/// in command names are replaced, so `/tmp/liveace0` becomes `tmp_liveace0`.
+fn get_extra_port_serial_number(port_path: &str) -> String {
+    port_path
+        .trim_start_matches('/')
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identifies_clone_boards() {
+        let mut config = Config::default();
+        assert_eq!(
+            get_board_type(0x2341, 0x0042, &config),
+            Some(ArduinoBoardType::Mega2560)
+        );
+        assert_eq!(
+            get_board_type(0x2341, 0x1234, &config),
+            Some(ArduinoBoardType::OtherArduino)
+        );
+        assert_eq!(
+            get_board_type(0x1a86, 0x7523, &config),
+            Some(ArduinoBoardType::Ch340Clone)
+        );
+        assert_eq!(get_board_type(0x1a86, 0x1234, &config), None);
+        assert_eq!(get_board_type(0x046d, 0xc52b, &config), None);
+
+        // Configured entries extend and take precedence over built-in ones.
+        config.discovery.usb_devices = vec![
+            usb_device(0x1a86, None, ArduinoBoardType::Unknown),
+            usb_device(0x0403, Some(0x6001), ArduinoBoardType::Unknown),
+        ];
+        assert_eq!(
+            get_board_type(0x1a86, 0x1234, &config),
+            Some(ArduinoBoardType::Unknown)
+        );
+        assert_eq!(
+            get_board_type(0x1a86, 0x7523, &config),
+            Some(ArduinoBoardType::Ch340Clone)
+        );
+        assert_eq!(
+            get_board_type(0x0403, 0x6001, &config),
+            Some(ArduinoBoardType::Unknown)
+        );
+    }
+
+    #[test]
+    fn backs_off_between_failed_probes() {
+        assert_eq!(get_probe_retry_delay(1), PROBE_RETRY_DELAY);
+        assert_eq!(get_probe_retry_delay(2), PROBE_RETRY_DELAY * 2);
+        assert_eq!(
+            get_probe_retry_delay(MAX_FAILED_PROBES - 1),
+            PROBE_RETRY_DELAY * 16
+        );
+    }
+}
@@ -0,0 +1,93 @@
+use crate::util::get_unix_timestamp_millis;
+use rocket::tokio::sync::broadcast;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+/// The number of events that are kept for `/events`. Older events are
+/// discarded.
+const RECENT_EVENT_COUNT: usize = 256;
+
+/// The number of events that a stream subscriber can fall behind by before it
+/// starts missing events.
+const SUBSCRIBER_CAPACITY: usize = 256;
+
+/// Something that happened to a device, such as an inventory sensor changing,
+/// an executor connecting or a command finishing. Events pushed by firmware
+/// and events raised by the server itself share the same shape.
+#[derive(Clone, Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceEvent {
+    /// Increases by one with every event published since the server started,
+    /// so that clients can tell whether they missed any.
+    pub id: u64,
+    /// The namespace of the executor that the event is about.
+    pub namespace: String,
+    /// The kind of event, such as `inventoryChanged`.
+    pub event: String,
+    /// Event-specific details, or `null` if the event has none.
+    pub data: serde_json::Value,
+    /// Milliseconds since the Unix epoch.
+    pub timestamp: u64,
+}
+
+/// Collects events from every connected device, keyed by the namespace of the
+/// executor that they are about, and fans them out to stream subscribers.
+pub struct EventBus {
+    recent_events: Mutex<VecDeque<DeviceEvent>>,
+    next_event_id: Mutex<u64>,
+    sender: broadcast::Sender<DeviceEvent>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        Self {
+            recent_events: Mutex::new(VecDeque::new()),
+            next_event_id: Mutex::new(0),
+            sender: broadcast::channel(SUBSCRIBER_CAPACITY).0,
+        }
+    }
+
+    pub fn publish(&self, namespace: &str, event: &str, data: serde_json::Value) {
+        // Held until the event is sent, so that subscribers receive events in
+        // id order.
+        let mut next_event_id = self.next_event_id.lock().unwrap();
+        let device_event = DeviceEvent {
+            id: *next_event_id,
+            namespace: namespace.to_string(),
+            event: event.to_string(),
+            data,
+            timestamp: get_unix_timestamp_millis(),
+        };
+        *next_event_id += 1;
+
+        {
+            let mut recent_events = self.recent_events.lock().unwrap();
+            if recent_events.len() == RECENT_EVENT_COUNT {
+                recent_events.pop_front();
+            }
+            recent_events.push_back(device_event.clone());
+        }
+
+        // Only fails if nobody is subscribed.
+        let _ = self.sender.send(device_event);
+    }
+
+    /// Returns a receiver for every event published from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<DeviceEvent> {
+        self.sender.subscribe()
+    }
+
+    /// Returns the most recent events, oldest first, optionally only those
+    /// published under the given namespace.
+    pub fn get_recent_events(&self, namespace: Option<&str>) -> Vec<DeviceEvent> {
+        self.recent_events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|device_event| {
+                namespace.is_none_or(|namespace| device_event.namespace == namespace)
+            })
+            .cloned()
+            .collect()
+    }
+}
@@ -0,0 +1,192 @@
+use crate::audit::CallerContext;
+use crate::command_executor::{
+    CommandArguments, CommandExecutorError, CommandExecutorManager, CommandValue, CommandValueType,
+};
+use crate::util::get_unix_timestamp_millis;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long finished jobs can still be queried for.
+const JOB_RETENTION: Duration = Duration::from_secs(60 * 60);
+
+/// The maximum number of jobs that can be queued or running at once. Further
+/// submissions are rejected until some of them finish.
+const MAX_UNFINISHED_JOBS: usize = 32;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum JobState {
+    /// Waiting for the executor to become available.
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+/// A command that is executed in the background, so that callers don't have
+/// to hold a request open while a slow command (such as a vend) runs.
+#[derive(Clone, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Job {
+    id: String,
+    command: String,
+    #[serde(rename = "type")]
+    value_type: CommandValueType,
+    namespace: String,
+    state: JobState,
+    /// Timestamps in milliseconds since the Unix epoch.
+    submitted_at: u64,
+    started_at: Option<u64>,
+    finished_at: Option<u64>,
+    /// The command's return value. Only set once the job has succeeded.
+    result: Option<CommandValue>,
+    /// The error that the command failed with. Only set once the job has
+    /// failed.
+    error: Option<serde_json::Value>,
+    #[serde(skip)]
+    finished_instant: Option<Instant>,
+}
+
+pub struct JobManager {
+    command_executor_manager: Arc<CommandExecutorManager>,
+    jobs: Mutex<HashMap<String, Job>>,
+    /// Random prefix for job ids, so that ids from a previous server run can't
+    /// be confused with new ones.
+    job_id_prefix: u32,
+    next_job_id: Mutex<u64>,
+}
+
+impl JobManager {
+    pub fn new(command_executor_manager: Arc<CommandExecutorManager>) -> Self {
+        Self {
+            command_executor_manager,
+            jobs: Mutex::new(HashMap::new()),
+            job_id_prefix: RandomState::new().build_hasher().finish() as u32,
+            next_job_id: Mutex::new(0),
+        }
+    }
+
+    /// Queues a command for execution on a background thread and returns the
+    /// new job. Fails immediately if the command doesn't exist or too many jobs
+    /// are already pending. `caller` is recorded to the audit log along with
+    /// the command.
+    pub fn submit(
+        self: &Arc<Self>,
+        command: String,
+        value_type: CommandValueType,
+        arguments: CommandArguments,
+        caller: CallerContext,
+    ) -> Result<Job, (CommandExecutorError, Option<String>)> {
+        let namespace = match self
+            .command_executor_manager
+            .get_command_namespace(&command, value_type)
+        {
+            Some(namespace) => namespace,
+            None => return Err((CommandExecutorError::UnknownCommand, None)),
+        };
+
+        let job = {
+            let mut jobs = self.jobs.lock().unwrap();
+            Self::remove_expired_jobs(&mut jobs);
+
+            let unfinished_job_count = jobs
+                .values()
+                .filter(|job| matches!(job.state, JobState::Queued | JobState::Running))
+                .count();
+            if unfinished_job_count >= MAX_UNFINISHED_JOBS {
+                return Err((CommandExecutorError::Busy, Some(namespace)));
+            }
+
+            let job = Job {
+                id: self.get_next_job_id(),
+                command,
+                value_type,
+                namespace,
+                state: JobState::Queued,
+                submitted_at: get_unix_timestamp_millis(),
+                started_at: None,
+                finished_at: None,
+                result: None,
+                error: None,
+                finished_instant: None,
+            };
+            jobs.insert(job.id.clone(), job.clone());
+            job
+        };
+
+        let job_manager = self.clone();
+        let job_id = job.id.clone();
+        let command = job.command.clone();
+        let span = tracing::info_span!(parent: tracing::Span::current(), "job", job_id = %job_id);
+        std::thread::spawn(move || {
+            let _span = span.enter();
+            job_manager.run_job(&job_id, &command, value_type, &arguments, &caller)
+        });
+
+        Ok(job)
+    }
+
+    /// Returns the job with the given id, or `None` if it doesn't exist or
+    /// finished longer ago than the retention window.
+    pub fn get_job(&self, job_id: &str) -> Option<Job> {
+        let mut jobs = self.jobs.lock().unwrap();
+        Self::remove_expired_jobs(&mut jobs);
+        jobs.get(job_id).cloned()
+    }
+
+    fn run_job(
+        &self,
+        job_id: &str,
+        command: &str,
+        value_type: CommandValueType,
+        arguments: &CommandArguments,
+        caller: &CallerContext,
+    ) {
+        let res = self
+            .command_executor_manager
+            .execute_command_with_start_callback(command, value_type, arguments, caller, || {
+                self.update_job(job_id, |job| {
+                    job.state = JobState::Running;
+                    job.started_at = Some(get_unix_timestamp_millis());
+                })
+            });
+
+        self.update_job(job_id, |job| {
+            match res {
+                Ok(value) => {
+                    job.state = JobState::Succeeded;
+                    job.result = Some(value);
+                }
+                Err(err) => {
+                    job.state = JobState::Failed;
+                    job.error = Some(err.to_json(Some(&job.namespace)));
+                }
+            }
+            job.finished_at = Some(get_unix_timestamp_millis());
+            job.finished_instant = Some(Instant::now());
+        });
+    }
+
+    fn update_job(&self, job_id: &str, update_fn: impl FnOnce(&mut Job)) {
+        if let Some(job) = self.jobs.lock().unwrap().get_mut(job_id) {
+            update_fn(job);
+        }
+    }
+
+    fn get_next_job_id(&self) -> String {
+        let mut next_job_id = self.next_job_id.lock().unwrap();
+        let job_id = format!("{:08x}{:x}", self.job_id_prefix, *next_job_id);
+        *next_job_id += 1;
+        job_id
+    }
+
+    fn remove_expired_jobs(jobs: &mut HashMap<String, Job>) {
+        jobs.retain(|_, job| {
+            job.finished_instant
+                .is_none_or(|finished_instant| finished_instant.elapsed() < JOB_RETENTION)
+        });
+    }
+}
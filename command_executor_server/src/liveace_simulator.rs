@@ -0,0 +1,1024 @@
+//! A simulated LiVeACE board that emulates `liveace_mega_sticker_machine.ino`,
+//! so that the server can be exercised without any hardware attached. Used by
+//! the server's tests and by the `liveace_emulator` binary.
+
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long the emulated firmware waits for the rest of a line before handling
+/// what it has received so far, like `Serial.setTimeout` in the firmware.
+const LINE_READ_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// The number of idempotency keys that the emulated firmware remembers.
+const REMEMBERED_IDEMPOTENCY_KEY_COUNT: usize = 8;
+
+/// The longest device id that the emulated firmware stores.
+const MAX_DEVICE_ID_LENGTH: usize = 32;
+
+/// Configures how a simulated board behaves.
+#[derive(Clone, Debug)]
+pub struct SimulatorConfig {
+    /// How long a successful vend takes.
+    pub vend_duration: Duration,
+    /// How long a vend takes to fail when a stepper's homing switch is broken.
+    pub homing_timeout: Duration,
+    /// How long every other command takes to be answered.
+    pub command_duration: Duration,
+    /// How long the board ignores input after being reset, such as when its
+    /// port is opened.
+    pub boot_duration: Duration,
+    /// The number of items loaded into each stepper. The board has one
+    /// stepper per entry, each with its own `stepper{n}`,
+    /// `stepper{n}HasInventory` and `stepper{n}OutOfInventory` commands.
+    pub initial_inventory: Vec<u32>,
+    /// The chance that any given byte sent to the board is lost, from 0 to 1,
+    /// such as when the board's receive buffer overflows.
+    pub byte_drop_rate: f64,
+    /// The chance that any given byte sent by the board is garbled on its way
+    /// to the host, from 0 to 1, such as on a noisy cable.
+    pub byte_corruption_rate: f64,
+    /// Seed for deciding which bytes are dropped or garbled, so that runs are
+    /// repeatable.
+    pub seed: u64,
+    /// The size of the board's serial receive buffer. Bytes that arrive while
+    /// the board is busy running a command are lost once the buffer is full.
+    pub rx_buffer_size: usize,
+    /// The shortest gap between bytes that the board can keep up with. Bytes
+    /// that arrive sooner after the previous one are lost, like on firmware
+    /// that is too slow to drain its receive buffer.
+    pub min_byte_interval: Duration,
+    /// Whether the firmware answers `hello`. Firmware that predates the
+    /// handshake doesn't.
+    pub supports_handshake: bool,
+    /// Whether the firmware supports checksummed frames.
+    pub supports_checksums: bool,
+    /// Whether the firmware can push events, such as inventory sensor
+    /// changes.
+    pub supports_events: bool,
+    /// Whether the firmware speaks LiVeACE 1.1 (request ids and idempotency
+    /// keys) or only LiVeACE 1.0.
+    pub supports_request_ids: bool,
+    /// Whether the firmware can store a device id in EEPROM.
+    pub supports_device_id: bool,
+    /// Whether the firmware reports a boot id that changes on every reset.
+    /// Firmware that predates boot ids doesn't.
+    pub supports_boot_id: bool,
+    /// The device id stored in EEPROM when the board is created, or `None` if
+    /// the board hasn't been provisioned.
+    pub device_id: Option<String>,
+    /// Commands that the board supports in addition to its stepper commands.
+    pub extra_commands: Vec<SimulatedCommand>,
+}
+
+/// A command that a simulated board supports in addition to its stepper
+/// commands, such as reading a sensor.
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SimulatedCommand {
+    pub name: String,
+    /// The type of value that `listCommands` reports the command as returning,
+    /// such as `integer` or `string`.
+    #[serde(rename = "type")]
+    pub value_type: String,
+    /// The arguments that `listCommands` reports the command as taking. The
+    /// board only checks that it receives the right number of them.
+    #[serde(default)]
+    pub args: Vec<SimulatedArgument>,
+    /// The raw JSON that the command responds with, in which `{name}` is
+    /// replaced by the argument called `name` as it was received. It isn't
+    /// checked against `value_type`, so that firmware bugs can be simulated
+    /// too.
+    pub response: String,
+}
+
+/// An argument of a `SimulatedCommand`.
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SimulatedArgument {
+    pub name: String,
+    /// `integer`, `float`, `boolean` or `string`.
+    #[serde(rename = "type")]
+    pub argument_type: String,
+    #[serde(default)]
+    pub min: Option<f64>,
+    #[serde(default)]
+    pub max: Option<f64>,
+}
+
+impl Default for SimulatorConfig {
+    fn default() -> Self {
+        Self {
+            vend_duration: Duration::from_millis(4400),
+            homing_timeout: Duration::from_millis(4500),
+            command_duration: Duration::from_millis(5),
+            boot_duration: Duration::from_millis(1500),
+            initial_inventory: vec![10; 2],
+            byte_drop_rate: 0.0,
+            byte_corruption_rate: 0.0,
+            seed: 0,
+            rx_buffer_size: 64,
+            min_byte_interval: Duration::ZERO,
+            supports_handshake: true,
+            supports_checksums: true,
+            supports_events: true,
+            supports_request_ids: true,
+            supports_device_id: true,
+            supports_boot_id: true,
+            device_id: None,
+            extra_commands: Vec::new(),
+        }
+    }
+}
+
+struct SimulatedStepper {
+    inventory: u32,
+    /// The inventory sensor reading that the firmware last saw, which it
+    /// compares against to detect changes.
+    last_has_inventory: bool,
+    homing_switch_broken: bool,
+    vend_count: u32,
+}
+
+struct RememberedResponse {
+    idempotency_key: String,
+    is_ok: bool,
+    error_code: String,
+    response: String,
+}
+
+/// The emulated firmware. Input is processed one line at a time in the order
+/// it arrives, and each response becomes readable once the command that
+/// produced it has finished running.
+pub struct SimulatedBoard {
+    config: SimulatorConfig,
+    steppers: Vec<SimulatedStepper>,
+    remembered_responses: VecDeque<RememberedResponse>,
+    /// Input that doesn't form a complete line yet.
+    input_line: Vec<u8>,
+    /// Output bytes along with the time that they become readable.
+    pending_output: VecDeque<(Instant, u8)>,
+    /// When the board finishes running the commands it has already received.
+    busy_until: Instant,
+    /// Input received before this time is ignored.
+    booted_at: Instant,
+    /// Identifies the current boot, like the boot counter in the firmware.
+    boot_id: u64,
+    /// When the last byte of input was accepted.
+    last_byte_at: Option<Instant>,
+    /// The number of bytes that arrived while the board was busy, which are
+    /// waiting in its receive buffer.
+    rx_buffered_byte_count: usize,
+    /// Whether `useChecksums` has been received since the last reset.
+    checksums_required: bool,
+    /// Whether `useEvents` has been received since the last reset.
+    events_enabled: bool,
+    /// Events raised while running the current command, which are sent once
+    /// it finishes.
+    pending_events: Vec<String>,
+    /// The device id stored in EEPROM, which survives resets.
+    device_id: Option<String>,
+    rng_state: u64,
+    /// The number of upcoming responses to discard entirely.
+    dropped_response_count: u32,
+}
+
+impl SimulatedBoard {
+    pub fn new(config: SimulatorConfig, now: Instant) -> Self {
+        let steppers = config
+            .initial_inventory
+            .iter()
+            .map(|inventory| SimulatedStepper {
+                inventory: *inventory,
+                last_has_inventory: *inventory > 0,
+                homing_switch_broken: false,
+                vend_count: 0,
+            })
+            .collect();
+        // Xorshift gets stuck at zero, so the seed is offset.
+        let rng_state = config.seed ^ 0x9e37_79b9_7f4a_7c15;
+        let device_id = config.device_id.clone();
+        let mut board = Self {
+            config,
+            steppers,
+            remembered_responses: VecDeque::new(),
+            input_line: Vec::new(),
+            pending_output: VecDeque::new(),
+            busy_until: now,
+            booted_at: now,
+            boot_id: 0,
+            last_byte_at: None,
+            rx_buffered_byte_count: 0,
+            checksums_required: false,
+            events_enabled: false,
+            pending_events: Vec::new(),
+            device_id,
+            rng_state,
+            dropped_response_count: 0,
+        };
+        board.reboot(now);
+        board
+    }
+
+    /// Resets the board, losing any unprocessed input, unsent output and
+    /// remembered idempotency keys. Inventory, vend counts and the device id
+    /// survive the reset, and the board reports a new boot id.
+    pub fn reboot(&mut self, now: Instant) {
+        self.boot_id += 1;
+        self.remembered_responses.clear();
+        self.checksums_required = false;
+        self.events_enabled = false;
+        self.pending_events.clear();
+        self.input_line.clear();
+        self.pending_output.clear();
+        self.booted_at = now + self.config.boot_duration;
+        self.busy_until = self.booted_at;
+    }
+
+    /// Loads or unloads items, which the firmware notices as soon as it's
+    /// done with the command it's running, if any.
+    pub fn set_inventory(&mut self, stepper: usize, inventory: u32) {
+        self.steppers[stepper].inventory = inventory;
+        self.check_inventory_sensors();
+        self.write_pending_events();
+    }
+
+    pub fn set_homing_switch_broken(&mut self, stepper: usize, broken: bool) {
+        self.steppers[stepper].homing_switch_broken = broken;
+    }
+
+    pub fn get_stepper_count(&self) -> usize {
+        self.steppers.len()
+    }
+
+    /// Returns how many times the stepper has successfully vended an item.
+    pub fn get_vend_count(&self, stepper: usize) -> u32 {
+        self.steppers[stepper].vend_count
+    }
+
+    pub fn set_byte_drop_rate(&mut self, byte_drop_rate: f64) {
+        self.config.byte_drop_rate = byte_drop_rate;
+    }
+
+    pub fn set_byte_corruption_rate(&mut self, byte_corruption_rate: f64) {
+        self.config.byte_corruption_rate = byte_corruption_rate;
+    }
+
+    pub fn set_min_byte_interval(&mut self, min_byte_interval: Duration) {
+        self.config.min_byte_interval = min_byte_interval;
+    }
+
+    /// Returns the device id stored in EEPROM, if any.
+    pub fn get_device_id(&self) -> Option<&str> {
+        self.device_id.as_deref()
+    }
+
+    /// Sends raw bytes to the host right away, such as the start of a line
+    /// that was cut off by a reset.
+    pub fn write_raw_output(&mut self, bytes: &[u8], now: Instant) {
+        for byte in bytes {
+            self.pending_output.push_back((now, *byte));
+        }
+    }
+
+    /// Discards the next `count` responses as if they were lost on the wire.
+    /// The commands they belong to are still executed.
+    pub fn drop_next_responses(&mut self, count: u32) {
+        self.dropped_response_count += count;
+    }
+
+    /// Feeds bytes sent by the host into the board.
+    pub fn receive_bytes(&mut self, bytes: &[u8], now: Instant) {
+        for byte in bytes {
+            if now < self.booted_at || self.roll(self.config.byte_drop_rate) {
+                continue;
+            }
+            if self
+                .last_byte_at
+                .is_some_and(|last_byte_at| now - last_byte_at < self.config.min_byte_interval)
+            {
+                continue;
+            }
+            if !self.input_line.is_empty()
+                && self
+                    .last_byte_at
+                    .is_some_and(|last_byte_at| now - last_byte_at >= LINE_READ_TIMEOUT)
+            {
+                let line = String::from_utf8_lossy(&self.input_line).trim().to_string();
+                self.input_line.clear();
+                self.handle_line(line, now);
+            }
+            if now < self.busy_until {
+                if self.rx_buffered_byte_count >= self.config.rx_buffer_size {
+                    continue;
+                }
+                self.rx_buffered_byte_count += 1;
+            } else {
+                self.rx_buffered_byte_count = 0;
+            }
+            self.last_byte_at = Some(now);
+            if *byte == b'\n' {
+                let line = String::from_utf8_lossy(&self.input_line).trim().to_string();
+                self.input_line.clear();
+                self.handle_line(line, now);
+            } else {
+                self.input_line.push(*byte);
+            }
+        }
+    }
+
+    /// Returns the number of output bytes that are readable at `now`.
+    pub fn get_readable_byte_count(&self, now: Instant) -> usize {
+        self.pending_output
+            .iter()
+            .take_while(|(readable_at, _)| *readable_at <= now)
+            .count()
+    }
+
+    /// Removes and returns up to `max_len` output bytes that are readable at
+    /// `now`.
+    pub fn take_output(&mut self, max_len: usize, now: Instant) -> Vec<u8> {
+        let len = self.get_readable_byte_count(now).min(max_len);
+        self.pending_output
+            .drain(..len)
+            .map(|(_, byte)| byte)
+            .collect()
+    }
+
+    fn handle_line(&mut self, line: String, now: Instant) {
+        let mut request = Request {
+            command: line,
+            request_id: String::new(),
+            idempotency_key: String::new(),
+            checksum_matches: None,
+        };
+        if self.config.supports_checksums {
+            request.parse_checksum(self.checksums_required);
+        }
+        if self.config.supports_request_ids {
+            request.parse_request_id();
+            request.parse_idempotency_key();
+        }
+
+        let start_time = now.max(self.busy_until);
+        let (duration, is_ok, response, error_code) = if request.checksum_matches == Some(false) {
+            // Mismatched requests are rejected without being executed, so
+            // their idempotency keys aren't remembered either.
+            request.idempotency_key.clear();
+            (
+                self.config.command_duration,
+                false,
+                String::from("\"checksum mismatch.\""),
+                String::from("checksumMismatch"),
+            )
+        } else {
+            self.execute(&request)
+        };
+        self.busy_until = start_time + duration;
+
+        if !request.idempotency_key.is_empty() && !self.is_replay(&request) {
+            if self.remembered_responses.len() == REMEMBERED_IDEMPOTENCY_KEY_COUNT {
+                self.remembered_responses.pop_front();
+            }
+            self.remembered_responses.push_back(RememberedResponse {
+                idempotency_key: request.idempotency_key.clone(),
+                is_ok,
+                error_code: error_code.clone(),
+                response: response.clone(),
+            });
+        }
+
+        if self.dropped_response_count > 0 {
+            self.dropped_response_count -= 1;
+            return;
+        }
+
+        let id = if request.request_id.is_empty() {
+            String::new()
+        } else {
+            format!("\"id\": {}, ", request.request_id)
+        };
+        let code = if !is_ok && !error_code.is_empty() {
+            format!("\"code\": \"{error_code}\", ")
+        } else {
+            String::new()
+        };
+        let output = format!(
+            "{{\"status\": \"{}\", \"command\": \"{}\", {id}{code}\"response\": {response}}}",
+            if is_ok { "ok" } else { "error" },
+            request.command
+        );
+        // Responses carry a checksum whenever their request did, or had to.
+        self.write_line(output, request.checksum_matches.is_some());
+
+        self.check_inventory_sensors();
+        self.write_pending_events();
+    }
+
+    /// Queues an `inventoryChanged` event for every stepper whose inventory
+    /// sensor reading changed since it was last checked.
+    fn check_inventory_sensors(&mut self) {
+        for (stepper_index, stepper) in self.steppers.iter_mut().enumerate() {
+            let has_inventory = stepper.inventory > 0;
+            if has_inventory != stepper.last_has_inventory {
+                stepper.last_has_inventory = has_inventory;
+                self.pending_events.push(format!(
+                    "{{\"event\": \"inventoryChanged\", \"data\": \
+                     {{\"stepper\": {stepper_index}, \"hasInventory\": {has_inventory}}}}}"
+                ));
+            }
+        }
+    }
+
+    /// Sends the queued events once the board is done with the command it's
+    /// running. Events are discarded unless `useEvents` has been received.
+    fn write_pending_events(&mut self) {
+        let events = std::mem::take(&mut self.pending_events);
+        if !self.events_enabled {
+            return;
+        }
+        for event in events {
+            self.write_line(event, self.checksums_required);
+        }
+    }
+
+    /// Queues a line of output that becomes readable once the board is done
+    /// with the command it's running, garbling bytes as configured.
+    fn write_line(&mut self, line: String, with_checksum: bool) {
+        let mut output = line;
+        if with_checksum {
+            output = format!("{output}*{:04X}", get_crc16(output.as_bytes()));
+        }
+        output.push_str("\r\n");
+        for mut byte in output.into_bytes() {
+            if self.roll(self.config.byte_corruption_rate) {
+                byte ^= 1 << (self.next_random() % 8);
+            }
+            self.pending_output.push_back((self.busy_until, byte));
+        }
+    }
+
+    fn is_replay(&self, request: &Request) -> bool {
+        self.find_remembered_response(&request.idempotency_key)
+            .is_some()
+    }
+
+    fn find_remembered_response(&self, idempotency_key: &str) -> Option<&RememberedResponse> {
+        self.remembered_responses
+            .iter()
+            .find(|remembered| remembered.idempotency_key == idempotency_key)
+    }
+
+    /// Runs a command and returns how long it took, whether it succeeded, its
+    /// raw JSON response and its error code.
+    fn execute(&mut self, request: &Request) -> (Duration, bool, String, String) {
+        let command_duration = self.config.command_duration;
+        let ok = |response: String| (command_duration, true, response, String::new());
+
+        if let Some(remembered) = self.find_remembered_response(&request.idempotency_key) {
+            return (
+                command_duration,
+                remembered.is_ok,
+                remembered.response.clone(),
+                remembered.error_code.clone(),
+            );
+        }
+
+        if let Some(idempotency_key) = request.command.strip_prefix("idempotencyKeyStatus ") {
+            if self.config.supports_request_ids {
+                let boot_id = self.get_boot_id_field();
+                return ok(
+                    match self.find_remembered_response(idempotency_key.trim()) {
+                        None => format!("{{\"state\": \"unknown\"{boot_id}}}"),
+                        Some(remembered) => {
+                            let code = if remembered.is_ok {
+                                String::new()
+                            } else {
+                                format!("\"code\": \"{}\", ", remembered.error_code)
+                            };
+                            format!(
+                                "{{\"state\": \"done\", \"ok\": {}, {code}\"response\": {}{boot_id}}}",
+                                remembered.is_ok, remembered.response
+                            )
+                        }
+                    },
+                );
+            }
+        }
+
+        if let Some(device_id) = request.command.strip_prefix("setDeviceId ") {
+            if self.config.supports_device_id {
+                let device_id = device_id.trim();
+                if device_id.is_empty()
+                    || device_id.len() > MAX_DEVICE_ID_LENGTH
+                    || !device_id
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+                {
+                    return (
+                        command_duration,
+                        false,
+                        String::from("\"invalid device id.\""),
+                        String::from("invalidDeviceId"),
+                    );
+                }
+                self.device_id = Some(device_id.to_string());
+                return ok(String::from("null"));
+            }
+        }
+
+        let (name, raw_arguments) = request
+            .command
+            .split_once(' ')
+            .unwrap_or((&request.command, ""));
+        if let Some(extra_command) = self
+            .config
+            .extra_commands
+            .iter()
+            .find(|extra_command| extra_command.name == name)
+        {
+            let argument_values: Vec<&str> = raw_arguments
+                .split(' ')
+                .filter(|argument_value| !argument_value.is_empty())
+                .collect();
+            if argument_values.len() != extra_command.args.len() {
+                return (
+                    command_duration,
+                    false,
+                    String::from("\"wrong number of arguments.\""),
+                    String::from("invalidArguments"),
+                );
+            }
+            let mut response = extra_command.response.clone();
+            for (arg, argument_value) in extra_command.args.iter().zip(argument_values) {
+                response = response.replace(&format!("{{{}}}", arg.name), argument_value);
+            }
+            return ok(response);
+        }
+
+        let command = request.command.as_str();
+        match command {
+            "useChecksums" if self.config.supports_checksums => {
+                self.checksums_required = true;
+                ok(String::from("null"))
+            }
+            "useEvents" if self.config.supports_events => {
+                self.events_enabled = true;
+                ok(String::from("null"))
+            }
+            "getDeviceId" if self.config.supports_device_id => ok(self.get_device_id_json()),
+            "hello" if self.config.supports_handshake => ok(format!(
+                "{{\"protocol\": \"1.1\", \"firmware\": \"liveace_simulator\", \
+                 \"build\": \"simulated\"{}, {}\
+                 \"capabilities\": [\"requestIds\", \"idempotencyKeys\"{}{}{}{}], \
+                 \"rxBufferSize\": {}}}",
+                self.get_boot_id_field(),
+                match &self.device_id {
+                    Some(device_id) if self.config.supports_device_id => {
+                        format!("\"boardId\": \"{device_id}\", ")
+                    }
+                    _ => String::new(),
+                },
+                if self.config.supports_checksums {
+                    ", \"checksums\""
+                } else {
+                    ""
+                },
+                if self.config.supports_events {
+                    ", \"events\""
+                } else {
+                    ""
+                },
+                if self.config.supports_device_id {
+                    ", \"deviceId\""
+                } else {
+                    ""
+                },
+                if self
+                    .config
+                    .extra_commands
+                    .iter()
+                    .any(|extra_command| !extra_command.args.is_empty())
+                {
+                    ", \"arguments\""
+                } else {
+                    ""
+                },
+                self.config.rx_buffer_size
+            )),
+            "listCommands" => ok(self.get_command_list()),
+            _ => match self.parse_stepper_command(command) {
+                Some((stepper_index, "")) => self.vend(stepper_index, command),
+                Some((stepper_index, "HasInventory")) => {
+                    ok((self.steppers[stepper_index].inventory > 0).to_string())
+                }
+                Some((stepper_index, "OutOfInventory")) => {
+                    ok((self.steppers[stepper_index].inventory == 0).to_string())
+                }
+                _ => (
+                    command_duration,
+                    false,
+                    format!("\"unknown command: `{command}`.\""),
+                    if self.config.supports_request_ids {
+                        String::from("unknownCommand")
+                    } else {
+                        String::new()
+                    },
+                ),
+            },
+        }
+    }
+
+    /// Returns the `bootId` field appended to responses that report it,
+    /// including its leading comma, or nothing if the firmware doesn't report
+    /// boot ids.
+    fn get_boot_id_field(&self) -> String {
+        if self.config.supports_boot_id {
+            format!(", \"bootId\": \"{:x}\"", self.boot_id)
+        } else {
+            String::new()
+        }
+    }
+
+    fn get_device_id_json(&self) -> String {
+        match &self.device_id {
+            Some(device_id) => format!("\"{device_id}\""),
+            None => String::from("null"),
+        }
+    }
+
+    fn get_command_list(&self) -> String {
+        let quote_all = |suffix: &str| -> String {
+            (0..self.steppers.len())
+                .map(|stepper_index| format!("\"stepper{stepper_index}{suffix}\""))
+                .collect::<Vec<String>>()
+                .join(", ")
+        };
+        let mut command_lists = vec![
+            (String::from("null"), vec![quote_all("")]),
+            (
+                String::from("boolean"),
+                vec![quote_all("HasInventory"), quote_all("OutOfInventory")],
+            ),
+        ];
+        let bound = |key: &str, bound: Option<f64>| match bound {
+            Some(bound) => format!(", \"{key}\": {bound}"),
+            None => String::new(),
+        };
+        for extra_command in &self.config.extra_commands {
+            let listed_command = if extra_command.args.is_empty() {
+                format!("\"{}\"", extra_command.name)
+            } else {
+                let args: Vec<String> = extra_command
+                    .args
+                    .iter()
+                    .map(|arg| {
+                        format!(
+                            "{{\"name\": \"{}\", \"type\": \"{}\"{}{}}}",
+                            arg.name,
+                            arg.argument_type,
+                            bound("min", arg.min),
+                            bound("max", arg.max)
+                        )
+                    })
+                    .collect();
+                format!(
+                    "{{\"name\": \"{}\", \"args\": [{}]}}",
+                    extra_command.name,
+                    args.join(", ")
+                )
+            };
+            match command_lists
+                .iter_mut()
+                .find(|(value_type, _)| *value_type == extra_command.value_type)
+            {
+                Some((_, commands)) => commands.push(listed_command),
+                None => {
+                    command_lists.push((extra_command.value_type.clone(), vec![listed_command]))
+                }
+            }
+        }
+        let command_lists: Vec<String> = command_lists
+            .into_iter()
+            .map(|(value_type, mut commands)| {
+                commands.retain(|commands| !commands.is_empty());
+                format!("\"{value_type}\": [{}]", commands.join(", "))
+            })
+            .collect();
+        format!("{{{}}}", command_lists.join(", "))
+    }
+
+    /// Splits a stepper command such as `stepper1HasInventory` into the index
+    /// of the stepper and the rest of the command. Returns `None` if the
+    /// command isn't for an existing stepper.
+    fn parse_stepper_command<'a>(&self, command: &'a str) -> Option<(usize, &'a str)> {
+        let rest = command.strip_prefix("stepper")?;
+        let digit_count = rest.chars().take_while(|c| c.is_ascii_digit()).count();
+        let stepper_index = rest[..digit_count].parse::<usize>().ok()?;
+        // Rejects indices with leading zeros, such as `stepper01`.
+        if stepper_index >= self.steppers.len() || stepper_index.to_string() != rest[..digit_count]
+        {
+            return None;
+        }
+        Some((stepper_index, &rest[digit_count..]))
+    }
+
+    fn vend(&mut self, stepper_index: usize, command: &str) -> (Duration, bool, String, String) {
+        let stepper = &mut self.steppers[stepper_index];
+        if stepper.homing_switch_broken {
+            self.pending_events.push(format!(
+                "{{\"event\": \"homingSwitchNotTriggered\", \"data\": {{\"stepper\": {stepper_index}}}}}"
+            ));
+            return (
+                self.config.homing_timeout,
+                false,
+                format!("\"{command} homing switch not triggered.\""),
+                String::from("homingSwitchNotTriggered"),
+            );
+        }
+        stepper.vend_count += 1;
+        stepper.inventory = stepper.inventory.saturating_sub(1);
+        (
+            self.config.vend_duration,
+            true,
+            String::from("null"),
+            String::new(),
+        )
+    }
+
+    /// Returns true with the given probability.
+    fn roll(&mut self, probability: f64) -> bool {
+        if probability <= 0.0 {
+            return false;
+        }
+        (self.next_random() as f64 / u64::MAX as f64) < probability
+    }
+
+    /// Xorshift64, which is plenty random for deciding which bytes to mangle.
+    fn next_random(&mut self) -> u64 {
+        self.rng_state ^= self.rng_state << 13;
+        self.rng_state ^= self.rng_state >> 7;
+        self.rng_state ^= self.rng_state << 17;
+        self.rng_state
+    }
+}
+
+/// A request line split into its parts, mirroring `parseChecksum`,
+/// `parseRequestId` and `parseIdempotencyKey` in the firmware.
+struct Request {
+    command: String,
+    request_id: String,
+    idempotency_key: String,
+    /// Whether the request's checksum matched, or `None` if it neither had
+    /// nor required one.
+    checksum_matches: Option<bool>,
+}
+
+impl Request {
+    fn parse_checksum(&mut self, checksums_required: bool) {
+        let missing_checksum = if checksums_required {
+            Some(false)
+        } else {
+            None
+        };
+        let Some((payload, checksum)) = self.command.rsplit_once('*') else {
+            self.checksum_matches = missing_checksum;
+            return;
+        };
+        if checksum.len() != 4 || !checksum.chars().all(|c| c.is_ascii_hexdigit()) {
+            self.checksum_matches = missing_checksum;
+            return;
+        }
+        let checksum_matches =
+            u16::from_str_radix(checksum, 16).ok() == Some(get_crc16(payload.as_bytes()));
+        self.checksum_matches = Some(checksum_matches);
+        self.command = payload.trim().to_string();
+    }
+
+    fn parse_request_id(&mut self) {
+        let Some(rest) = self.command.strip_prefix('#') else {
+            return;
+        };
+        let Some((raw_request_id, command)) = rest.split_once(' ') else {
+            return;
+        };
+        if raw_request_id.is_empty() || !raw_request_id.chars().all(|c| c.is_ascii_digit()) {
+            return;
+        }
+        self.request_id = raw_request_id.to_string();
+        self.command = command.trim().to_string();
+    }
+
+    fn parse_idempotency_key(&mut self) {
+        if self.request_id.is_empty() {
+            return;
+        }
+        let Some(rest) = self.command.strip_prefix('!') else {
+            return;
+        };
+        let Some((idempotency_key, command)) = rest.split_once(' ') else {
+            return;
+        };
+        if idempotency_key.is_empty() {
+            return;
+        }
+        self.idempotency_key = idempotency_key.to_string();
+        self.command = command.trim().to_string();
+    }
+}
+
+/// Returns the CRC-16/CCITT-FALSE of `data`, like `crc16` in the firmware.
+fn get_crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// A `SerialPort` connected to a `SimulatedBoard`. Like a real Arduino, the
+/// board is reset when the port is opened.
+#[derive(Clone)]
+pub struct SimulatedSerialPort {
+    board: Arc<Mutex<SimulatedBoard>>,
+    timeout: Duration,
+}
+
+impl SimulatedSerialPort {
+    pub fn new(config: SimulatorConfig) -> Self {
+        Self {
+            board: Arc::new(Mutex::new(SimulatedBoard::new(config, Instant::now()))),
+            timeout: Duration::from_millis(1),
+        }
+    }
+
+    /// Returns the board behind the port, so that its state can be inspected
+    /// or changed.
+    pub fn get_board(&self) -> Arc<Mutex<SimulatedBoard>> {
+        self.board.clone()
+    }
+}
+
+impl Read for SimulatedSerialPort {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let start_time = Instant::now();
+        loop {
+            let output = self
+                .board
+                .lock()
+                .unwrap()
+                .take_output(buf.len(), Instant::now());
+            if !output.is_empty() {
+                buf[..output.len()].copy_from_slice(&output);
+                return Ok(output.len());
+            }
+            if start_time.elapsed() >= self.timeout {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "Operation timed out",
+                ));
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
+
+impl Write for SimulatedSerialPort {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.board
+            .lock()
+            .unwrap()
+            .receive_bytes(buf, Instant::now());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl serialport::SerialPort for SimulatedSerialPort {
+    fn name(&self) -> Option<String> {
+        Some(String::from("simulated"))
+    }
+
+    fn baud_rate(&self) -> serialport::Result<u32> {
+        Ok(57600)
+    }
+
+    fn data_bits(&self) -> serialport::Result<serialport::DataBits> {
+        Ok(serialport::DataBits::Eight)
+    }
+
+    fn flow_control(&self) -> serialport::Result<serialport::FlowControl> {
+        Ok(serialport::FlowControl::None)
+    }
+
+    fn parity(&self) -> serialport::Result<serialport::Parity> {
+        Ok(serialport::Parity::None)
+    }
+
+    fn stop_bits(&self) -> serialport::Result<serialport::StopBits> {
+        Ok(serialport::StopBits::One)
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn set_baud_rate(&mut self, _baud_rate: u32) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_data_bits(&mut self, _data_bits: serialport::DataBits) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_flow_control(
+        &mut self,
+        _flow_control: serialport::FlowControl,
+    ) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_parity(&mut self, _parity: serialport::Parity) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_stop_bits(&mut self, _stop_bits: serialport::StopBits) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> serialport::Result<()> {
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    fn write_request_to_send(&mut self, _level: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn write_data_terminal_ready(&mut self, _level: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn read_clear_to_send(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn read_data_set_ready(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn read_ring_indicator(&mut self) -> serialport::Result<bool> {
+        Ok(false)
+    }
+
+    fn read_carrier_detect(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+
+    fn bytes_to_read(&self) -> serialport::Result<u32> {
+        Ok(self
+            .board
+            .lock()
+            .unwrap()
+            .get_readable_byte_count(Instant::now()) as u32)
+    }
+
+    fn bytes_to_write(&self) -> serialport::Result<u32> {
+        Ok(0)
+    }
+
+    /// Discards output that is already readable. Output for commands that are
+    /// still running hasn't reached the host yet, so it is kept.
+    fn clear(&self, buffer_to_clear: serialport::ClearBuffer) -> serialport::Result<()> {
+        if buffer_to_clear != serialport::ClearBuffer::Output {
+            let mut board = self.board.lock().unwrap();
+            let now = Instant::now();
+            let readable_byte_count = board.get_readable_byte_count(now);
+            board.take_output(readable_byte_count, now);
+        }
+        Ok(())
+    }
+
+    fn try_clone(&self) -> serialport::Result<Box<dyn serialport::SerialPort>> {
+        Ok(Box::new(self.clone()))
+    }
+
+    fn set_break(&self) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn clear_break(&self) -> serialport::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,123 @@
+//! Logging setup, and the correlation ids that tie log lines to the HTTP
+//! request that caused them.
+//!
+//! Every request gets an id, taken from its `X-Request-Id` header if it has a
+//! usable one, or generated otherwise. The id is sent back in the response's
+//! `X-Request-Id` header, and is attached to every line logged while the
+//! request's command runs, down to individual serial retries.
+
+use crate::config::{LogFormat, LoggingConfig};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Header;
+use rocket::request::{FromRequest, Outcome};
+use rocket::{Data, Request, Response};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::IsTerminal;
+use std::time::Instant;
+use tracing_subscriber::EnvFilter;
+
+const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// The longest request id that is accepted from clients.
+const MAX_REQUEST_ID_LENGTH: usize = 64;
+
+/// Installs the global logger. Must only be called once.
+pub fn init(logging_config: &LoggingConfig) {
+    // Validated when the configuration was loaded.
+    let env_filter = EnvFilter::try_new(&logging_config.level).unwrap();
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(env_filter)
+        .with_ansi(std::io::stdout().is_terminal());
+    match logging_config.format {
+        LogFormat::Text => subscriber.init(),
+        // Fields of the spans that a line was logged in, such as the request
+        // id, are included so that log collectors can filter by them.
+        LogFormat::Json => subscriber
+            .json()
+            .with_current_span(false)
+            .with_span_list(true)
+            .init(),
+    }
+}
+
+/// The correlation id of the request being handled.
+#[derive(Clone, Debug)]
+pub struct RequestId(pub String);
+
+impl std::fmt::Display for RequestId {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RequestId {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(get_request_id(request).clone())
+    }
+}
+
+/// Returns the request's correlation id, choosing it on first use.
+fn get_request_id<'r>(request: &'r Request<'_>) -> &'r RequestId {
+    request.local_cache(|| {
+        let client_request_id = request.headers().get_one(REQUEST_ID_HEADER).filter(|id| {
+            !id.is_empty()
+                && id.len() <= MAX_REQUEST_ID_LENGTH
+                && id.chars().all(|c| c.is_ascii_graphic())
+        });
+        RequestId(match client_request_id {
+            Some(request_id) => request_id.to_string(),
+            None => format!("{:016x}", RandomState::new().build_hasher().finish()),
+        })
+    })
+}
+
+/// When the request started being handled.
+struct RequestStart(Instant);
+
+/// Logs every request along with its status and how long it took, and sends
+/// its correlation id back to the client.
+pub struct RequestLogger;
+
+#[rocket::async_trait]
+impl Fairing for RequestLogger {
+    fn info(&self) -> Info {
+        Info {
+            name: "Log requests with correlation ids",
+            kind: Kind::Request | Kind::Response | Kind::Liftoff,
+        }
+    }
+
+    async fn on_liftoff(&self, rocket: &rocket::Rocket<rocket::Orbit>) {
+        tracing::info!(
+            address = %rocket.config().address,
+            port = rocket.config().port,
+            "Server started"
+        );
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _data: &mut Data<'_>) {
+        request.local_cache(|| RequestStart(Instant::now()));
+        get_request_id(request);
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let request_id = get_request_id(request);
+        let elapsed = request
+            .local_cache(|| RequestStart(Instant::now()))
+            .0
+            .elapsed();
+        tracing::info!(
+            request_id = %request_id,
+            method = %request.method(),
+            uri = %request.uri(),
+            status = response.status().code,
+            elapsed_ms = elapsed.as_millis() as u64,
+            "Handled request"
+        );
+        response.set_header(Header::new(REQUEST_ID_HEADER, request_id.0.clone()));
+    }
+}
@@ -1,68 +1,655 @@
 #[macro_use]
 extern crate rocket;
-use serialport::{SerialPortInfo, SerialPortType, UsbPortInfo};
-use std::time::Duration;
+mod audit;
 mod command_executor;
-use command_executor::liveace::LiVeAceSerialPort;
-use command_executor::{CommandExecutor, CommandExecutorManager, NamespacedCommandExecutor};
-use rayon::prelude::*;
+mod config;
+mod discovery;
+mod events;
+mod jobs;
+#[cfg(test)]
+mod liveace_simulator;
+mod logging;
+mod metrics;
+mod util;
+use audit::{AuditLog, AuditQuery, CallerContext};
+use command_executor::{
+    CommandArguments, CommandExecutorError, CommandExecutorManager, CommandValueType,
+    ConnectionState,
+};
+use config::Config;
+use discovery::{PortStatuses, PortWatcher};
+use events::EventBus;
+use jobs::JobManager;
+use logging::{RequestId, RequestLogger};
 use rocket::{
     fairing::{Fairing, Info, Kind},
-    http::Header,
+    http::{ContentType, Header, Status},
+    request::{self, FromRequest},
+    response::stream::{Event, EventStream},
+    tokio::sync::broadcast::error::RecvError,
     Request, Response, State,
 };
-use std::sync::Mutex;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+const AUDIT_CONTEXT_HEADER: &str = "X-Audit-Context";
+
+type CommandResponse = Result<
+    rocket::serde::json::Json<serde_json::Value>,
+    rocket::response::status::Custom<rocket::serde::json::Json<serde_json::Value>>,
+>;
 
 #[get("/nullCommands/<command>")]
-fn run_null_command_handler(
+async fn run_null_command_handler(
+    command: String,
+    command_executor_manager: &State<Arc<CommandExecutorManager>>,
+    caller: CallerContext,
+) -> CommandResponse {
+    run_command(
+        command_executor_manager.inner().clone(),
+        caller,
+        command,
+        CommandValueType::Null,
+        CommandArguments::new(),
+    )
+    .await
+}
+
+#[post("/nullCommands/<command>", data = "<arguments>")]
+async fn run_null_command_with_arguments_handler(
     command: String,
-    command_executor_manager_mutex: &State<Mutex<CommandExecutorManager>>,
-) -> Result<rocket::serde::json::Json<serde_json::Value>, rocket::response::status::NotFound<String>>
-{
-    let mut command_executor_manager = command_executor_manager_mutex.lock().unwrap();
+    arguments: rocket::serde::json::Json<CommandArguments>,
+    command_executor_manager: &State<Arc<CommandExecutorManager>>,
+    caller: CallerContext,
+) -> CommandResponse {
+    run_command(
+        command_executor_manager.inner().clone(),
+        caller,
+        command,
+        CommandValueType::Null,
+        arguments.into_inner(),
+    )
+    .await
+}
 
-    match command_executor_manager.execute_null_command(&command) {
-        Ok(_) => Ok(rocket::serde::json::Json(serde_json::json!(null))),
-        Err(err) => {
-            // TODO - NotFound isn't always going to be the right response here. Let's take more care to make sure we always return a relevant HTTP status code.
-            Err(rocket::response::status::NotFound(format!("{err:?}")))
+#[get("/boolCommands/<command>")]
+async fn run_bool_command_handler(
+    command: String,
+    command_executor_manager: &State<Arc<CommandExecutorManager>>,
+    caller: CallerContext,
+) -> CommandResponse {
+    run_command(
+        command_executor_manager.inner().clone(),
+        caller,
+        command,
+        CommandValueType::Boolean,
+        CommandArguments::new(),
+    )
+    .await
+}
+
+#[post("/boolCommands/<command>", data = "<arguments>")]
+async fn run_bool_command_with_arguments_handler(
+    command: String,
+    arguments: rocket::serde::json::Json<CommandArguments>,
+    command_executor_manager: &State<Arc<CommandExecutorManager>>,
+    caller: CallerContext,
+) -> CommandResponse {
+    run_command(
+        command_executor_manager.inner().clone(),
+        caller,
+        command,
+        CommandValueType::Boolean,
+        arguments.into_inner(),
+    )
+    .await
+}
+
+#[get("/integerCommands/<command>")]
+async fn run_integer_command_handler(
+    command: String,
+    command_executor_manager: &State<Arc<CommandExecutorManager>>,
+    caller: CallerContext,
+) -> CommandResponse {
+    run_command(
+        command_executor_manager.inner().clone(),
+        caller,
+        command,
+        CommandValueType::Integer,
+        CommandArguments::new(),
+    )
+    .await
+}
+
+#[post("/integerCommands/<command>", data = "<arguments>")]
+async fn run_integer_command_with_arguments_handler(
+    command: String,
+    arguments: rocket::serde::json::Json<CommandArguments>,
+    command_executor_manager: &State<Arc<CommandExecutorManager>>,
+    caller: CallerContext,
+) -> CommandResponse {
+    run_command(
+        command_executor_manager.inner().clone(),
+        caller,
+        command,
+        CommandValueType::Integer,
+        arguments.into_inner(),
+    )
+    .await
+}
+
+#[get("/floatCommands/<command>")]
+async fn run_float_command_handler(
+    command: String,
+    command_executor_manager: &State<Arc<CommandExecutorManager>>,
+    caller: CallerContext,
+) -> CommandResponse {
+    run_command(
+        command_executor_manager.inner().clone(),
+        caller,
+        command,
+        CommandValueType::Float,
+        CommandArguments::new(),
+    )
+    .await
+}
+
+#[post("/floatCommands/<command>", data = "<arguments>")]
+async fn run_float_command_with_arguments_handler(
+    command: String,
+    arguments: rocket::serde::json::Json<CommandArguments>,
+    command_executor_manager: &State<Arc<CommandExecutorManager>>,
+    caller: CallerContext,
+) -> CommandResponse {
+    run_command(
+        command_executor_manager.inner().clone(),
+        caller,
+        command,
+        CommandValueType::Float,
+        arguments.into_inner(),
+    )
+    .await
+}
+
+#[get("/stringCommands/<command>")]
+async fn run_string_command_handler(
+    command: String,
+    command_executor_manager: &State<Arc<CommandExecutorManager>>,
+    caller: CallerContext,
+) -> CommandResponse {
+    run_command(
+        command_executor_manager.inner().clone(),
+        caller,
+        command,
+        CommandValueType::String,
+        CommandArguments::new(),
+    )
+    .await
+}
+
+#[post("/stringCommands/<command>", data = "<arguments>")]
+async fn run_string_command_with_arguments_handler(
+    command: String,
+    arguments: rocket::serde::json::Json<CommandArguments>,
+    command_executor_manager: &State<Arc<CommandExecutorManager>>,
+    caller: CallerContext,
+) -> CommandResponse {
+    run_command(
+        command_executor_manager.inner().clone(),
+        caller,
+        command,
+        CommandValueType::String,
+        arguments.into_inner(),
+    )
+    .await
+}
+
+#[get("/jsonCommands/<command>")]
+async fn run_json_command_handler(
+    command: String,
+    command_executor_manager: &State<Arc<CommandExecutorManager>>,
+    caller: CallerContext,
+) -> CommandResponse {
+    run_command(
+        command_executor_manager.inner().clone(),
+        caller,
+        command,
+        CommandValueType::Json,
+        CommandArguments::new(),
+    )
+    .await
+}
+
+#[post("/jsonCommands/<command>", data = "<arguments>")]
+async fn run_json_command_with_arguments_handler(
+    command: String,
+    arguments: rocket::serde::json::Json<CommandArguments>,
+    command_executor_manager: &State<Arc<CommandExecutorManager>>,
+    caller: CallerContext,
+) -> CommandResponse {
+    run_command(
+        command_executor_manager.inner().clone(),
+        caller,
+        command,
+        CommandValueType::Json,
+        arguments.into_inner(),
+    )
+    .await
+}
+
+/// The caller of a command, identified by the request's correlation id and by
+/// the optional `X-Audit-Context` header, such as the invoice that paid for a
+/// vend. Both are recorded to the audit log along with the command.
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for CallerContext {
+    type Error = String;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let context = request.headers().get_one(AUDIT_CONTEXT_HEADER);
+        if context.is_some_and(|context| context.len() > audit::MAX_CONTEXT_LENGTH) {
+            return request::Outcome::Error((
+                Status::BadRequest,
+                format!(
+                    "{AUDIT_CONTEXT_HEADER} must be at most {} bytes",
+                    audit::MAX_CONTEXT_LENGTH
+                ),
+            ));
         }
+
+        let request_id = request.guard::<RequestId>().await.unwrap();
+        request::Outcome::Success(CallerContext {
+            request_id: Some(request_id.0),
+            context: context.map(String::from),
+        })
     }
 }
 
-#[get("/boolCommands/<command>")]
-fn run_bool_command_handler(
+async fn run_command(
+    command_executor_manager: Arc<CommandExecutorManager>,
+    caller: CallerContext,
     command: String,
-    command_executor_manager_mutex: &State<Mutex<CommandExecutorManager>>,
-) -> Result<rocket::serde::json::Json<serde_json::Value>, rocket::response::status::NotFound<String>>
-{
-    let mut command_executor_manager = command_executor_manager_mutex.lock().unwrap();
+    value_type: CommandValueType,
+    arguments: CommandArguments,
+) -> CommandResponse {
+    // Commands can block for a long time (e.g. while a motor moves), so they
+    // run on the blocking thread pool to keep the async workers free to serve
+    // other requests, including commands for other executors.
+    let span = tracing::info_span!("request", request_id = caller.request_id.as_deref());
+    let res = rocket::tokio::task::spawn_blocking(move || {
+        let _span = span.enter();
+        match command_executor_manager.execute_command(&command, value_type, &arguments, &caller) {
+            Ok(value) => Ok(rocket::serde::json::Json(serde_json::json!(value))),
+            Err(err) => Err(get_command_error_response(
+                &err,
+                command_executor_manager
+                    .get_command_namespace(&command, value_type)
+                    .as_deref(),
+            )),
+        }
+    })
+    .await;
+    // A command that panicked may have left its device in any state, which the
+    // executor reports to later commands.
+    res.unwrap_or_else(|err| {
+        tracing::error!(error = %err, "Command panicked");
+        Err(rocket::response::status::Custom(
+            Status::InternalServerError,
+            rocket::serde::json::Json(serde_json::json!({
+                "kind": "internalError",
+                "message": "The command failed unexpectedly"
+            })),
+        ))
+    })
+}
 
-    match command_executor_manager.execute_bool_command(&command) {
-        Ok(bool_res) => Ok(rocket::serde::json::Json(serde_json::json!(bool_res))),
-        Err(err) => {
-            // TODO - NotFound isn't always going to be the right response here. Let's take more care to make sure we always return a relevant HTTP status code.
-            Err(rocket::response::status::NotFound(format!("{err:?}")))
+fn get_command_error_response(
+    err: &CommandExecutorError,
+    namespace: Option<&str>,
+) -> rocket::response::status::Custom<rocket::serde::json::Json<serde_json::Value>> {
+    let status = match err {
+        CommandExecutorError::UnknownCommand => Status::NotFound,
+        CommandExecutorError::InvalidArguments(_) => Status::BadRequest,
+        CommandExecutorError::Timeout | CommandExecutorError::Indeterminate => {
+            Status::GatewayTimeout
         }
+        CommandExecutorError::MalformedResponse => Status::BadGateway,
+        CommandExecutorError::Busy => Status::Conflict,
+        CommandExecutorError::DeviceError { .. } | CommandExecutorError::ConnectionError(_) => {
+            Status::ServiceUnavailable
+        }
+    };
+
+    rocket::response::status::Custom(status, rocket::serde::json::Json(err.to_json(namespace)))
+}
+
+#[derive(serde::Deserialize)]
+struct JobRequest {
+    command: String,
+    #[serde(rename = "type")]
+    value_type: CommandValueType,
+    #[serde(default)]
+    arguments: CommandArguments,
+}
+
+#[post("/jobs", data = "<job_request>")]
+fn submit_job_handler(
+    job_request: rocket::serde::json::Json<JobRequest>,
+    job_manager: &State<Arc<JobManager>>,
+    caller: CallerContext,
+) -> Result<
+    rocket::response::status::Custom<rocket::serde::json::Json<serde_json::Value>>,
+    rocket::response::status::Custom<rocket::serde::json::Json<serde_json::Value>>,
+> {
+    let job_request = job_request.into_inner();
+    // The job's thread inherits this span, so that its log lines carry the
+    // id of the request that submitted it.
+    let _span = tracing::info_span!("request", request_id = caller.request_id.as_deref()).entered();
+
+    match job_manager.submit(
+        job_request.command,
+        job_request.value_type,
+        job_request.arguments,
+        caller,
+    ) {
+        Ok(job) => Ok(rocket::response::status::Custom(
+            Status::Accepted,
+            rocket::serde::json::Json(serde_json::json!(job)),
+        )),
+        Err((err, namespace)) => Err(get_command_error_response(&err, namespace.as_deref())),
+    }
+}
+
+#[get("/jobs/<job_id>")]
+fn get_job_handler(
+    job_id: String,
+    job_manager: &State<Arc<JobManager>>,
+) -> Result<
+    rocket::serde::json::Json<serde_json::Value>,
+    rocket::response::status::NotFound<rocket::serde::json::Json<serde_json::Value>>,
+> {
+    match job_manager.get_job(&job_id) {
+        Some(job) => Ok(rocket::serde::json::Json(serde_json::json!(job))),
+        None => Err(rocket::response::status::NotFound(
+            rocket::serde::json::Json(serde_json::json!({
+                "kind": "unknownJob",
+                "message": format!("Unknown job '{job_id}'")
+            })),
+        )),
     }
 }
 
 #[get("/listCommands")]
 fn list_commands_handler(
-    command_executor_manager_mutex: &State<Mutex<CommandExecutorManager>>,
+    command_executor_manager: &State<Arc<CommandExecutorManager>>,
 ) -> rocket::serde::json::Json<serde_json::Value> {
-    let command_executor_manager = command_executor_manager_mutex.lock().unwrap();
+    let mut commands_by_type = serde_json::Map::new();
+    // Only commands that take arguments are listed here, so that clients that
+    // don't support arguments can keep treating every command as a bare name.
+    let mut command_arguments = serde_json::Map::new();
+    for value_type in CommandValueType::ALL {
+        let mut commands: Vec<String> = Vec::new();
+        for command_spec in command_executor_manager.get_commands(value_type) {
+            if !command_spec.arguments.is_empty() {
+                command_arguments.insert(
+                    command_spec.name.clone(),
+                    serde_json::json!(command_spec.arguments),
+                );
+            }
+            commands.push(command_spec.name);
+        }
+        commands.sort();
+        commands_by_type.insert(
+            get_command_list_key(value_type).to_string(),
+            serde_json::json!(commands),
+        );
+    }
+    commands_by_type.insert(
+        String::from("commandArguments"),
+        serde_json::Value::Object(command_arguments),
+    );
+    // Commands of aliased executors are listed under both names, and this
+    // tells clients which namespace each alias currently stands for.
+    commands_by_type.insert(
+        String::from("namespaceAliases"),
+        serde_json::json!(command_executor_manager.get_namespace_aliases()),
+    );
 
-    let mut null_commands: Vec<&str> = command_executor_manager.get_null_commands().collect();
-    null_commands.sort();
+    rocket::serde::json::Json(serde_json::Value::Object(commands_by_type))
+}
+
+/// Returns the firmware and protocol features of every connected device, keyed
+/// by executor namespace.
+#[get("/deviceInfo")]
+fn device_info_handler(
+    command_executor_manager: &State<Arc<CommandExecutorManager>>,
+) -> rocket::serde::json::Json<serde_json::Value> {
+    rocket::serde::json::Json(serde_json::json!(
+        command_executor_manager.get_device_info_by_namespace()
+    ))
+}
+
+/// Returns the counters of every connected device, such as how many corrupted
+/// frames it has sent, keyed by executor namespace.
+#[get("/executorStats")]
+fn executor_stats_handler(
+    command_executor_manager: &State<Arc<CommandExecutorManager>>,
+) -> rocket::serde::json::Json<serde_json::Value> {
+    rocket::serde::json::Json(serde_json::Value::Object(
+        command_executor_manager.get_stats_by_namespace(),
+    ))
+}
+
+/// Returns the health of every executor, keyed by namespace, including the
+/// port and board type it was discovered on, the device's firmware, and how its
+/// recent commands went. Aliased namespaces without a connected executor are
+/// listed as disconnected, so that a missing board shows up as needing service.
+#[get("/executors")]
+fn executors_handler(
+    command_executor_manager: &State<Arc<CommandExecutorManager>>,
+    port_statuses: &State<Arc<PortStatuses>>,
+) -> rocket::serde::json::Json<serde_json::Value> {
+    let ports_by_namespace = port_statuses.get_ports_by_namespace();
+    let executors: serde_json::Map<String, serde_json::Value> = command_executor_manager
+        .get_executor_statuses()
+        .into_iter()
+        .map(|(namespace, executor_status)| {
+            let port = ports_by_namespace.get(&namespace);
+            let mut executor_json = serde_json::json!(executor_status);
+            executor_json["portPath"] = serde_json::json!(port.map(|(port_name, _)| port_name));
+            executor_json["boardType"] =
+                serde_json::json!(port.and_then(|(_, port_status)| port_status.board_type));
+            (namespace, executor_json)
+        })
+        .collect();
+    rocket::serde::json::Json(serde_json::Value::Object(executors))
+}
+
+/// Returns metrics about command execution in the Prometheus text format, such
+/// as how many commands succeeded or failed, how long they took and how often
+/// requests had to be resent, per executor namespace and command.
+#[get("/metrics")]
+fn metrics_handler(
+    command_executor_manager: &State<Arc<CommandExecutorManager>>,
+) -> (ContentType, String) {
+    (
+        // The version of the text format, which Prometheus uses to pick a
+        // parser.
+        ContentType::new("text", "plain").with_params(("version", "0.0.4")),
+        command_executor_manager.get_metrics_text(),
+    )
+}
+
+/// Liveness check, which succeeds as long as the server is responding. The
+/// body is the same as for `/health/ready`.
+#[get("/health")]
+fn health_handler(
+    command_executor_manager: &State<Arc<CommandExecutorManager>>,
+) -> rocket::serde::json::Json<serde_json::Value> {
+    rocket::serde::json::Json(get_health(command_executor_manager).1)
+}
+
+/// Readiness check, which fails with 503 unless at least one executor is
+/// registered and every executor, including aliased ones, is connected and
+/// responsive.
+#[get("/health/ready")]
+fn readiness_handler(
+    command_executor_manager: &State<Arc<CommandExecutorManager>>,
+) -> rocket::response::status::Custom<rocket::serde::json::Json<serde_json::Value>> {
+    let (ready, health) = get_health(command_executor_manager);
+    rocket::response::status::Custom(
+        if ready {
+            Status::Ok
+        } else {
+            Status::ServiceUnavailable
+        },
+        rocket::serde::json::Json(health),
+    )
+}
+
+/// Returns whether the server is ready to run commands, along with a summary
+/// of its health for API clients.
+fn get_health(command_executor_manager: &CommandExecutorManager) -> (bool, serde_json::Value) {
+    let executor_statuses = command_executor_manager.get_executor_statuses();
+    let unhealthy_namespaces: Vec<&String> = executor_statuses
+        .iter()
+        .filter(|(_, executor_status)| {
+            executor_status.connection_state != ConnectionState::Connected
+        })
+        .map(|(namespace, _)| namespace)
+        .collect();
+    let connected_count = executor_statuses
+        .values()
+        .filter(|executor_status| executor_status.connection_state != ConnectionState::Disconnected)
+        .count();
+    let ready = connected_count > 0 && unhealthy_namespaces.is_empty();
+    (
+        ready,
+        serde_json::json!({
+            "live": true,
+            "ready": ready,
+            "connectedExecutorCount": connected_count,
+            "unhealthyExecutors": unhealthy_namespaces
+        }),
+    )
+}
+
+/// Returns every serial port found during the last scan, keyed by port name,
+/// with the namespace of the executor running on it or the reason it was
+/// skipped.
+#[get("/ports")]
+fn ports_handler(
+    port_statuses: &State<Arc<PortStatuses>>,
+) -> rocket::serde::json::Json<serde_json::Value> {
+    rocket::serde::json::Json(serde_json::json!(port_statuses.get_snapshot()))
+}
+
+/// Returns the audit log's records of executed commands, oldest first, along
+/// with whether the log has been tampered with. `from` and `to` are inclusive
+/// bounds in milliseconds since the Unix epoch, and `command` is either the
+/// command as it was requested or just its name, such as `vend`. Only answers
+/// localhost unless `audit.allow_remote_queries` is set.
+#[get("/audit?<from>&<to>&<command>")]
+async fn audit_handler(
+    from: Option<u64>,
+    to: Option<u64>,
+    command: Option<String>,
+    // The peer's address, rather than the spoofable `X-Real-IP` header that
+    // `IpAddr` is read from.
+    remote: Option<SocketAddr>,
+    audit_log: &State<Arc<AuditLog>>,
+    config: &State<Arc<Config>>,
+) -> Result<
+    rocket::serde::json::Json<serde_json::Value>,
+    rocket::response::status::Custom<rocket::serde::json::Json<serde_json::Value>>,
+> {
+    if !config.audit.allow_remote_queries && !remote.is_some_and(|remote| remote.ip().is_loopback())
+    {
+        return Err(rocket::response::status::Custom(
+            Status::Forbidden,
+            rocket::serde::json::Json(serde_json::json!({
+                "kind": "forbidden",
+                "message": "The audit log can only be queried from localhost"
+            })),
+        ));
+    }
+
+    let audit_log = audit_log.inner().clone();
+    let query = AuditQuery { from, to, command };
+    // The whole log is read to check its chain, which can take a while.
+    match rocket::tokio::task::spawn_blocking(move || audit_log.query(&query))
+        .await
+        .unwrap()
+    {
+        Ok(result) => Ok(rocket::serde::json::Json(serde_json::json!(result))),
+        Err(err) => Err(rocket::response::status::Custom(
+            Status::InternalServerError,
+            rocket::serde::json::Json(serde_json::json!({
+                "kind": "auditLogError",
+                "message": err
+            })),
+        )),
+    }
+}
+
+/// Returns the most recent events pushed by devices, oldest first. Pass a
+/// namespace to only get the events of a single device.
+#[get("/events?<namespace>")]
+fn recent_events_handler(
+    namespace: Option<String>,
+    event_bus: &State<Arc<EventBus>>,
+) -> rocket::serde::json::Json<serde_json::Value> {
+    rocket::serde::json::Json(serde_json::json!(
+        event_bus.get_recent_events(namespace.as_deref())
+    ))
+}
 
-    let mut bool_commands: Vec<&str> = command_executor_manager.get_bool_commands().collect();
-    bool_commands.sort();
+/// Streams events as they are published, as Server-Sent Events whose data is
+/// the JSON-encoded event. This includes executors connecting and
+/// disconnecting, commands starting and finishing, and events pushed by
+/// devices, such as inventory sensor changes. Pass a namespace to only get the
+/// events of a single device.
+#[get("/events/stream?<namespace>")]
+fn event_stream_handler(
+    namespace: Option<String>,
+    event_bus: &State<Arc<EventBus>>,
+    mut shutdown: rocket::Shutdown,
+) -> EventStream![] {
+    let mut receiver = event_bus.subscribe();
+    EventStream! {
+        loop {
+            let device_event = rocket::tokio::select! {
+                res = receiver.recv() => match res {
+                    Ok(device_event) => device_event,
+                    // Events that a slow client missed can't be recovered, but
+                    // the gap in event ids tells it to refetch any state it
+                    // depends on.
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                },
+                _ = &mut shutdown => break,
+            };
+            if namespace
+                .as_ref()
+                .is_some_and(|namespace| &device_event.namespace != namespace)
+            {
+                continue;
+            }
+            yield Event::json(&device_event).id(device_event.id.to_string());
+        }
+    }
+}
 
-    rocket::serde::json::Json(serde_json::json!({
-        "nullCommands": null_commands,
-        "boolCommands": bool_commands
-    }))
+/// Returns the key under which commands returning values of the given type are
+/// listed in the response to `/listCommands`.
+fn get_command_list_key(value_type: CommandValueType) -> &'static str {
+    match value_type {
+        CommandValueType::Null => "nullCommands",
+        CommandValueType::Boolean => "boolCommands",
+        CommandValueType::Integer => "integerCommands",
+        CommandValueType::Float => "floatCommands",
+        CommandValueType::String => "stringCommands",
+        CommandValueType::Json => "jsonCommands",
+    }
 }
 
 struct Cors;
@@ -89,103 +676,111 @@ impl Fairing for Cors {
 
 #[rocket::launch]
 async fn rocket() -> _ {
-    println!("Bootstrapping Arduino(s)...");
-    let serial_ports = match serialport::available_ports() {
-        Ok(serial_ports) => {
-            println!("Discovered {} serial ports", serial_ports.len());
-            println!();
-            for serial_port in &serial_ports {
-                println!("{:#?}", serial_port);
-                println!();
-            }
-            serial_ports
+    let config = match Config::load() {
+        Ok(config) => Arc::new(config),
+        Err(err) => {
+            eprintln!("Invalid configuration: {err}");
+            std::process::exit(1);
         }
+    };
+    logging::init(&config.logging);
+
+    let event_bus = Arc::new(EventBus::new());
+    let command_executor_manager =
+        Arc::new(CommandExecutorManager::new(Vec::new(), event_bus.clone()).unwrap());
+    command_executor_manager.set_namespace_aliases(config.aliases.clone());
+    let audit_log = match AuditLog::open(&config.audit.path) {
+        Ok(audit_log) => Arc::new(audit_log),
         Err(err) => {
-            println!("Unable to enumerate serial ports: {}", err);
-            Vec::default()
+            tracing::error!(error = %err, "Unable to open the audit log");
+            std::process::exit(1);
         }
     };
+    command_executor_manager.set_audit_log(audit_log.clone());
 
-    println!("Discovering LiVeACE Arduinos...");
-    let liveace_serial_ports: Vec<LiVeAceSerialPort> = serial_ports
-        .into_par_iter()
-        .map(get_liveace_serial_port)
-        .filter_map(|port_or| port_or)
-        .collect();
+    // Boards are discovered in the background, so that probing slow or
+    // unrelated devices doesn't hold up the server. Until they are found,
+    // `/health/ready` reports the server as not ready.
+    let port_watcher = PortWatcher::new(
+        command_executor_manager.clone(),
+        event_bus.clone(),
+        config.clone(),
+    );
+    let port_statuses = port_watcher.get_port_statuses();
+    port_watcher.spawn();
 
-    let command_executors: Vec<Box<dyn NamespacedCommandExecutor>> = liveace_serial_ports
-        .into_iter()
-        .map(|port| Box::from(port) as Box<dyn NamespacedCommandExecutor>)
-        .collect();
-    println!("Discovered {} LiVeACE Arduinos!", command_executors.len());
+    let job_manager = Arc::new(JobManager::new(command_executor_manager.clone()));
 
-    println!("Starting server...");
+    tracing::info!("Starting server...");
     rocket::build()
-        .manage(Mutex::from(
-            CommandExecutorManager::new(command_executors).unwrap(),
-        ))
+        .manage(command_executor_manager)
+        .manage(job_manager)
+        .manage(event_bus)
+        .manage(port_statuses)
+        .manage(audit_log)
+        .manage(config.clone())
         .configure(rocket::Config {
-            port: 21000,
+            // Validated when the configuration was loaded.
+            address: config.server.address.parse().unwrap(),
+            port: config.server.port,
             ..Default::default()
         })
+        .attach(RequestLogger)
         .attach(Cors)
         .mount(
             "/",
             routes![
                 run_null_command_handler,
+                run_null_command_with_arguments_handler,
                 run_bool_command_handler,
-                list_commands_handler
+                run_bool_command_with_arguments_handler,
+                run_integer_command_handler,
+                run_integer_command_with_arguments_handler,
+                run_float_command_handler,
+                run_float_command_with_arguments_handler,
+                run_string_command_handler,
+                run_string_command_with_arguments_handler,
+                run_json_command_handler,
+                run_json_command_with_arguments_handler,
+                submit_job_handler,
+                get_job_handler,
+                list_commands_handler,
+                device_info_handler,
+                executor_stats_handler,
+                ports_handler,
+                executors_handler,
+                health_handler,
+                readiness_handler,
+                metrics_handler,
+                audit_handler,
+                recent_events_handler,
+                event_stream_handler
             ],
         )
 }
 
-fn get_liveace_serial_port(serial_port_info: SerialPortInfo) -> Option<LiVeAceSerialPort> {
-    let usb_port_info = match &serial_port_info.port_type {
-        SerialPortType::UsbPort(usb_port_info) => usb_port_info,
-        _ => return None,
-    };
-
-    // Return None if the USB port isn't an Arduino.
-    get_board_type(usb_port_info)?;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let port_builder = serialport::new(&serial_port_info.port_name, 57600)
-        .timeout(Duration::from_millis(1))
-        .data_bits(serialport::DataBits::Eight);
-
-    let port = match port_builder.open() {
-        Ok(port) => port,
-        Err(_) => return None,
-    };
-
-    let board_serial_number = match &usb_port_info.serial_number {
-        Some(board_serial_number) => board_serial_number,
-        None => return None,
-    };
+    #[test]
+    fn maps_command_errors_to_http_statuses() {
+        let get_status = |err: CommandExecutorError| get_command_error_response(&err, None).0;
 
-    match LiVeAceSerialPort::new(port, board_serial_number.clone()) {
-        Ok(call_response_serial_port) => Some(call_response_serial_port),
-        Err(_) => None,
+        assert_eq!(
+            get_status(CommandExecutorError::InvalidArguments(String::from(
+                "Missing argument 'copies' for command 'printLabel'"
+            ))),
+            Status::BadRequest
+        );
+        assert_eq!(
+            get_status(CommandExecutorError::UnknownCommand),
+            Status::NotFound
+        );
+        assert_eq!(get_status(CommandExecutorError::Busy), Status::Conflict);
+        assert_eq!(
+            get_status(CommandExecutorError::Indeterminate),
+            Status::GatewayTimeout
+        );
     }
 }
-
-/// Returns the board type for the given USB port info, or None if the port
-/// isn't an Arduino.
-fn get_board_type(usb_port_info: &UsbPortInfo) -> Option<ArduinoBoardType> {
-    // 9025 is the decimal version of 2341.
-    // See https://devicehunt.com/view/type/usb/vendor/2341 for board
-    // number references, and remember to convert from hex to decimal.
-    if usb_port_info.vid != 9025 {
-        return None;
-    }
-
-    match usb_port_info.pid {
-        66 => Some(ArduinoBoardType::Mega2560),
-        _ => Some(ArduinoBoardType::Unknown),
-    }
-}
-
-#[derive(PartialEq, std::fmt::Debug)]
-enum ArduinoBoardType {
-    Unknown,
-    Mega2560,
-}
@@ -0,0 +1,142 @@
+use crate::command_executor::RequestCounters;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+};
+use std::time::Duration;
+
+/// Upper bounds of the command duration histogram's buckets, in seconds. Most
+/// commands are answered within milliseconds, while vends take several
+/// seconds.
+const COMMAND_DURATION_BUCKETS: [f64; 13] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0,
+];
+
+/// Prometheus metrics describing how commands fare on every executor. Commands
+/// are labeled by executor namespace and by their name within the namespace,
+/// so that commands run through aliases are counted along with the rest.
+pub struct Metrics {
+    registry: Registry,
+    /// Labeled with `result`, which is `success` or the kind of error, such as
+    /// `timeout`.
+    commands: IntCounterVec,
+    command_duration: HistogramVec,
+    request_retries: IntCounterVec,
+    request_timeouts: IntCounterVec,
+    corrupted_frames: IntCounterVec,
+    malformed_frames: IntCounterVec,
+    connected_executors: IntGauge,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let command_labels = ["namespace", "command"];
+        let metrics = Self {
+            registry: Registry::new(),
+            commands: IntCounterVec::new(
+                Opts::new("liveace_commands_total", "Commands run, by result."),
+                &["namespace", "command", "result"],
+            )
+            .unwrap(),
+            command_duration: HistogramVec::new(
+                HistogramOpts::new(
+                    "liveace_command_duration_seconds",
+                    "How long commands took to run, including retries.",
+                )
+                .buckets(COMMAND_DURATION_BUCKETS.to_vec()),
+                &command_labels,
+            )
+            .unwrap(),
+            request_retries: IntCounterVec::new(
+                Opts::new(
+                    "liveace_request_retries_total",
+                    "Requests resent because the previous attempt went unanswered or was corrupted.",
+                ),
+                &command_labels,
+            )
+            .unwrap(),
+            request_timeouts: IntCounterVec::new(
+                Opts::new(
+                    "liveace_request_timeouts_total",
+                    "Request attempts that got no response in time.",
+                ),
+                &command_labels,
+            )
+            .unwrap(),
+            corrupted_frames: IntCounterVec::new(
+                Opts::new(
+                    "liveace_corrupted_frames_total",
+                    "Frames that failed their checksum, in either direction.",
+                ),
+                &command_labels,
+            )
+            .unwrap(),
+            malformed_frames: IntCounterVec::new(
+                Opts::new(
+                    "liveace_malformed_frames_total",
+                    "Frames received from devices that couldn't be parsed.",
+                ),
+                &command_labels,
+            )
+            .unwrap(),
+            connected_executors: IntGauge::new(
+                "liveace_connected_executors",
+                "Executors currently registered.",
+            )
+            .unwrap(),
+        };
+
+        for collector in [
+            Box::new(metrics.commands.clone()) as Box<dyn prometheus::core::Collector>,
+            Box::new(metrics.command_duration.clone()),
+            Box::new(metrics.request_retries.clone()),
+            Box::new(metrics.request_timeouts.clone()),
+            Box::new(metrics.corrupted_frames.clone()),
+            Box::new(metrics.malformed_frames.clone()),
+            Box::new(metrics.connected_executors.clone()),
+        ] {
+            metrics.registry.register(collector).unwrap();
+        }
+        metrics
+    }
+
+    /// Records a command that ran on the executor with the given namespace.
+    /// `request_counters` holds how much the executor's counters grew while
+    /// the command ran.
+    pub fn record_command(
+        &self,
+        namespace: &str,
+        command: &str,
+        result: &str,
+        duration: Duration,
+        request_counters: &RequestCounters,
+    ) {
+        let labels = [namespace, command];
+        self.commands
+            .with_label_values(&[namespace, command, result])
+            .inc();
+        self.command_duration
+            .with_label_values(&labels)
+            .observe(duration.as_secs_f64());
+        for (counter, count) in [
+            (&self.request_retries, request_counters.retries),
+            (&self.request_timeouts, request_counters.timeouts),
+            (&self.corrupted_frames, request_counters.corrupted_frames),
+            (&self.malformed_frames, request_counters.malformed_frames),
+        ] {
+            // Touched even when zero, so that the series exists from the
+            // command's first run and rates can be computed right away.
+            counter.with_label_values(&labels).inc_by(count);
+        }
+    }
+
+    /// Returns every metric in the Prometheus text format.
+    pub fn get_text(&self, connected_executor_count: usize) -> String {
+        self.connected_executors
+            .set(connected_executor_count as i64);
+        let mut text = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut text)
+            .unwrap();
+        String::from_utf8(text).unwrap()
+    }
+}
@@ -0,0 +1,11 @@
+//! Small helpers shared by the rest of the server.
+
+use std::time::SystemTime;
+
+/// Returns the current time in milliseconds since the Unix epoch, which is how
+/// timestamps are reported over the API and stored in the audit log.
+pub fn get_unix_timestamp_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_millis() as u64)
+}
@@ -0,0 +1,97 @@
+import {makeUuid} from '../../../shared/uuid';
+
+/**
+ * An event streamed from the command executor server running on the kiosk,
+ * such as an executor connecting, a command finishing or an inventory sensor
+ * changing.
+ */
+export interface CommandExecutorEvent {
+  /**
+   * Increases by one with every event, so gaps mean that events were missed.
+   */
+  id: number,
+  /** The namespace of the executor that the event is about. */
+  namespace: string,
+  /** The kind of event, such as `commandFinished` or `inventoryChanged`. */
+  event: string,
+  /** Event-specific details. */
+  data: any,
+  /** Milliseconds since the Unix epoch. */
+  timestamp: number
+}
+
+interface EventCallbacks {
+  onEvent: (event: CommandExecutorEvent) => void,
+  onConnect: () => void
+}
+
+/**
+ * Shares a single connection to the command executor server's event stream
+ * between every subscriber. The connection is opened by the first subscription
+ * and closed once the last subscriber unsubscribes.
+ */
+class CommandExecutorApi {
+  private eventCallbacks: Map<string, EventCallbacks> = new Map();
+  private eventSource: EventSource | undefined;
+
+  /**
+   * Sets up an event listener that is called with every event streamed from
+   * the command executor server.
+   * @param onEvent A function that should be called with every event.
+   * @param onConnect A function that should be called whenever the stream
+   * connects or reconnects. Events that happened while the stream was
+   * disconnected are lost, so any state derived from events should be
+   * refetched here.
+   * @returns A callback id, which can be passed to `unsubscribeFromEvents` to
+   * remove the callbacks.
+   */
+  public subscribeToEvents(
+    onEvent: (event: CommandExecutorEvent) => void,
+    onConnect: () => void
+  ): string {
+    const callbackId = makeUuid();
+    this.eventCallbacks.set(callbackId, {onEvent, onConnect});
+    this.openEventSource();
+    return callbackId;
+  }
+
+  /**
+   * Removes an event listener created from `subscribeToEvents`.
+   * @param callbackId The id of the callback, returned from
+   * `subscribeToEvents`.
+   * @returns Whether the callback was successfully removed. True means it was
+   * removed.
+   */
+  public unsubscribeFromEvents(callbackId: string): boolean {
+    const removed = this.eventCallbacks.delete(callbackId);
+    if (this.eventCallbacks.size === 0 && this.eventSource) {
+      this.eventSource.close();
+      this.eventSource = undefined;
+    }
+    return removed;
+  }
+
+  private openEventSource() {
+    // `EventSource` only exists in browsers, not in unit tests.
+    if (this.eventSource || typeof EventSource === 'undefined') {
+      return;
+    }
+
+    // The browser reconnects automatically if the stream drops.
+    this.eventSource =
+      new EventSource('http://localhost:21000/events/stream');
+    this.eventSource.onopen = () => {
+      this.eventCallbacks.forEach((callbacks) => {
+        callbacks.onConnect();
+      });
+    };
+    this.eventSource.onmessage = (message) => {
+      const event: CommandExecutorEvent = JSON.parse(message.data);
+      this.eventCallbacks.forEach((callbacks) => {
+        callbacks.onEvent(event);
+      });
+    };
+  }
+}
+
+export const commandExecutorApi = new CommandExecutorApi();
@@ -13,6 +13,7 @@ import {TransitionProps} from '@mui/material/transitions';
 import Typography from '@mui/material/Typography';
 import Zoom from '@mui/material/Zoom';
 import axios from 'axios';
+import {commandExecutorApi} from './api/commandExecutorApi';
 import {deviceApi} from './api/deviceApi';
 
 // TODO - Store this in LocalStorage so that reloading the page doesn't break
@@ -39,21 +40,47 @@ const SlideDownTransition = React.forwardRef(function Transition(
 const SelectionItem = (props: SelectionItemProps) => {
   const [hasInventory, setHasInventory] = useState(true);
 
-  // Periodically check if the item has available inventory.
+  // Check if the item has available inventory whenever something that could
+  // change it happens, rather than polling.
   useEffect(() => {
-    const interval = setInterval(async () => {
-      const inventoryCommand =
-        props.inventoryItem.inventoryCheckBoolExecutionCommand;
-      if (inventoryCommand) {
+    const inventoryCommand =
+      props.inventoryItem.inventoryCheckBoolExecutionCommand;
+    if (!inventoryCommand) {
+      return;
+    }
+
+    const checkInventory = async () => {
+      try {
         const res = await axios.get(
           `http://localhost:21000/boolCommands/${inventoryCommand}`
         );
         if (typeof res.data === 'boolean') {
           setHasInventory(res.data);
         }
+      } catch {
+        // The check is repeated once the executor reconnects.
+      }
+    };
+
+    checkInventory();
+    const callbackId = commandExecutorApi.subscribeToEvents((event) => {
+      if (event.event === 'commandFinished' &&
+          event.data.command === inventoryCommand) {
+        if (typeof event.data.result === 'boolean') {
+          setHasInventory(event.data.result);
+        }
+      } else if (event.event === 'inventoryChanged' ||
+                 event.event === 'executorConnected' ||
+                 (event.event === 'commandFinished' &&
+                  event.data.type === 'null')) {
+        // Vends may empty a stepper, and firmware without inventory events
+        // doesn't report it.
+        checkInventory();
       }
-    }, 2000);
-    return () => clearInterval(interval);
+    }, checkInventory);
+    return () => {
+      commandExecutorApi.unsubscribeFromEvents(callbackId);
+    };
   }, [props.inventoryItem.inventoryCheckBoolExecutionCommand]);
 
   return (
@@ -1,10 +1,50 @@
 /*
-  Arduino Program that interfaces using LiVeACE 1.0 (LightningVend Arduino
+  Arduino Program that interfaces using LiVeACE 1.1 (LightningVend Arduino
   Command Execution) protocol to control 2 stepper motors that each have a
   homing switch and inventory sensor. Currently only designed for and tested on
   an Arduino Mega.
+
+  LiVeACE 1.1 requests are prefixed with a request id (e.g. `#42 stepper0`),
+  which is echoed back in the response's `id` field. Requests without a request
+  id are handled as LiVeACE 1.0 requests.
+
+  LiVeACE 1.1 requests may also carry an idempotency key after the request id
+  (e.g. `#42 !a1b2 stepper0`). The responses to the most recent keyed requests
+  are remembered, and a request whose key has already been seen gets the
+  remembered response instead of being executed again. This lets the server
+  safely resend a vend whose response was lost. The server can also ask whether
+  a key has been seen with `idempotencyKeyStatus <key>`. Remembered keys are
+  lost on reset, so `hello` and `idempotencyKeyStatus` both report a `bootId`
+  that changes on every reset. A key that is unknown to a different boot than
+  the one the server shook hands with may still have been executed before the
+  reset, and must not be resent.
+
+  The `hello` command describes the firmware and the optional protocol features
+  it supports, so that the server doesn't have to guess them. It also reports
+  the size of the serial receive buffer, which the server uses to decide how
+  much it can write at once.
+
+  Requests may end with a CRC-16/CCITT-FALSE checksum of the rest of the line
+  (e.g. `#42 stepper0*1A2B`), in which case the response ends with a checksum
+  too. A request whose checksum doesn't match is rejected with a
+  `checksumMismatch` error without being executed. After `useChecksums`, every
+  request must carry a checksum until the next reset, so that a request whose
+  checksum was garbled beyond recognition isn't executed either.
+
+  After `useEvents`, the firmware also pushes event lines that aren't responses
+  to any request, such as
+  `{"event": "inventoryChanged", "data": {"stepper": 0, "hasInventory": false}}`
+  when an inventory sensor changes, or `homingSwitchNotTriggered` when a vend
+  fails. Event lines carry a checksum once `useChecksums` has been received.
+
+  A device id of up to 32 letters, digits, `-` and `_` can be stored in EEPROM
+  with `setDeviceId <id>` and read back with `getDeviceId`, which returns null
+  until an id has been set. It is also reported as `boardId` by `hello`. The
+  server names boards without a trustworthy USB serial number, such as most
+  clones, after this id, and assigns one to boards that don't have one yet.
 */
 
+#include <EEPROM.h>
 #include <Stepper.h>
 
 // --- Configuration ---
@@ -78,13 +118,77 @@ const int millisecondsPerVendRetraction =
 // case.
 const int homingTimeoutMs = millisecondsPerVendRetraction + 100;
 
+// The name and build of this firmware, reported by `hello`.
+const String firmwareName = "liveace_mega_sticker_machine";
+const String firmwareBuild = __DATE__ " " __TIME__;
+
+// Where the device id is stored in EEPROM. The first byte marks that an id has
+// been stored, since erased EEPROM reads as 0xFF. It's followed by the id's
+// length and then its characters.
+const int deviceIdEepromAddress = 0;
+const byte deviceIdEepromMarker = 0x4C;
+const unsigned int maxDeviceIdLength = 32;
+
+// Where the number of times the board has booted is stored in EEPROM, after
+// the longest possible device id.
+const int bootCountEepromAddress = deviceIdEepromAddress + 2 + maxDeviceIdLength;
+
 // Global buffer for reading commands from the serial port.
 String command;
 
+// The request id of the command currently being handled, or an empty string if
+// the request didn't include one.
+String requestId;
+
+// The idempotency key of the command currently being handled, or an empty
+// string if the request didn't include one.
+String idempotencyKey;
+
+// Identifies the current boot, so that the server can tell whether the board
+// was reset since it last shook hands. Set in `setup`.
+String bootId;
+
+// Whether `useChecksums` has been received since the last reset.
+bool checksumsRequired = false;
+
+// Whether the command currently being handled carried, or had to carry, a
+// checksum. If so, its response carries one too.
+bool respondWithChecksum;
+
+// Whether the command currently being handled must be rejected because its
+// checksum didn't match or was missing.
+bool checksumMismatched;
+
+// Whether `useEvents` has been received since the last reset.
+bool eventsEnabled = false;
+
+// The inventory sensor readings that were last seen, which are compared against
+// to detect changes.
+bool stepper0LastHasInventory;
+bool stepper1LastHasInventory;
+
+// Ring buffer of the most recently seen idempotency keys and the responses that
+// were sent for them.
+const int rememberedIdempotencyKeyCount = 8;
+String rememberedIdempotencyKeys[rememberedIdempotencyKeyCount];
+bool rememberedIdempotencyKeyIsOk[rememberedIdempotencyKeyCount];
+String rememberedIdempotencyKeyErrorCodes[rememberedIdempotencyKeyCount];
+String rememberedIdempotencyKeyResponses[rememberedIdempotencyKeyCount];
+int nextRememberedIdempotencyKeyIndex = 0;
+
 void setup() {
   Serial.begin(57600);
   Serial.setTimeout(500);
 
+  // Counting boots in EEPROM, unlike seeding a random number, can't repeat an
+  // earlier boot's id. The EEPROM is rated for 100,000 writes, which lasts
+  // over ten years of hourly resets.
+  unsigned long bootCount;
+  EEPROM.get(bootCountEepromAddress, bootCount);
+  bootCount++;
+  EEPROM.put(bootCountEepromAddress, bootCount);
+  bootId = String(bootCount, HEX);
+
   // Setup stepper motor 0.
   stepper0.setSpeed(motorRpm);
   pinMode(stepper0InventorySensorPin, INPUT);
@@ -106,13 +210,64 @@ void setup() {
   // controller from getting hot when the machine is idle.
   digitalWrite(stepper1PowerPin0, LOW);
   digitalWrite(stepper1PowerPin1, LOW);
+
+  stepper0LastHasInventory = !digitalRead(stepper0InventorySensorPin);
+  stepper1LastHasInventory = !digitalRead(stepper1InventorySensorPin);
 }
 
 void loop() {
   if (Serial.available()) {
     command = Serial.readStringUntil('\n');
     command.trim();
-    if (command.equals("listCommands")) {
+    parseChecksum();
+    parseRequestId();
+    parseIdempotencyKey();
+    if (checksumMismatched) {
+      // Mismatched requests are rejected without being executed, so their
+      // idempotency keys aren't remembered either.
+      idempotencyKey = "";
+      printJsonErrorResponse("checksumMismatch", "checksum mismatch.");
+    } else if (replayRememberedResponse()) {
+      // The command was already executed, and its response was sent again.
+    } else if (command.startsWith("idempotencyKeyStatus ")) {
+      printIdempotencyKeyStatus(command.substring(21));
+    } else if (command.equals("hello")) {
+      String deviceId = readDeviceId();
+      String boardId = deviceId.length() > 0
+        ? "\"boardId\": \"" + deviceId + "\", "
+        : "";
+      printJsonResponse(
+        true,
+        "{\"protocol\": \"1.1\", "
+         "\"firmware\": \"" + firmwareName + "\", "
+         "\"build\": \"" + firmwareBuild + "\", " +
+         boardId +
+         "\"bootId\": \"" + bootId + "\", "
+         "\"capabilities\": [\"requestIds\", \"idempotencyKeys\", "
+                            "\"checksums\", \"events\", \"deviceId\"], "
+         "\"rxBufferSize\": " + String(SERIAL_RX_BUFFER_SIZE) + "}",
+        "");
+    } else if (command.equals("getDeviceId")) {
+      String deviceId = readDeviceId();
+      printJsonResponse(
+        true,
+        deviceId.length() > 0 ? "\"" + deviceId + "\"" : "null",
+        "");
+    } else if (command.startsWith("setDeviceId ")) {
+      String deviceId = command.substring(12);
+      deviceId.trim();
+      if (writeDeviceId(deviceId)) {
+        printJsonSuccessNullResponse();
+      } else {
+        printJsonErrorResponse("invalidDeviceId", "invalid device id.");
+      }
+    } else if (command.equals("useChecksums")) {
+      checksumsRequired = true;
+      printJsonSuccessNullResponse();
+    } else if (command.equals("useEvents")) {
+      eventsEnabled = true;
+      printJsonSuccessNullResponse();
+    } else if (command.equals("listCommands")) {
       printJsonResponse(
         true,
         "{\"null\": [\"stepper0\", "
@@ -120,7 +275,8 @@ void loop() {
          "\"boolean\": [\"stepper0HasInventory\", "
                        "\"stepper1HasInventory\", "
                        "\"stepper0OutOfInventory\", "
-                       "\"stepper1OutOfInventory\"]}");
+                       "\"stepper1OutOfInventory\"]}",
+        "");
     } else if (command.equals("stepper0")) {
       bool stepperSucceeded = moveStepper(
         stepper0,
@@ -132,7 +288,11 @@ void loop() {
       if (stepperSucceeded) {
         printJsonSuccessNullResponse();
       } else {
-        printJsonErrorResponse("stepper0 homing switch not triggered.");
+        printJsonErrorResponse(
+          "homingSwitchNotTriggered",
+          "stepper0 homing switch not triggered."
+        );
+        printJsonEvent("homingSwitchNotTriggered", "{\"stepper\": 0}");
       }
     } else if (command.equals("stepper1")) {
       bool stepperSucceeded = moveStepper(
@@ -145,7 +305,11 @@ void loop() {
       if (stepperSucceeded) {
         printJsonSuccessNullResponse();
       } else {
-        printJsonErrorResponse("stepper1 homing switch not triggered.");
+        printJsonErrorResponse(
+          "homingSwitchNotTriggered",
+          "stepper1 homing switch not triggered."
+        );
+        printJsonEvent("homingSwitchNotTriggered", "{\"stepper\": 1}");
       }
     } else if (command.equals("stepper0HasInventory")) {
       printJsonSuccessBoolResponse(!digitalRead(stepper0InventorySensorPin));
@@ -156,29 +320,291 @@ void loop() {
     } else if (command.equals("stepper1OutOfInventory")) {
       printJsonSuccessBoolResponse(digitalRead(stepper1InventorySensorPin));
     } else {
-      printJsonErrorResponse("unknown command: `" + command + "`.");
+      printJsonErrorResponse(
+        "unknownCommand",
+        "unknown command: `" + command + "`."
+      );
+    }
+  }
+
+  checkInventorySensor(0, stepper0InventorySensorPin, stepper0LastHasInventory);
+  checkInventorySensor(1, stepper1InventorySensorPin, stepper1LastHasInventory);
+}
+
+// Sends an `inventoryChanged` event if the stepper's inventory sensor reading
+// changed since it was last checked.
+void checkInventorySensor(int stepper,
+                          int inventorySensorPin,
+                          bool& lastHasInventory) {
+  bool hasInventory = !digitalRead(inventorySensorPin);
+  if (hasInventory == lastHasInventory) {
+    return;
+  }
+
+  lastHasInventory = hasInventory;
+  printJsonEvent(
+    "inventoryChanged",
+    "{\"stepper\": " + String(stepper) + ", "
+     "\"hasInventory\": " + (hasInventory ? "true" : "false") + "}");
+}
+
+// Returns the device id stored in EEPROM, or an empty string if none has been
+// stored.
+String readDeviceId() {
+  if (EEPROM.read(deviceIdEepromAddress) != deviceIdEepromMarker) {
+    return "";
+  }
+
+  unsigned int length = EEPROM.read(deviceIdEepromAddress + 1);
+  if (length > maxDeviceIdLength) {
+    return "";
+  }
+
+  String deviceId;
+  for (unsigned int i = 0; i < length; i++) {
+    deviceId += (char)EEPROM.read(deviceIdEepromAddress + 2 + i);
+  }
+  return deviceId;
+}
+
+// Stores `deviceId` in EEPROM. Returns false without storing anything if the
+// id is empty, too long, or contains characters other than letters, digits,
+// `-` and `_`.
+bool writeDeviceId(String deviceId) {
+  if (deviceId.length() == 0 || deviceId.length() > maxDeviceIdLength) {
+    return false;
+  }
+  for (unsigned int i = 0; i < deviceId.length(); i++) {
+    char c = deviceId.charAt(i);
+    if (!isAlphaNumeric(c) && c != '-' && c != '_') {
+      return false;
+    }
+  }
+
+  if (deviceId.equals(readDeviceId())) {
+    return true;
+  }
+
+  // The marker is cleared while the id is written, so that an id that was
+  // only partially written when the board lost power is never read back.
+  // `update` only writes bytes that changed, which spares the EEPROM's
+  // limited write cycles.
+  EEPROM.update(deviceIdEepromAddress, 0xFF);
+  EEPROM.update(deviceIdEepromAddress + 1, deviceId.length());
+  for (unsigned int i = 0; i < deviceId.length(); i++) {
+    EEPROM.update(deviceIdEepromAddress + 2 + i, deviceId.charAt(i));
+  }
+  EEPROM.update(deviceIdEepromAddress, deviceIdEepromMarker);
+  return true;
+}
+
+// Splits a checksum (e.g. `*1A2B`) off the end of `command`, and sets
+// `checksumMismatched` if it doesn't match the rest of the line. A missing or
+// malformed checksum only counts as a mismatch once `useChecksums` has been
+// received.
+void parseChecksum() {
+  respondWithChecksum = checksumsRequired;
+  checksumMismatched = checksumsRequired;
+
+  int separatorIndex = command.lastIndexOf('*');
+  if (separatorIndex < 0 || command.length() - separatorIndex != 5) {
+    return;
+  }
+
+  String checksum = command.substring(separatorIndex + 1);
+  for (unsigned int i = 0; i < checksum.length(); i++) {
+    if (!isHexadecimalDigit(checksum.charAt(i))) {
+      return;
+    }
+  }
+
+  String payload = command.substring(0, separatorIndex);
+  respondWithChecksum = true;
+  checksumMismatched = strtol(checksum.c_str(), NULL, 16) != crc16(payload);
+  command = payload;
+  command.trim();
+}
+
+// Computes the CRC-16/CCITT-FALSE checksum of `data`.
+uint16_t crc16(String data) {
+  uint16_t crc = 0xFFFF;
+  for (unsigned int i = 0; i < data.length(); i++) {
+    crc ^= (uint16_t)(uint8_t)data.charAt(i) << 8;
+    for (int bit = 0; bit < 8; bit++) {
+      crc = crc & 0x8000 ? (crc << 1) ^ 0x1021 : crc << 1;
     }
   }
+  return crc;
 }
 
-void printJsonResponse(bool isOk, String response) {
+// Splits a LiVeACE 1.1 request id (e.g. `#42 `) off the front of `command` and
+// stores it in `requestId`. Leaves `command` untouched and clears `requestId` if
+// the request doesn't start with a valid request id.
+void parseRequestId() {
+  requestId = "";
+  if (!command.startsWith("#")) {
+    return;
+  }
+
+  int separatorIndex = command.indexOf(' ');
+  if (separatorIndex < 2) {
+    return;
+  }
+
+  String rawRequestId = command.substring(1, separatorIndex);
+  for (unsigned int i = 0; i < rawRequestId.length(); i++) {
+    if (!isDigit(rawRequestId.charAt(i))) {
+      return;
+    }
+  }
+
+  requestId = rawRequestId;
+  command = command.substring(separatorIndex + 1);
+  command.trim();
+}
+
+// Splits a LiVeACE 1.1 idempotency key (e.g. `!a1b2 `) off the front of
+// `command` and stores it in `idempotencyKey`. Clears `idempotencyKey` if the
+// request doesn't include one. Only requests with a request id may include an
+// idempotency key.
+void parseIdempotencyKey() {
+  idempotencyKey = "";
+  if (requestId.length() == 0 || !command.startsWith("!")) {
+    return;
+  }
+
+  int separatorIndex = command.indexOf(' ');
+  if (separatorIndex < 2) {
+    return;
+  }
+
+  idempotencyKey = command.substring(1, separatorIndex);
+  command = command.substring(separatorIndex + 1);
+  command.trim();
+}
+
+// Returns the index of `key` in the remembered idempotency keys, or -1 if it
+// isn't remembered.
+int findRememberedIdempotencyKey(String key) {
+  for (int i = 0; i < rememberedIdempotencyKeyCount; i++) {
+    if (rememberedIdempotencyKeys[i].length() > 0 &&
+        rememberedIdempotencyKeys[i].equals(key)) {
+      return i;
+    }
+  }
+  return -1;
+}
+
+// If the current request's idempotency key has already been seen, sends the
+// remembered response and returns true. Otherwise returns false.
+bool replayRememberedResponse() {
+  if (idempotencyKey.length() == 0) {
+    return false;
+  }
+
+  int index = findRememberedIdempotencyKey(idempotencyKey);
+  if (index < 0) {
+    return false;
+  }
+
+  // Cleared so that the replayed response isn't remembered a second time.
+  idempotencyKey = "";
+  printJsonResponse(
+    rememberedIdempotencyKeyIsOk[index],
+    rememberedIdempotencyKeyResponses[index],
+    rememberedIdempotencyKeyErrorCodes[index]
+  );
+  return true;
+}
+
+void printIdempotencyKeyStatus(String key) {
+  key.trim();
+  int index = findRememberedIdempotencyKey(key);
+  if (index < 0) {
+    printJsonResponse(
+      true,
+      "{\"state\": \"unknown\", \"bootId\": \"" + bootId + "\"}",
+      "");
+    return;
+  }
+
+  String ok = rememberedIdempotencyKeyIsOk[index] ? "true" : "false";
+  String code = rememberedIdempotencyKeyIsOk[index]
+    ? ""
+    : "\"code\": \"" + rememberedIdempotencyKeyErrorCodes[index] + "\", ";
+  printJsonResponse(
+    true,
+    "{\"state\": \"done\", \"ok\": " + ok + ", " +
+     code +
+     "\"response\": " + rememberedIdempotencyKeyResponses[index] + ", "
+     "\"bootId\": \"" + bootId + "\"}",
+    "");
+}
+
+// Prints a LiVeACE response. `errorCode` is a machine-readable identifier for
+// the failure and is only sent alongside error responses, so pass an empty
+// string for successful responses.
+void printJsonResponse(bool isOk, String response, String errorCode) {
+  if (idempotencyKey.length() > 0) {
+    rememberedIdempotencyKeys[nextRememberedIdempotencyKeyIndex] =
+      idempotencyKey;
+    rememberedIdempotencyKeyIsOk[nextRememberedIdempotencyKeyIndex] = isOk;
+    rememberedIdempotencyKeyErrorCodes[nextRememberedIdempotencyKeyIndex] =
+      errorCode;
+    rememberedIdempotencyKeyResponses[nextRememberedIdempotencyKeyIndex] =
+      response;
+    nextRememberedIdempotencyKeyIndex =
+      (nextRememberedIdempotencyKeyIndex + 1) % rememberedIdempotencyKeyCount;
+  }
+
   String status = isOk ? "ok" : "error";
-  Serial.println(
+  // The request id is echoed verbatim rather than parsed into an integer so
+  // that ids larger than a `long` don't overflow.
+  String id = requestId.length() > 0 ? "\"id\": " + requestId + ", " : "";
+  String code = !isOk && errorCode.length() > 0
+    ? "\"code\": \"" + errorCode + "\", "
+    : "";
+  printLine(
     "{\"status\": \"" + status + "\", " +
      "\"command\": \"" + command + "\", " +
-     "\"response\": " + response + "}");
+     id +
+     code +
+     "\"response\": " + response + "}",
+    respondWithChecksum);
+}
+
+// Prints an unsolicited event line, if the server asked for events. `data` is
+// the event's raw JSON details.
+void printJsonEvent(String event, String data) {
+  if (!eventsEnabled) {
+    return;
+  }
+
+  printLine(
+    "{\"event\": \"" + event + "\", \"data\": " + data + "}",
+    checksumsRequired);
+}
+
+// Prints a line, followed by its checksum if `withChecksum` is set.
+void printLine(String line, bool withChecksum) {
+  if (withChecksum) {
+    char checksum[6];
+    sprintf(checksum, "*%04X", crc16(line));
+    line += checksum;
+  }
+  Serial.println(line);
 }
 
 void printJsonSuccessNullResponse() {
-  printJsonResponse(true, "null");
+  printJsonResponse(true, "null", "");
 }
 
 void printJsonSuccessBoolResponse(bool response) {
-  printJsonResponse(true, response ? "true" : "false");
+  printJsonResponse(true, response ? "true" : "false", "");
 }
 
-void printJsonErrorResponse(String errorMessage) {
-  printJsonResponse(false, "\"" + errorMessage + "\"");
+void printJsonErrorResponse(String errorCode, String errorMessage) {
+  printJsonResponse(false, "\"" + errorMessage + "\"", errorCode);
 }
 
 // Moves the stepper motor backwards until it hits the homing switch, then